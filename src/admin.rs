@@ -0,0 +1,222 @@
+use std::sync::Arc;
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use clap::ValueEnum;
+use reqwest::Url;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::reasoning_sink::SinkTarget;
+use crate::{ServerState, error};
+
+const REDACTED: &str = "***";
+
+/// `GET /admin/config`, return the effective runtime config, the secrets are redacted
+pub async fn config(state: State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    if let Some(response) = authorize(&state, &headers) {
+        return response;
+    }
+
+    Json(effective_config(&state)).into_response()
+}
+
+/// check the admin token of the admin and debug endpoints, return the error response when the
+/// request is rejected
+pub fn authorize(state: &ServerState, headers: &HeaderMap) -> Option<Response> {
+    let Some(admin_token) = &state.admin_token else {
+        return Some(error::openai_error(
+            StatusCode::NOT_FOUND,
+            "admin endpoint is disabled",
+            None,
+        ));
+    };
+
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()));
+    if !authorized {
+        warn!("unauthorized admin request");
+
+        return Some(error::openai_error(
+            StatusCode::UNAUTHORIZED,
+            "invalid admin token",
+            Some("invalid_api_key"),
+        ));
+    }
+
+    None
+}
+
+fn effective_config(state: &ServerState) -> Value {
+    json!({
+        "backend": redact_url(&state.backend),
+        "limits": {
+            "input_max_token": state.input_max_token,
+            "protect_last_user": state.truncate_options.protect_last_user,
+            "min_message_tokens": state.truncate_options.min_message_tokens,
+            "allow_no_truncate": state.allow_no_truncate,
+            "max_prompt_chars": state.max_prompt_chars,
+            "max_json_depth": state.max_json_depth,
+            "max_json_elements": state.max_json_elements,
+            "output_max_token": state.output_max_token,
+            "context_window": state.context_window,
+            "context_min_output_token": state.context_min_output_token,
+            "chat_max_tokens_field": state.chat_max_tokens_field.as_ref().map(value_name),
+            "max_streams_per_key": state.stream_limiter.as_ref().map(|limiter| limiter.max()),
+            "max_connections_per_ip": state
+                .connection_limiter
+                .as_ref()
+                .map(|limiter| limiter.max()),
+            "max_decompress_ratio": state.max_decompress_ratio,
+            "max_decompressed_size": state.max_decompressed_size,
+            "smooth": state.smoother.is_some(),
+            "priority_keys": state.priority_keys.len(),
+            "load_shedding": state.load_shedder.is_some(),
+            "request_timeout": state.request_timeout.map(|timeout| timeout.as_secs_f64()),
+        },
+        "moderation": {
+            "endpoint": state.moderator.as_ref().map(|moderator| redact_url(moderator.endpoint())),
+            "mode": value_name(&state.moderation_mode),
+        },
+        "cot": {
+            "parser": state.cot_parser.as_ref().map(value_name),
+            "model_parsers": state
+                .model_cot_parsers
+                .iter()
+                .map(|(model, parser)| (model.clone(), parser.as_ref().map(value_name).into()))
+                .collect::<serde_json::Map<_, _>>(),
+            "fence_label": state.cot_fence_label,
+            "reasoning_loop_repeats": state.reasoning_loop.map(|reasoning_loop| reasoning_loop.repeats),
+            "reasoning_loop_action": state
+                .reasoning_loop
+                .as_ref()
+                .and_then(|reasoning_loop| value_name(&reasoning_loop.action)),
+            "summarize_reasoning": state.summarizer.as_ref().map(|summarizer| json!({
+                "model": summarizer.model(),
+                "endpoint": redact_url(summarizer.endpoint()),
+                "min_chars": summarizer.min_chars(),
+            })),
+            "reasoning_ratio_alert": state.reasoning_ratio_alert,
+            "reasoning_sink": state.reasoning_sink.as_ref().map(|sink| match sink.target() {
+                SinkTarget::File(path) => path.display().to_string(),
+                SinkTarget::Url(url) => redact_url(url),
+            }),
+            "reasoning_field": state.reasoning_field,
+            "lenient_sse": state.lenient_sse,
+            "strict_chunks": state.strict_chunks,
+            "drop_after_finish": state.drop_after_finish,
+            "normalize_newlines": state.normalize_newlines,
+            "reasoning_prefix": state.reasoning_marker.as_ref().map(|marker| &marker.prefix),
+            "reasoning_suffix": state.reasoning_marker.as_ref().map(|marker| &marker.suffix),
+        },
+        "request": {
+            "prompt_template": state.prompt_template,
+            "role_map": state.role_map,
+            "user_message_template": state.user_message_template,
+            "duplicate_auth": value_name(&state.duplicate_auth),
+            "allowed_proxy_paths": state.allowed_proxy_paths,
+            "allowed_proxy_methods": state
+                .allowed_proxy_methods
+                .iter()
+                .map(Method::as_str)
+                .collect::<Vec<_>>(),
+            "downgrade_proxy_stream": state.downgrade_proxy_stream,
+            "derive_user_from": state.derive_user_from.as_ref().map(|name| name.as_str()),
+            "default_seed": state.default_seed,
+            "inject_stream_usage": state.inject_stream_usage,
+            "max_stop_sequences": state.max_stop_sequences,
+            "stop_overflow": value_name(&state.stop_overflow),
+            "deny_streaming": state.deny_streaming,
+            "deny_non_streaming": state.deny_non_streaming,
+            "denied_stream_action": value_name(&state.denied_stream_action),
+            "enforce_echo": state.enforce_echo,
+            "strip_store": state.strip_store,
+            "default_metadata": state.default_metadata,
+            "rename_params": state.rename_params,
+            "fallback_models": state.fallback_models,
+            "prices": state
+                .prices
+                .iter()
+                .map(|(model, price)| (model.clone(), json!([price.input, price.output])))
+                .collect::<serde_json::Map<_, _>>(),
+            "enable_cancel": state.cancels.is_some(),
+            "request_id_source": state
+                .request_id_source
+                .as_ref()
+                .map(|source| source.to_string()),
+            "transform_command": state.transform_command,
+            "transform_timeout": state.transform_timeout.as_secs_f64(),
+        },
+        "response": {
+            "output_redact": state.redactor.is_some(),
+            "response_script": state.response_script.is_some(),
+            "response_script_stream": state.response_script_stream,
+            "allow_debug_raw": state.allow_debug_raw,
+            "sse_initial_comment": state.sse_initial_comment,
+            "forward_upstream_keepalive": state.forward_upstream_keepalive,
+            "reasoning_event_name": state.reasoning_event_name,
+            "sse_keepalive": state.sse_keepalive.map(|interval| interval.as_secs_f64()),
+            "strip_response_headers": header_names(&state.strip_response_headers),
+            "allow_response_headers": header_names(&state.allow_response_headers),
+            "stream_error_min_text_chunks": state.stream_error_min_text_chunks,
+            "output_token_rate": state.pace_rate.map(|rate| rate.content.get()),
+            "reasoning_token_rate": state.pace_rate.map(|rate| rate.reasoning.get()),
+            "stream_buffer": state.stream_buffer,
+            "stream_buffer_timeout": state.stream_buffer_timeout.as_secs_f64(),
+            "track_fingerprint": state.fingerprints.is_some(),
+        },
+    })
+}
+
+fn value_name<T: ValueEnum>(value: &T) -> Option<String> {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+}
+
+fn header_names(names: &[header::HeaderName]) -> Vec<&str> {
+    names.iter().map(|name| name.as_str()).collect()
+}
+
+/// hide the userinfo and the query, which may carry an api key
+pub fn redact_url(url: &Url) -> String {
+    let mut url = url.clone();
+    if !url.username().is_empty() {
+        let _ = url.set_username(REDACTED);
+    }
+    if url.password().is_some() {
+        let _ = url.set_password(Some(REDACTED));
+    }
+    if url.query().is_some() {
+        url.set_query(Some(REDACTED));
+    }
+
+    url.to_string()
+}
+
+/// compare the SHA-256 of the secrets, so the time doesn't leak the length or the common prefix
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+
+    a.iter().zip(&b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_secrets() {
+        assert!(constant_time_eq(b"admin-secret", b"admin-secret"));
+        assert!(!constant_time_eq(b"admin-secret", b"admin-secreT"));
+        assert!(!constant_time_eq(b"admin-secret", b"admin"));
+        assert!(!constant_time_eq(b"", b"admin-secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,167 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use axum::body::{self, Body};
+use axum::extract::ConnectInfo;
+use axum::http::{Request, header};
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response, Sse};
+use axum::routing::post;
+use axum::{Json, Router};
+use futures_util::{StreamExt, stream};
+use reqwest::Url;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+use crate::cli::BenchArgs;
+use crate::listener::PeerAddr;
+
+const PROMPT_WORD: &str = "hello ";
+const ECHO_CONTENTS: &[&str] = &[
+    "<think>\n",
+    "bench ",
+    "reasoning",
+    "</think>",
+    "bench ",
+    "content",
+];
+
+/// spawn a built-in echo backend, return its url
+pub async fn spawn_echo_backend() -> anyhow::Result<Url> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+
+    let app = Router::new()
+        .route("/v1/completions", post(echo))
+        .route("/v1/chat/completions", post(echo));
+
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    Ok(format!("http://{addr}").parse()?)
+}
+
+async fn echo(Json(request): Json<Value>) -> Response {
+    let model = request["model"].clone();
+
+    if !request["stream"].as_bool().unwrap_or_default() {
+        return Json(json!({
+            "id": "bench",
+            "object": "chat.completion",
+            "created": 0,
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": ECHO_CONTENTS.concat()},
+                "finish_reason": "stop",
+            }],
+        }))
+        .into_response();
+    }
+
+    let events = stream::iter(ECHO_CONTENTS.iter().enumerate())
+        .map(move |(i, content)| {
+            let finish_reason = (i == ECHO_CONTENTS.len() - 1).then_some("stop");
+
+            Event::default().json_data(json!({
+                "id": "bench",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": model,
+                "choices": [{
+                    "index": 0,
+                    "delta": {"content": content},
+                    "finish_reason": finish_reason,
+                }],
+            }))
+        })
+        .chain(stream::once(async { Ok(Event::default().data("[DONE]")) }));
+
+    Sse::new(events).into_response()
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+/// send synthetic chat requests to the in-process `app` and print the summary
+pub async fn bench(app: Router, args: &BenchArgs) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(&json!({
+        "model": args.model,
+        "messages": [{"role": "user", "content": PROMPT_WORD.repeat(args.prompt_words)}],
+        "stream": args.stream,
+    }))?;
+
+    let start = Instant::now();
+    let stats = stream::iter(0..args.requests)
+        .map(|_| send(app.clone(), body.clone(), args.api_key.as_deref()))
+        .buffer_unordered(args.concurrency.max(1))
+        .fold(Stats::default(), async |mut stats, result| {
+            match result {
+                Ok(latency) => stats.latencies.push(latency),
+                Err(_) => stats.errors += 1,
+            }
+
+            stats
+        })
+        .await;
+    let elapsed = start.elapsed();
+
+    print_summary(args, stats, elapsed);
+
+    Ok(())
+}
+
+async fn send(app: Router, body: Vec<u8>, api_key: Option<&str>) -> anyhow::Result<Duration> {
+    let mut builder =
+        Request::post("/v1/chat/completions").header(header::CONTENT_TYPE, "application/json");
+    if let Some(api_key) = api_key {
+        builder = builder.header(header::AUTHORIZATION, format!("Bearer {api_key}"));
+    }
+
+    let mut request = builder.body(Body::from(body))?;
+    request
+        .extensions_mut()
+        .insert(ConnectInfo(PeerAddr(SocketAddr::from((
+            Ipv4Addr::LOCALHOST,
+            0,
+        )))));
+
+    let start = Instant::now();
+    let response = app.oneshot(request).await?;
+    let status = response.status();
+    body::to_bytes(response.into_body(), usize::MAX).await?;
+    if !status.is_success() {
+        anyhow::bail!("bench request failed: {status}");
+    }
+
+    Ok(start.elapsed())
+}
+
+fn print_summary(args: &BenchArgs, mut stats: Stats, elapsed: Duration) {
+    stats.latencies.sort();
+
+    let percentile = |p: usize| {
+        if stats.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+
+        stats.latencies[(stats.latencies.len() - 1) * p / 100]
+    };
+
+    println!("| requests | concurrency | stream | p50 | p95 | p99 | req/s | error rate |");
+    println!("|---|---|---|---|---|---|---|---|");
+    println!(
+        "| {} | {} | {} | {:?} | {:?} | {:?} | {:.2} | {:.2}% |",
+        args.requests,
+        args.concurrency,
+        args.stream,
+        percentile(50),
+        percentile(95),
+        percentile(99),
+        args.requests as f64 / elapsed.as_secs_f64(),
+        stats.errors as f64 * 100.0 / args.requests.max(1) as f64,
+    );
+}
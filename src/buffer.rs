@@ -0,0 +1,110 @@
+use std::pin::pin;
+use std::time::Duration;
+
+use futures_util::{FutureExt, Stream, StreamExt, select, stream};
+use tokio::sync::mpsc::{self, error::SendTimeoutError};
+use tracing::{debug, warn};
+
+/// forward `st` through a bounded channel, so a slow client applies backpressure to the upstream
+/// instead of buffering unboundedly, when the client doesn't consume in `send_timeout`, the
+/// upstream stream is dropped, it is also dropped as soon as the client stream is dropped
+pub fn bounded<S>(st: S, size: usize, send_timeout: Duration) -> impl Stream<Item = S::Item>
+where
+    S: Stream + Send + 'static,
+    S::Item: Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(size);
+
+    tokio::spawn(async move {
+        let mut st = pin!(st);
+        loop {
+            // don't wait the next upstream item when the client is gone
+            let item = select! {
+                item = st.next().fuse() => item,
+                _ = tx.closed().fuse() => {
+                    debug!("client stream closed, abort upstream stream");
+
+                    return;
+                }
+            };
+            let Some(item) = item else {
+                return;
+            };
+
+            match tx.send_timeout(item, send_timeout).await {
+                Ok(_) => {}
+
+                Err(SendTimeoutError::Timeout(_)) => {
+                    warn!(?send_timeout, "client too slow, abort upstream stream");
+
+                    return;
+                }
+
+                Err(SendTimeoutError::Closed(_)) => {
+                    debug!("client stream closed, abort upstream stream");
+
+                    return;
+                }
+            }
+        }
+    });
+
+    stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+    use tokio::time;
+
+    use super::*;
+
+    /// the items of `st`, the receiver is resolved once the stream is dropped
+    fn tracked<S: Stream>(st: S) -> (impl Stream<Item = S::Item>, oneshot::Receiver<()>) {
+        let (dropped_tx, dropped_rx) = oneshot::channel::<()>();
+        let st = st.inspect(move |_| {
+            let _ = &dropped_tx;
+        });
+
+        (st, dropped_rx)
+    }
+
+    #[tokio::test]
+    async fn abort_upstream_of_slow_client() {
+        let (st, dropped) = tracked(stream::iter(0..10));
+        let st = bounded(st, 2, Duration::from_millis(50));
+
+        time::sleep(Duration::from_millis(200)).await;
+        time::timeout(Duration::from_secs(1), dropped)
+            .await
+            .unwrap()
+            .unwrap_err();
+
+        // only the buffered items and the one waiting for the slot are sent
+        let items = st.collect::<Vec<_>>().await;
+        assert_eq!(items, [0, 1]);
+    }
+
+    #[tokio::test]
+    async fn abort_upstream_when_client_is_dropped() {
+        let (st, dropped) = tracked(stream::once(async { 0 }).chain(stream::pending()));
+        let mut st = Box::pin(bounded(st, 2, Duration::from_secs(60)));
+
+        assert_eq!(st.next().await, Some(0));
+        drop(st);
+
+        // the upstream never ends by itself, it is dropped without waiting the timeout
+        time::timeout(Duration::from_secs(1), dropped)
+            .await
+            .unwrap()
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn forward_all_items() {
+        let st = bounded(stream::iter(0..10), 2, Duration::from_secs(1));
+
+        let items = st.collect::<Vec<_>>().await;
+        assert_eq!(items, (0..10).collect::<Vec<_>>());
+    }
+}
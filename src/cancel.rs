@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap};
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderValue;
+use futures_util::{FutureExt, Stream, StreamExt, select};
+use serde_json::{Value, json};
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::sse::{Chunk, FinishReason};
+
+/// the partial result of an ended stream is kept this long, so it can still be fetched after the
+/// client closed the stream
+const ENDED_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct PartialChoice {
+    reasoning: String,
+    content: String,
+    finish_reason: Option<FinishReason>,
+}
+
+#[derive(Debug, Default)]
+struct Partial {
+    id: String,
+    created: u32,
+    model: String,
+    choices: BTreeMap<i64, PartialChoice>,
+    ended_at: Option<Instant>,
+}
+
+/// an in-flight CoT parsed stream
+#[derive(Debug)]
+pub struct Tracked {
+    authorization: Option<HeaderValue>,
+    partial: Mutex<Partial>,
+    cancel: Notify,
+}
+
+impl Tracked {
+    fn push(&self, chunk: &Chunk) {
+        let mut partial = self.partial.lock().unwrap();
+        if partial.id.is_empty() {
+            partial.id.clone_from(&chunk.id);
+            partial.created = chunk.created;
+            partial.model.clone_from(&chunk.model);
+        }
+
+        for choice in &chunk.choices {
+            let output = partial.choices.entry(choice.index).or_default();
+            if let Some(reasoning) = &choice.delta.reasoning_content {
+                output.reasoning.push_str(reasoning);
+            }
+            if let Some(content) = &choice.delta.content {
+                output.content.push_str(content);
+            }
+            if choice.finish_reason.is_some() {
+                output.finish_reason = choice.finish_reason;
+            }
+        }
+    }
+
+    /// the accumulated output as a non streaming chat completion, the unfinished choices get
+    /// `finish_reason: stop`
+    fn completion(&self) -> Value {
+        let partial = self.partial.lock().unwrap();
+        let choices = partial
+            .choices
+            .iter()
+            .map(|(index, choice)| {
+                let mut message = json!({
+                    "role": "assistant",
+                    "content": choice.content,
+                });
+                if !choice.reasoning.is_empty() {
+                    message["reasoning_content"] = Value::String(choice.reasoning.clone());
+                }
+
+                json!({
+                    "index": index,
+                    "message": message,
+                    "finish_reason": choice.finish_reason.unwrap_or(FinishReason::Stop),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "id": partial.id,
+            "object": "chat.completion",
+            "created": partial.created,
+            "model": partial.model,
+            "choices": choices,
+        })
+    }
+}
+
+/// the in-flight streams keyed by the request ID
+#[derive(Debug, Default)]
+pub struct Cancels {
+    streams: Mutex<HashMap<String, Arc<Tracked>>>,
+}
+
+impl Cancels {
+    /// start tracking the stream, the ended streams past the TTL are cleaned, [`None`] when a
+    /// stream of the request ID is still in flight
+    ///
+    /// the stream is marked ended when the returned guard is dropped
+    pub fn track(
+        &self,
+        request_id: String,
+        authorization: Option<HeaderValue>,
+    ) -> Option<EndGuard> {
+        let mut streams = self.streams.lock().unwrap();
+        streams.retain(|_, tracked| {
+            tracked
+                .partial
+                .lock()
+                .unwrap()
+                .ended_at
+                .is_none_or(|ended_at| ended_at.elapsed() < ENDED_TTL)
+        });
+        if streams
+            .get(&request_id)
+            .is_some_and(|tracked| tracked.partial.lock().unwrap().ended_at.is_none())
+        {
+            return None;
+        }
+
+        let tracked = Arc::new(Tracked {
+            authorization,
+            partial: Default::default(),
+            cancel: Notify::new(),
+        });
+        streams.insert(request_id, tracked.clone());
+
+        Some(EndGuard(tracked))
+    }
+
+    /// stop the stream and return its partial result, [`None`] when the stream is unknown or the
+    /// `Authorization` differs from the stream request
+    ///
+    /// a stream requested without `Authorization` can only be cancelled by the admin
+    pub fn cancel(
+        &self,
+        request_id: &str,
+        authorization: Option<&HeaderValue>,
+        admin: impl FnOnce() -> bool,
+    ) -> Option<Value> {
+        let tracked = self.streams.lock().unwrap().get(request_id).cloned()?;
+        let authorized = match (&tracked.authorization, authorization) {
+            (None, _) => admin(),
+            (Some(expect), Some(authorization)) => {
+                crate::admin::constant_time_eq(expect.as_bytes(), authorization.as_bytes())
+            }
+            (Some(_), None) => false,
+        };
+        if !authorized {
+            return None;
+        }
+
+        info!(request_id, "cancel stream");
+
+        tracked.cancel.notify_one();
+
+        Some(tracked.completion())
+    }
+}
+
+/// mark the stream ended when the stream is done or dropped by the client
+#[derive(Debug)]
+pub struct EndGuard(Arc<Tracked>);
+
+impl Drop for EndGuard {
+    fn drop(&mut self) {
+        self.0.partial.lock().unwrap().ended_at = Some(Instant::now());
+    }
+}
+
+/// accumulate the chunks of the stream, end the stream when it is cancelled
+pub async gen fn accumulate<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    guard: EndGuard,
+) -> anyhow::Result<Chunk> {
+    let tracked = &guard.0;
+
+    let mut st = pin!(st);
+    loop {
+        let chunk = select! {
+            chunk = st.next().fuse() => chunk,
+            _ = tracked.cancel.notified().fuse() => return,
+        };
+        let Some(chunk) = chunk else {
+            return;
+        };
+
+        if let Ok(chunk) = &chunk {
+            tracked.push(chunk);
+        }
+
+        yield chunk;
+    }
+}
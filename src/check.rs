@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use reqwest::{Client, StatusCode, Url};
+use tokio::net;
+use tracing::info;
+
+use crate::backend_url;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// preflight check: resolve backend DNS and request `/v1/models`
+pub async fn check(backend: &Url, client: &Client, api_key: Option<&str>) -> anyhow::Result<()> {
+    let host = backend
+        .host_str()
+        .with_context(|| format!("backend {backend} has no host"))?;
+    let port = backend
+        .port_or_known_default()
+        .with_context(|| format!("backend {backend} has no port"))?;
+
+    let addrs = net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("resolve backend {host}:{port} failed"))?
+        .collect::<Vec<_>>();
+
+    println!("resolve {host}:{port}: ok {addrs:?}");
+
+    let url = backend_url(backend, "/v1/models");
+    let mut request = client.get(url.clone());
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("request {url} failed"))?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!("request {url}: {status}");
+    }
+
+    println!("request {url}: ok {status}");
+
+    Ok(())
+}
+
+/// startup probe: request `/v1/models` without credentials, any HTTP response means the backend is
+/// reachable, even an authorization error
+pub async fn probe(backend: &Url, client: &Client) -> anyhow::Result<()> {
+    let (url, status) = reachable(backend, client, PROBE_TIMEOUT).await?;
+
+    info!(%url, %status, "backend is reachable");
+
+    Ok(())
+}
+
+/// request `/v1/models` without credentials, return the requested URL and the response status
+pub async fn reachable(
+    backend: &Url,
+    client: &Client,
+    timeout: Duration,
+) -> anyhow::Result<(Url, StatusCode)> {
+    let url = backend_url(backend, "/v1/models");
+    let response = client
+        .get(url.clone())
+        .timeout(timeout)
+        .send()
+        .await
+        .with_context(|| format!("backend {backend} is unreachable, request {url} failed"))?;
+
+    Ok((url, response.status()))
+}
@@ -1,5 +1,17 @@
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::PathBuf;
+
+use axum::http::{HeaderName, Method};
 use clap::builder::styling;
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use reqwest::Url;
+use tracing::level_filters::LevelFilter;
+
+use crate::price::Price;
+use crate::reasoning_sink::SinkTarget;
+use crate::request_id::RequestIdSource;
+
+pub const TEMPLATE_PROMPT: &str = "{prompt}";
 
 const STYLES: styling::Styles = styling::Styles::styled()
     .header(styling::AnsiColor::Green.on_default().bold())
@@ -9,28 +21,994 @@ const STYLES: styling::Styles = styling::Styles::styled()
 
 #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
 pub enum CotParser {
+    /// `<think>` tagged reasoning
     Deepseek,
+    /// reasoning in the leading markdown fenced block labeled `--cot-fence-label`
+    MarkdownFence,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum FollowRedirects {
+    /// don't follow redirects
+    None,
+    /// only follow redirects to the same host
+    SameHost,
+    /// follow limited redirects to any host, sensitive headers are stripped on cross host
+    Limited,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum DualStack {
+    /// IPv6 listen addr also accepts IPv4-mapped addr
+    On,
+    /// IPv6 listen addr only accepts IPv6
+    Off,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum TokenizerName {
+    O200kBase,
+    Cl100kBase,
+    P50kBase,
+    R50kBase,
+    P50kEdit,
+    Gpt2,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum TokenizerFallback {
+    /// exit at startup
+    Fail,
+    /// estimate about one token per 3 bytes, an auto selected tokenizer falls back to the default
+    /// one
+    Estimate,
+    /// disable the input truncation and the context window fitting, of all models when the
+    /// default tokenizer fails, or of the models of a failed auto selected tokenizer, the other
+    /// token counting features estimate the tokens
+    Disable,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum ModerationMode {
+    /// forward the request when the moderation endpoint fails
+    FailOpen,
+    /// reject the request when the moderation endpoint fails
+    FailClosed,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum ReasoningLoopAction {
+    /// end the choice with `finish_reason: length`
+    Abort,
+    /// drop the rest reasoning of the choice, keep waiting for the content
+    Content,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum StopOverflow {
+    /// reject the request with 400
+    Reject,
+    /// keep the first stop sequences
+    Truncate,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum DuplicateAuth {
+    /// forward the first `Authorization`
+    First,
+    /// reject the request with 400
+    Reject,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum DeniedStreamAction {
+    /// reject the request with 400
+    Reject,
+    /// flip the `stream` flag to the allowed mode
+    Flip,
+}
+
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, ValueEnum)]
+pub enum MaxTokensField {
+    MaxTokens,
+    MaxCompletionTokens,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// validate config and backend connectivity without serving
+    Check {
+        #[arg(long)]
+        /// api key used to request backend `/v1/models`
+        api_key: Option<String>,
+    },
+
+    /// load test the proxy pipeline in-process, print the latency summary
+    Bench(BenchArgs),
+
+    /// run the configured CoT parsers against built-in recorded streams, without serving
+    Selftest,
+}
+
+#[derive(Debug, Args)]
+pub struct BenchArgs {
+    #[arg(long, default_value_t = 100)]
+    /// total requests
+    pub requests: usize,
+
+    #[arg(long, default_value_t = 10)]
+    /// concurrent requests
+    pub concurrency: usize,
+
+    #[arg(long, default_value_t = 1000)]
+    /// prompt size in words
+    pub prompt_words: usize,
+
+    #[arg(long)]
+    /// send streaming requests
+    pub stream: bool,
+
+    #[arg(long, default_value = "bench")]
+    /// request model
+    pub model: String,
+
+    #[arg(long)]
+    /// use a built-in echo backend instead of the configured backend
+    pub echo: bool,
+
+    #[arg(long)]
+    /// api key sent to the backend
+    pub api_key: Option<String>,
 }
 
 #[derive(Debug, Parser)]
-#[command(styles = STYLES)]
+// the subcommands don't serve, `--listen` is not required and `--backend` is checked by them
+#[command(styles = STYLES, subcommand_negates_reqs = true)]
 pub struct Cli {
-    #[arg(short, long)]
-    /// listen addr
-    pub listen: String,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(
+        short,
+        long,
+        required = true,
+        value_delimiter = ',',
+        env = "OPENAI_ENHANCE_LISTEN"
+    )]
+    /// listen addr, comma separated or repeated to listen on multiple addrs
+    pub listen: Vec<String>,
+
+    #[arg(long, value_enum, env = "OPENAI_ENHANCE_DUAL_STACK")]
+    /// IPv6 listen addr dual stack behavior, default is the OS default
+    pub dual_stack: Option<DualStack>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_CLIENT_KEEPALIVE")]
+    /// send TCP keep-alive probes on idle client connections after the seconds
+    pub client_keepalive: Option<u64>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_CLIENT_IDLE_TIMEOUT")]
+    /// close client connections without in-flight request after idle the seconds, streaming
+    /// responses send keep-alive comments to stay active
+    pub client_idle_timeout: Option<u64>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_REQUEST_TIMEOUT")]
+    /// respond 504 when a request doesn't get the response head in the seconds, include input
+    /// truncating time, streaming body after the first chunk is not limited
+    pub request_timeout: Option<u64>,
 
-    #[arg(short, long)]
-    /// backend addr
-    pub backend: String,
+    #[arg(long, env = "OPENAI_ENHANCE_TRUST_PROXY")]
+    /// resolve client ip from `X-Forwarded-For` or `Forwarded` header
+    pub trust_proxy: bool,
 
-    #[arg(short, long)]
+    #[arg(short, long, required = true, env = "OPENAI_ENHANCE_BACKEND")]
+    /// backend addr, optional for `selftest` and `bench --echo`
+    pub backend: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_REQUIRE_BACKEND_AT_STARTUP")]
+    /// probe the backend `/v1/models` at startup, exit when the backend is unreachable
+    pub require_backend_at_startup: bool,
+
+    #[arg(
+        long,
+        default_value = concat!("openai_enhance/", env!("CARGO_PKG_VERSION")),
+        env = "OPENAI_ENHANCE_UPSTREAM_USER_AGENT"
+    )]
+    /// `User-Agent` sent to backend
+    pub upstream_user_agent: String,
+
+    #[arg(long, env = "OPENAI_ENHANCE_OUTBOUND_PROXY")]
+    /// proxy to reach backend, supports `http://`, `https://` and `socks5://`
+    pub outbound_proxy: Option<String>,
+
+    #[arg(
+        long,
+        requires = "outbound_proxy",
+        env = "OPENAI_ENHANCE_OUTBOUND_NO_PROXY"
+    )]
+    /// comma separated hosts bypass the outbound proxy, same format as `NO_PROXY`
+    pub outbound_no_proxy: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = FollowRedirects::SameHost, env = "OPENAI_ENHANCE_FOLLOW_REDIRECTS")]
+    /// backend redirects policy
+    pub follow_redirects: FollowRedirects,
+
+    #[arg(short, long, env = "OPENAI_ENHANCE_INPUT_MAX_TOKEN")]
     /// limit input token size
     pub input_max_token: Option<usize>,
 
-    #[arg(long, value_enum)]
+    #[arg(long, env = "OPENAI_ENHANCE_PROTECT_LAST_USER")]
+    /// never truncate the last user message unless it alone exceeds the token limit, drop or
+    /// truncate the other messages instead
+    pub protect_last_user: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MIN_MESSAGE_TOKENS")]
+    /// drop the front message instead of truncating it to less tokens than this
+    pub min_message_tokens: Option<usize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_ALLOW_NO_TRUNCATE")]
+    /// let the request with `X-No-Truncate: true` header skip the input truncation and the context
+    /// window fitting, for the trusted clients managing their own budgets
+    pub allow_no_truncate: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_AUTO_TOKENIZER")]
+    /// select tokenizer by request model, fallback to o200k_base
+    pub auto_tokenizer: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        requires = "auto_tokenizer",
+        value_delimiter = ',',
+        env = "OPENAI_ENHANCE_PRELOAD_TOKENIZER"
+    )]
+    /// load the auto selected tokenizer at startup instead of the first request, comma separated
+    /// or repeated
+    pub preload_tokenizer: Vec<TokenizerName>,
+
+    #[arg(long, value_enum, default_value_t = TokenizerFallback::Fail, env = "OPENAI_ENHANCE_TOKENIZER_FALLBACK")]
+    /// behavior when a tokenizer fails to load
+    pub tokenizer_fallback: TokenizerFallback,
+
+    #[arg(long, value_enum, env = "OPENAI_ENHANCE_REMAP_LOGIT_BIAS")]
+    /// re-encode request `logit_bias` from the client tokenizer to the request model tokenizer
+    pub remap_logit_bias: Option<TokenizerName>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_TOKEN_CACHE_SIZE")]
+    /// cache encoded tokens of repeated message contents, with the cache size
+    pub token_cache_size: Option<NonZeroUsize>,
+
+    #[arg(
+        long,
+        default_value_t = 1024,
+        env = "OPENAI_ENHANCE_TOKEN_CACHE_MIN_LEN"
+    )]
+    /// only cache encoded tokens of message contents not shorter than the bytes size
+    pub token_cache_min_len: usize,
+
+    #[arg(long, value_parser = parse_template, env = "OPENAI_ENHANCE_PROMPT_TEMPLATE")]
+    /// wrap the completion prompt with the template before truncating, `{prompt}` is replaced with
+    /// the prompt
+    pub prompt_template: Option<String>,
+
+    #[arg(long, value_parser = |s: &str| parse_key_value(s, "role map", "from=to", non_empty), value_delimiter = ',', env = "OPENAI_ENHANCE_ROLE_MAP")]
+    /// rename the chat message roles before anything else, format `from=to`, comma separated or
+    /// repeated, e.g. `human=user,bot=assistant`, the unmapped roles are kept
+    pub role_map: Vec<(String, String)>,
+
+    #[arg(long, value_parser = parse_template, env = "OPENAI_ENHANCE_USER_MESSAGE_TEMPLATE")]
+    /// wrap each chat user message with the template before truncating, `{prompt}` is replaced
+    /// with the message content
+    pub user_message_template: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_PROMPT_CHARS")]
+    /// reject input longer than the chars size before tokenizing
+    pub max_prompt_chars: Option<usize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_JSON_DEPTH")]
+    /// reject the chat and completion request JSON nesting deeper than the depth with 400
+    pub max_json_depth: Option<NonZeroUsize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_JSON_ELEMENTS")]
+    /// reject the chat and completion request JSON carrying more array elements and object members
+    /// than the count with 400
+    pub max_json_elements: Option<NonZeroUsize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MODERATION_ENDPOINT")]
+    /// check the user input with the OpenAI compatible moderation endpoint, reject the flagged
+    /// request
+    pub moderation_endpoint: Option<Url>,
+
+    #[arg(long, value_enum, default_value_t = ModerationMode::FailOpen, env = "OPENAI_ENHANCE_MODERATION_MODE")]
+    /// how to handle the moderation endpoint failure
+    pub moderation_mode: ModerationMode,
+
+    #[arg(long, env = "OPENAI_ENHANCE_SUMMARIZE_REASONING")]
+    /// replace the long `reasoning_content` of the non streaming chat response with a summary made
+    /// by the model, the streams are not summarized
+    pub summarize_reasoning: Option<String>,
+
+    #[arg(
+        long,
+        requires = "summarize_reasoning",
+        env = "OPENAI_ENHANCE_SUMMARIZE_REASONING_ENDPOINT"
+    )]
+    /// OpenAI compatible chat completions endpoint of the summarization, default is the backend,
+    /// the client `Authorization` is only forwarded to the backend origin
+    pub summarize_reasoning_endpoint: Option<Url>,
+
+    #[arg(
+        long,
+        requires = "summarize_reasoning_endpoint",
+        env = "OPENAI_ENHANCE_SUMMARIZE_REASONING_API_KEY"
+    )]
+    /// api key of the summarization endpoint, it is sent instead of the client `Authorization`
+    pub summarize_reasoning_api_key: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 4000,
+        requires = "summarize_reasoning",
+        env = "OPENAI_ENHANCE_SUMMARIZE_REASONING_MIN_CHARS"
+    )]
+    /// only the reasoning of at least the chars is summarized
+    pub summarize_reasoning_min_chars: usize,
+
+    #[arg(long, value_parser = parse_ratio, env = "OPENAI_ENHANCE_REASONING_RATIO_ALERT")]
+    /// warn when the reasoning tokens of a chat response exceed the multiple of its content tokens,
+    /// the non streaming responses and the CoT parsed streams are checked
+    pub reasoning_ratio_alert: Option<f64>,
+
+    #[arg(long, value_parser = parse_reasoning_sink, env = "OPENAI_ENHANCE_REASONING_SINK")]
+    /// write the reasoning of the CoT parsed streams to the file as JSON lines, or `POST` it to the
+    /// `http://` or `https://` URL, one record per request, the stream doesn't wait for the sink,
+    /// the reasoning is written as sent to the client, after `--output-redact`
+    pub reasoning_sink: Option<SinkTarget>,
+
+    #[arg(short, long, env = "OPENAI_ENHANCE_OUTPUT_MAX_TOKEN")]
+    /// limit output token size, shared by all `n` choices
+    pub output_max_token: Option<usize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_CONTEXT_WINDOW")]
+    /// limit input and output total token size, `max_tokens` is clamped before truncating input
+    pub context_window: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = 256,
+        env = "OPENAI_ENHANCE_CONTEXT_MIN_OUTPUT_TOKEN"
+    )]
+    /// min output token size kept when fitting the context window
+    pub context_min_output_token: usize,
+
+    #[arg(long, value_enum, env = "OPENAI_ENHANCE_CHAT_MAX_TOKENS_FIELD")]
+    /// chat field name of the output token limit sent to backend, default is the name client used
+    pub chat_max_tokens_field: Option<MaxTokensField>,
+
+    #[arg(long, value_enum, env = "OPENAI_ENHANCE_COT_PARSER")]
     pub cot_parser: Option<CotParser>,
 
-    #[arg(short, long)]
+    #[arg(long, value_parser = parse_model_cot_parser, value_delimiter = ',', env = "OPENAI_ENHANCE_MODEL_COT_PARSER")]
+    /// CoT parser of the model, format `model=parser`, parser `none` disables it, comma separated
+    /// or repeated, unmapped models use `--cot-parser`
+    pub model_cot_parser: Vec<(String, Option<CotParser>)>,
+
+    #[arg(long, default_value = "thinking", value_parser = parse_cot_fence_label, env = "OPENAI_ENHANCE_COT_FENCE_LABEL")]
+    /// language label of the reasoning fenced block of the `markdown-fence` CoT parser
+    pub cot_fence_label: String,
+
+    #[arg(long, env = "OPENAI_ENHANCE_DETECT_REASONING_LOOP")]
+    /// cut the CoT parsed reasoning off when its tail repeats the same phrase, of 8 to 512 bytes,
+    /// back to back
+    pub detect_reasoning_loop: bool,
+
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u16).range(2..), requires = "detect_reasoning_loop", env = "OPENAI_ENHANCE_REASONING_LOOP_REPEATS")]
+    /// the times the phrase repeats to be a reasoning loop
+    pub reasoning_loop_repeats: u16,
+
+    #[arg(long, value_enum, default_value_t = ReasoningLoopAction::Abort, requires = "detect_reasoning_loop", env = "OPENAI_ENHANCE_REASONING_LOOP_ACTION")]
+    /// how to handle the looping choice
+    pub reasoning_loop_action: ReasoningLoopAction,
+
+    #[arg(long, env = "OPENAI_ENHANCE_LENIENT_SSE")]
+    /// repair the trailing commas of malformed backend chunk JSON, skip the unrepairable chunks
+    pub lenient_sse: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_STRICT_CHUNKS")]
+    /// abort the stream when a chunk without choice doesn't carry `usage`, by default it is passed
+    /// through
+    pub strict_chunks: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_DROP_AFTER_FINISH")]
+    /// drop the chunks of a choice arriving after its `finish_reason` in the CoT parsed streams,
+    /// by default they are passed through
+    pub drop_after_finish: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_REASONING_FIELD")]
+    /// backend delta field carrying reasoning, default `reasoning_content`
+    pub reasoning_field: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_NORMALIZE_NEWLINES")]
+    /// normalize CRLF to LF in streaming `reasoning_content` and `content`
+    pub normalize_newlines: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_REASONING_PREFIX")]
+    /// insert the prefix at the start of each streaming `reasoning_content` line of the CoT parsed
+    /// streams, e.g. `> ` to render the reasoning as a markdown blockquote
+    pub reasoning_prefix: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_REASONING_SUFFIX")]
+    /// append the suffix to the streaming `reasoning_content` of the CoT parsed streams once the
+    /// reasoning ends
+    pub reasoning_suffix: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_OUTPUT_REDACT")]
+    /// replace the regex matched output with `[REDACTED]`, can be repeated, the env var holds one
+    /// regex as a regex may contain commas, the streams are parsed to redact them even without a
+    /// CoT parser, the responses of the fallback proxy are not redacted
+    pub output_redact: Vec<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_OUTPUT_REDACT_REASONING")]
+    /// also redact the reasoning output
+    pub output_redact_reasoning: bool,
+
+    #[arg(
+        long,
+        default_value_t = 64,
+        env = "OPENAI_ENHANCE_OUTPUT_REDACT_WINDOW"
+    )]
+    /// streaming output chars held back to catch secrets split across chunks, at least the max
+    /// secret length
+    pub output_redact_window: usize,
+
+    #[arg(long, env = "OPENAI_ENHANCE_DERIVE_USER_FROM")]
+    /// set the request `user` from the client header when request doesn't set it, `Authorization`
+    /// is hashed
+    pub derive_user_from: Option<HeaderName>,
+
+    #[arg(
+        long,
+        requires = "derive_user_from",
+        env = "OPENAI_ENHANCE_DERIVE_USER_SALT"
+    )]
+    /// salt of the SHA-256 hashed `Authorization` user, required when `--derive-user-from` is
+    /// `Authorization`
+    pub derive_user_salt: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_DEFAULT_SEED")]
+    /// inject the `seed` when request doesn't set it
+    pub default_seed: Option<i64>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_INJECT_STREAM_USAGE")]
+    /// always request usage on streaming chat by injecting `stream_options.include_usage`
+    pub inject_stream_usage: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_STOP_SEQUENCES")]
+    /// max `stop` sequences of the chat and completion requests
+    pub max_stop_sequences: Option<usize>,
+
+    #[arg(long, value_enum, default_value_t = StopOverflow::Reject, requires = "max_stop_sequences", env = "OPENAI_ENHANCE_STOP_OVERFLOW")]
+    /// how to handle the request with too many `stop` sequences
+    pub stop_overflow: StopOverflow,
+
+    #[arg(
+        long,
+        conflicts_with = "deny_non_streaming",
+        env = "OPENAI_ENHANCE_DENY_STREAMING"
+    )]
+    /// deny the chat and completion requests with `stream: true`
+    pub deny_streaming: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_DENY_NON_STREAMING")]
+    /// deny the chat and completion requests without `stream: true`
+    pub deny_non_streaming: bool,
+
+    #[arg(long, value_enum, default_value_t = DeniedStreamAction::Reject, env = "OPENAI_ENHANCE_DENIED_STREAM_ACTION")]
+    /// how to handle the request of the denied stream mode, the flipped request gets the response
+    /// of the allowed mode
+    pub denied_stream_action: DeniedStreamAction,
+
+    #[arg(long, value_enum, default_value_t = DuplicateAuth::First, env = "OPENAI_ENHANCE_DUPLICATE_AUTH")]
+    /// how to handle the request with several `Authorization` headers, only one is forwarded
+    pub duplicate_auth: DuplicateAuth,
+
+    #[arg(long, value_parser = parse_proxy_path, value_delimiter = ',', env = "OPENAI_ENHANCE_ALLOWED_PROXY_PATH")]
+    /// only forward the paths starting with the prefix to backend by the fallback proxy, the
+    /// prefix matches whole path segments, comma separated or repeated, other paths and the paths
+    /// with `.` or `..` segments get 403, all paths are forwarded when not set
+    pub allowed_proxy_path: Vec<String>,
+
+    #[arg(long, value_parser = parse_method, value_delimiter = ',', env = "OPENAI_ENHANCE_ALLOWED_PROXY_METHOD")]
+    /// only forward the methods to backend by the fallback proxy, comma separated or repeated,
+    /// other methods get 403, all methods are forwarded when not set
+    pub allowed_proxy_method: Vec<Method>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_DOWNGRADE_PROXY_STREAM")]
+    /// set `stream: false` and drop `stream_options` of the JSON request forwarded by the fallback
+    /// proxy, for the backend paths which can't stream
+    pub downgrade_proxy_stream: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_ADMIN_TOKEN")]
+    /// bearer token of the `/admin/config`, `/debug/fingerprints` and `/debug/shed` endpoints, the
+    /// endpoints are disabled when not set
+    pub admin_token: Option<String>,
+
+    #[arg(long, value_parser = parse_ready_path, env = "OPENAI_ENHANCE_READY_PATH")]
+    /// serve the readiness probe on the path, e.g. `/ready`, it checks the backend, the tokenizer
+    /// and the config, returns 503 when any of them is not ready, the path is not forwarded to
+    /// the backend
+    pub ready_path: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_TRACK_FINGERPRINT")]
+    /// record the upstream `system_fingerprint` of each model, warn when it changes, the
+    /// fingerprints are served at `/debug/fingerprints` with `--admin-token`, only the non
+    /// streaming responses and the CoT parsed streams are checked
+    pub track_fingerprint: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_ENFORCE_ECHO")]
+    /// prepend the prompt to completion `text` when `echo` is set but the backend ignores it
+    pub enforce_echo: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_STREAMS_PER_KEY")]
+    /// max concurrent streaming requests of each `Authorization`, the requests without it are
+    /// limited by client ip, exceeded requests get 429
+    pub max_streams_per_key: Option<NonZeroUsize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_CONNECTIONS_PER_IP")]
+    /// max concurrent in-flight requests of each client IP, resolved with `--trust-proxy`, a
+    /// streaming request is counted until its body ends, exceeded requests get 429
+    pub max_connections_per_ip: Option<NonZeroUsize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_DECOMPRESS_RATIO")]
+    /// max ratio of the decompressed request body size to the compressed size, a
+    /// `Content-Encoding` request body expanding beyond it gets 413, the bodies up to 64 KiB
+    /// decompressed are not limited by the ratio
+    pub max_decompress_ratio: Option<NonZeroUsize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_MAX_DECOMPRESSED_SIZE")]
+    /// max decompressed size in bytes of a `Content-Encoding` request body, exceeded requests get
+    /// 413
+    pub max_decompressed_size: Option<NonZeroUsize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_SMOOTH_RATE")]
+    /// pace the chat and completion requests to the backend at most the requests per second, the
+    /// burst is queued instead of rejected
+    pub smooth_rate: Option<NonZeroU32>,
+
+    #[arg(
+        long,
+        default_value_t = 100,
+        requires = "smooth_rate",
+        env = "OPENAI_ENHANCE_SMOOTH_QUEUE_DEPTH"
+    )]
+    /// max requests waiting for the pacing, exceeded requests get 429
+    pub smooth_queue_depth: usize,
+
+    #[arg(
+        long,
+        requires = "smooth_rate",
+        value_delimiter = ',',
+        env = "OPENAI_ENHANCE_PRIORITY_KEY"
+    )]
+    /// api key served before the other queued requests of the pacing, the priority requests have
+    /// their own queue of `--smooth-queue-depth`, comma separated or repeated
+    pub priority_key: Vec<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_LATENCY_SHED_THRESHOLD")]
+    /// start rejecting chat and completion requests with 503 when the rolling average backend
+    /// latency in milliseconds of the successful requests stays above it for 10 seconds, the state
+    /// is served at `/debug/shed` with `--admin-token`
+    pub latency_shed_threshold: Option<u64>,
+
+    #[arg(long, default_value_t = 0.5, value_parser = parse_shed_fraction, requires = "latency_shed_threshold", env = "OPENAI_ENHANCE_SHED_FRACTION")]
+    /// fraction of the requests rejected while shedding, less than 1 so the latency keeps being
+    /// sampled
+    pub shed_fraction: f64,
+
+    #[arg(
+        long,
+        conflicts_with = "default_metadata",
+        env = "OPENAI_ENHANCE_STRIP_STORE"
+    )]
+    /// remove the `store` and `metadata` fields of the chat and completion requests, so the
+    /// backend doesn't keep the conversation
+    pub strip_store: bool,
+
+    #[arg(long, value_parser = |s: &str| parse_key_value(s, "metadata", "key=value", |value| Some(value.to_string())), value_delimiter = ',', env = "OPENAI_ENHANCE_DEFAULT_METADATA")]
+    /// add the `metadata` entry to the chat and completion requests unless the client sets the
+    /// key, format `key=value`, comma separated or repeated, a non object client `metadata` is
+    /// rejected with 400
+    pub default_metadata: Vec<(String, String)>,
+
+    #[arg(long, value_parser = |s: &str| parse_key_value(s, "rename", "old=new", non_empty), value_delimiter = ',', env = "OPENAI_ENHANCE_RENAME_PARAM")]
+    /// rename request JSON top level field before forwarding, format `old=new`, comma separated or
+    /// repeated
+    pub rename_param: Vec<(String, String)>,
+
+    #[arg(long, value_parser = |s: &str| parse_key_value(s, "fallback model", "primary=fallback", non_empty), value_delimiter = ',', env = "OPENAI_ENHANCE_FALLBACK_MODEL")]
+    /// retry the non streaming request once with the fallback model when the primary model
+    /// responds 429 or 503, format `primary=fallback`, comma separated or repeated
+    pub fallback_model: Vec<(String, String)>,
+
+    #[arg(long, value_parser = |s: &str| parse_key_value(s, "price", "model=input_per_1k,output_per_1k", price), value_delimiter = ';', env = "OPENAI_ENHANCE_PRICE")]
+    /// model price per 1k tokens, format `model=input_per_1k,output_per_1k`, `;` separated or
+    /// repeated, the estimated cost is returned in the `X-Estimated-Cost` header of the non
+    /// streaming response, or a `: estimated-cost` comment before `[DONE]` of the CoT parsed
+    /// stream, which needs the usage chunk
+    pub price: Vec<(String, Price)>,
+
+    #[arg(long, value_parser = parse_request_id_source, env = "OPENAI_ENHANCE_REQUEST_ID_SOURCE")]
+    /// attach a request ID to the chat and completion requests, `uuid`, `ulid` or `header:NAME` to
+    /// reuse the client header, it is sent to the backend and returned in the `X-Request-Id`
+    /// header, streams also start with a `: request-id` comment
+    pub request_id_source: Option<RequestIdSource>,
+
+    #[arg(
+        long,
+        requires = "request_id_source",
+        env = "OPENAI_ENHANCE_ENABLE_CANCEL"
+    )]
+    /// serve `POST /v1/cancel/{request_id}`, which stops the CoT parsed stream and returns its
+    /// partial result as a non streaming chat completion, the result is kept for 60 seconds after
+    /// the stream ends, so it can be fetched after the client closed the stream, a stream
+    /// requested without `Authorization` can only be cancelled with the admin token, a request ID
+    /// that is still streaming is rejected with 409
+    pub enable_cancel: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_TRANSFORM_COMMAND")]
+    /// pipe request JSON through the command stdin and forward its stdout JSON
+    pub transform_command: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 10, env = "OPENAI_ENHANCE_TRANSFORM_TIMEOUT")]
+    /// transform command timeout in seconds
+    pub transform_timeout: u64,
+
+    #[arg(long, env = "OPENAI_ENHANCE_RESPONSE_SCRIPT")]
+    /// rhai script defines `fn on_response(response)` to post-process non-streaming response
+    pub response_script: Option<PathBuf>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_RESPONSE_SCRIPT_STREAM")]
+    /// also post-process each parsed streaming chunk with the response script
+    pub response_script_stream: bool,
+
+    #[arg(
+        long,
+        default_value_t = 100_000,
+        env = "OPENAI_ENHANCE_RESPONSE_SCRIPT_MAX_OPERATIONS"
+    )]
+    /// max operations of each response script call
+    pub response_script_max_operations: u64,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = "OPENAI_ENHANCE_STRIP_RESPONSE_HEADER"
+    )]
+    /// drop the upstream response header, comma separated or repeated, hop-by-hop headers are
+    /// always dropped
+    pub strip_response_header: Vec<HeaderName>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        env = "OPENAI_ENHANCE_ALLOW_RESPONSE_HEADER"
+    )]
+    /// only forward the allowed upstream response headers, comma separated or repeated,
+    /// `Content-Type` and `Content-Length` are always forwarded
+    pub allow_response_header: Vec<HeaderName>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_ALLOW_DEBUG_RAW")]
+    /// let the non streaming request with `X-Debug-Raw: true` header get the upstream response
+    /// before the proxy processing under the `_raw` field, for debugging the response changes, the
+    /// raw response is still redacted by `--output-redact`, an upstream `_raw` field is removed
+    pub allow_debug_raw: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_SSE_INITIAL_COMMENT")]
+    /// send a `: connected` SSE comment before the first chunk, defeat intermediary buffering
+    pub sse_initial_comment: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_FORWARD_UPSTREAM_KEEPALIVE")]
+    /// send a keep-alive comment to the client when the upstream sends an SSE comment in the CoT
+    /// parsed stream, the passthrough stream always forwards the comments as is
+    pub forward_upstream_keepalive: bool,
+
+    #[arg(long, value_parser = parse_event_name, env = "OPENAI_ENHANCE_REASONING_EVENT_NAME")]
+    /// SSE `event:` name of the CoT parsed chunks carrying `reasoning_content`, the content chunks
+    /// keep the default unnamed event, a chunk with both is split into a reasoning chunk and a
+    /// content chunk
+    pub reasoning_event_name: Option<String>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_STREAM_ERROR_MIN_TEXT_CHUNKS")]
+    /// end the stream with `[DONE]` instead of aborting when an error happens after the count of
+    /// chunks with text were sent
+    pub stream_error_min_text_chunks: Option<usize>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_OUTPUT_TOKEN_RATE")]
+    /// pace the CoT parsed stream to the client at most the tokens per second
+    pub output_token_rate: Option<NonZeroU32>,
+
+    #[arg(
+        long,
+        requires = "output_token_rate",
+        env = "OPENAI_ENHANCE_REASONING_TOKEN_RATE"
+    )]
+    /// pace the reasoning at a different tokens per second, default is `--output-token-rate`
+    pub reasoning_token_rate: Option<NonZeroU32>,
+
+    #[arg(long, env = "OPENAI_ENHANCE_STREAM_BUFFER")]
+    /// buffer size of streaming chunks between backend and client
+    pub stream_buffer: Option<usize>,
+
+    #[arg(
+        long,
+        default_value_t = 30,
+        env = "OPENAI_ENHANCE_STREAM_BUFFER_TIMEOUT"
+    )]
+    /// abort backend stream when client doesn't consume the full buffer in seconds
+    pub stream_buffer_timeout: u64,
+
+    #[cfg(feature = "otel")]
+    #[arg(long, env = "OPENAI_ENHANCE_OTLP_ENDPOINT")]
+    /// OTLP HTTP endpoint to export traces, e.g. `http://localhost:4318/v1/traces`
+    pub otlp_endpoint: Option<String>,
+
+    #[arg(short, long, env = "OPENAI_ENHANCE_DEBUG")]
     /// enable debug log
     pub debug: bool,
+
+    #[arg(long, env = "OPENAI_ENHANCE_TRUNCATION_LOG_LEVEL")]
+    /// log level of the truncation decisions, e.g. `off`, `warn` or `debug`, default to the
+    /// general log level
+    pub truncation_log_level: Option<LevelFilter>,
+}
+
+/// parse the `key=value` arg, the key can't be empty, `name` and `format` describe the arg in the
+/// error
+fn parse_key_value<T>(
+    s: &str,
+    name: &str,
+    format: &str,
+    parse_value: impl FnOnce(&str) -> Option<T>,
+) -> Result<(String, T), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .filter(|(key, _)| !key.is_empty())
+        .and_then(|(key, value)| Some((key.to_string(), parse_value(value)?)))
+        .ok_or_else(|| format!("invalid {name} `{s}`, expect `{format}`"))
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// parse the `input_per_1k,output_per_1k` price
+fn price(value: &str) -> Option<Price> {
+    let (input, output) = value.split_once(',')?;
+    let price = Price {
+        input: input.trim().parse().ok()?,
+        output: output.trim().parse().ok()?,
+    };
+
+    (price.input >= 0.0 && price.output >= 0.0).then_some(price)
+}
+
+fn parse_request_id_source(s: &str) -> Result<RequestIdSource, String> {
+    match s {
+        "uuid" => Ok(RequestIdSource::Uuid),
+        "ulid" => Ok(RequestIdSource::Ulid),
+        _ => match s.strip_prefix("header:") {
+            Some(name) => name
+                .parse()
+                .map(RequestIdSource::Header)
+                .map_err(|err| format!("invalid request ID header `{name}`: {err}")),
+
+            None => Err(format!(
+                "invalid request ID source `{s}`, expect `uuid`, `ulid` or `header:NAME`"
+            )),
+        },
+    }
+}
+
+fn parse_model_cot_parser(s: &str) -> Result<(String, Option<CotParser>), String> {
+    let Some((model, parser)) = s.split_once('=').filter(|(model, _)| !model.is_empty()) else {
+        return Err(format!(
+            "invalid model CoT parser `{s}`, expect `model=parser`"
+        ));
+    };
+
+    let parser = match parser {
+        "none" => None,
+        parser => Some(CotParser::from_str(parser, true)?),
+    };
+
+    Ok((model.to_string(), parser))
+}
+
+fn parse_event_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(['\r', '\n']) {
+        return Err(format!("invalid SSE event name `{s}`"));
+    }
+
+    Ok(s.to_string())
+}
+
+fn parse_proxy_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') {
+        return Err(format!(
+            "invalid proxy path `{s}`, expect starting with `/`"
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+fn parse_ready_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') || s.starts_with("/v1/") {
+        return Err(format!(
+            "invalid ready path `{s}`, expect starting with `/` and outside `/v1/`"
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+fn parse_method(s: &str) -> Result<Method, String> {
+    Method::from_bytes(s.to_ascii_uppercase().as_bytes())
+        .map_err(|err| format!("invalid method `{s}`: {err}"))
+}
+
+fn parse_reasoning_sink(s: &str) -> Result<SinkTarget, String> {
+    if s.starts_with("http://") || s.starts_with("https://") {
+        return s
+            .parse()
+            .map(SinkTarget::Url)
+            .map_err(|err| format!("invalid reasoning sink URL `{s}`: {err}"));
+    }
+
+    if s.is_empty() {
+        return Err("empty reasoning sink".to_string());
+    }
+
+    Ok(SinkTarget::File(s.into()))
+}
+
+fn parse_cot_fence_label(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c == '`') {
+        return Err(format!(
+            "invalid fence label `{s}`, expect no whitespace or backtick"
+        ));
+    }
+
+    Ok(s.to_string())
+}
+
+fn parse_shed_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction < 1.0 => Ok(fraction),
+        _ => Err(format!(
+            "invalid shed fraction `{s}`, expect between 0 and 1"
+        )),
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio.is_finite() => Ok(ratio),
+        _ => Err(format!("invalid ratio `{s}`, expect a positive number")),
+    }
+}
+
+fn parse_template(s: &str) -> Result<String, String> {
+    if !s.contains(TEMPLATE_PROMPT) {
+        return Err(format!("template must contain `{TEMPLATE_PROMPT}`"));
+    }
+
+    Ok(s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommand_without_listen() {
+        let cli = Cli::try_parse_from(["openai_enhance", "check"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Check { .. })));
+        assert!(cli.listen.is_empty());
+        assert!(cli.backend.is_none());
+
+        let cli = Cli::try_parse_from(["openai_enhance", "-b", "http://127.0.0.1:8080", "check"])
+            .unwrap();
+        assert_eq!(cli.backend.as_deref(), Some("http://127.0.0.1:8080"));
+
+        Cli::try_parse_from(["openai_enhance", "bench", "--echo"]).unwrap();
+    }
+
+    #[test]
+    fn parse_key_value_args() {
+        assert_eq!(
+            parse_key_value(" user = human ", "role map", "from=to", non_empty),
+            Ok(("user".to_string(), "human".to_string()))
+        );
+        assert_eq!(
+            parse_key_value("team=", "metadata", "key=value", |value| Some(
+                value.to_string()
+            )),
+            Ok(("team".to_string(), String::new()))
+        );
+        assert_eq!(
+            parse_key_value("gpt-4o=1.5, 2", "price", "model=input,output", price),
+            Ok((
+                "gpt-4o".to_string(),
+                Price {
+                    input: 1.5,
+                    output: 2.0
+                }
+            ))
+        );
+
+        for (s, parse_value) in [
+            ("=new", non_empty as fn(&str) -> Option<String>),
+            ("old=", non_empty),
+            ("old", non_empty),
+        ] {
+            assert_eq!(
+                parse_key_value(s, "rename", "old=new", parse_value),
+                Err(format!("invalid rename `{s}`, expect `old=new`"))
+            );
+        }
+        for s in ["gpt-4o=1.5", "gpt-4o=-1,2", "gpt-4o=a,b"] {
+            parse_key_value(s, "price", "model=input,output", price).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn serve_requires_listen_and_backend() {
+        Cli::try_parse_from(["openai_enhance", "-b", "http://127.0.0.1:8080"]).unwrap_err();
+        Cli::try_parse_from(["openai_enhance", "-l", "127.0.0.1:0"]).unwrap_err();
+        Cli::try_parse_from([
+            "openai_enhance",
+            "-l",
+            "127.0.0.1:0",
+            "-b",
+            "http://127.0.0.1:8080",
+        ])
+        .unwrap();
+    }
+
+    /// the env vars are set in a child process, so they don't leak into the other tests
+    #[test]
+    fn parse_env_vars() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "cli::tests::parse_env_vars_child", "--ignored"])
+            .env("OPENAI_ENHANCE_LISTEN", "127.0.0.1:1,127.0.0.1:2")
+            .env("OPENAI_ENHANCE_BACKEND", "http://env")
+            .env("OPENAI_ENHANCE_RENAME_PARAM", "a=b,c=d")
+            .env("OPENAI_ENHANCE_PRICE", "m1=1,2;m2=3,4")
+            .env("OPENAI_ENHANCE_OUTPUT_REDACT", "sk-[a-z]{1,3}")
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    #[ignore = "run by parse_env_vars with the env vars"]
+    fn parse_env_vars_child() {
+        let cli = Cli::try_parse_from(["openai_enhance"]).unwrap();
+        assert_eq!(cli.listen, ["127.0.0.1:1", "127.0.0.1:2"]);
+        assert_eq!(cli.backend.as_deref(), Some("http://env"));
+        assert_eq!(
+            cli.rename_param,
+            [
+                ("a".to_string(), "b".to_string()),
+                ("c".to_string(), "d".to_string())
+            ]
+        );
+        let models = cli.price.iter().map(|(model, _)| model.as_str());
+        assert_eq!(models.collect::<Vec<_>>(), ["m1", "m2"]);
+        assert_eq!(cli.output_redact, ["sk-[a-z]{1,3}"]);
+
+        // the flag overrides the env var
+        let cli = Cli::try_parse_from(["openai_enhance", "-b", "http://flag"]).unwrap();
+        assert_eq!(cli.backend.as_deref(), Some("http://flag"));
+    }
 }
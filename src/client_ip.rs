@@ -0,0 +1,134 @@
+use std::net::IpAddr;
+
+use axum::http::HeaderMap;
+use axum::http::header::FORWARDED;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// the resolved client ip, set as request extension
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+/// resolve the client ip, when `trust_proxy` is set, the first untrusted hop from the right of
+/// `X-Forwarded-For` or `Forwarded` is used, internal network addrs are treated as trusted proxies
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, trust_proxy: bool) -> IpAddr {
+    if !trust_proxy {
+        return peer;
+    }
+
+    let hops = forwarded_hops(headers);
+
+    hops.iter()
+        .rev()
+        .find(|ip| !is_trusted_hop(**ip))
+        .or(hops.first())
+        .copied()
+        .unwrap_or(peer)
+}
+
+fn forwarded_hops(headers: &HeaderMap) -> Vec<IpAddr> {
+    let x_forwarded_for = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| parse_ip(hop.trim()))
+        .collect::<Vec<_>>();
+    if !x_forwarded_for.is_empty() {
+        return x_forwarded_for;
+    }
+
+    // Forwarded: for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"
+    headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            key.eq_ignore_ascii_case("for")
+                .then(|| parse_ip(value.trim_matches('"')))
+                .flatten()
+        })
+        .collect()
+}
+
+/// parse `ip`, `ip:port` or `[ipv6]:port`
+fn parse_ip(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = hop.parse() {
+        return Some(ip);
+    }
+
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+
+    hop.rsplit_once(':')?.0.parse().ok()
+}
+
+fn is_trusted_hop(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_trusted_hop(IpAddr::V4(ip));
+            }
+
+            ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn headers(name: &'static str, values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+
+        headers
+    }
+
+    #[test]
+    fn resolve_multi_hop_forwarded_for() {
+        let peer = "10.0.0.1".parse().unwrap();
+        let xff = headers(
+            X_FORWARDED_FOR,
+            &["203.0.113.7, 198.51.100.2:8080", "192.168.1.1, 10.0.0.2"],
+        );
+
+        // the first untrusted hop from the right, the private proxies are skipped
+        assert_eq!(
+            resolve(peer, &xff, true),
+            "198.51.100.2".parse::<IpAddr>().unwrap()
+        );
+        // the headers are ignored without trust proxy
+        assert_eq!(resolve(peer, &xff, false), peer);
+
+        // all hops are trusted, the first one is the client
+        let xff = headers(X_FORWARDED_FOR, &["192.168.1.9, 10.0.0.2"]);
+        assert_eq!(
+            resolve(peer, &xff, true),
+            "192.168.1.9".parse::<IpAddr>().unwrap()
+        );
+
+        let forwarded = headers(
+            "forwarded",
+            &[r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.2"#],
+        );
+        assert_eq!(
+            resolve(peer, &forwarded, true),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        // the malformed hops are skipped, the peer is used when nothing is left
+        let xff = headers(X_FORWARDED_FOR, &["unknown, garbage"]);
+        assert_eq!(resolve(peer, &xff, true), peer);
+    }
+}
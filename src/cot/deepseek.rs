@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::pin::pin;
 
 use futures_util::{Stream, StreamExt};
 
+use super::{clear_prompt_logprobs, split_choices, split_reasoning_chunk};
 use crate::sse::{Chunk, Delta};
 
 const THINK_BEGIN_TAG: &str = "<think>";
@@ -10,19 +12,28 @@ const THINK_END_TAG: &str = "</think>";
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 enum ThinkTagState {
     Init,
-    Begin { trimmed_follow_new_line: bool }, // for some
+    Begin {
+        trimmed_follow_new_line: bool,
+    },
     End,
     NoTag,
+    /// the backend sends the reasoning in `reasoning_content`, the CoT format is decided by the
+    /// first text, so a later `<think>` in `content` is kept as content
+    Field,
 }
 
+/// move the `<think>` tagged part of streaming `content` to `reasoning_content`, when the first
+/// text of a choice is in `reasoning_content`, the choice is passed through, when `strict` is set,
+/// a chunk without choice is an error unless it carries `usage`
 pub async gen fn extract_cot<S: Stream<Item = anyhow::Result<Chunk>>>(
-    mut st: S,
+    st: S,
+    strict: bool,
 ) -> anyhow::Result<Chunk> {
-    let mut state = ThinkTagState::Init;
+    let mut states = HashMap::new();
 
     let mut st = pin!(st);
     while let Some(chunk) = st.next().await {
-        let mut chunk = match chunk {
+        let chunk = match chunk {
             Err(err) => {
                 yield Err(err);
                 return;
@@ -32,163 +43,605 @@ pub async gen fn extract_cot<S: Stream<Item = anyhow::Result<Chunk>>>(
         };
 
         if chunk.choices.is_empty() {
+            // the usage chunk of `stream_options.include_usage` has no choice, some backends also
+            // send diagnostics without choice
+            if chunk.usage.is_some() || !strict {
+                yield Ok(chunk);
+                continue;
+            }
+
             yield Err(anyhow::anyhow!("empty choice"));
             return;
         }
 
-        let delta = &chunk.choices[0].delta;
-
-        // skip empty chunk
-        if delta
-            .reasoning_content
-            .as_ref()
-            .map(|s| s.is_empty())
-            .unwrap_or_default()
-            && delta
-                .content
-                .as_ref()
-                .map(|s| s.is_empty())
-                .unwrap_or_default()
-        {
-            continue;
-        }
+        // each choice of the `n` choices has its own think tag state, a chunk carrying several
+        // choices is split, so every choice keeps its index
+        let chunks = if chunk.choices.len() == 1 {
+            vec![chunk]
+        } else {
+            split_choices(chunk)
+        };
 
-        match state {
-            ThinkTagState::Init => {
-                if delta.reasoning_content.is_some() {
-                    state = ThinkTagState::End;
+        for mut chunk in chunks {
+            let state = states
+                .entry(chunk.choices[0].index)
+                .or_insert(ThinkTagState::Init);
+
+            let choice = &chunk.choices[0];
+            let delta = &choice.delta;
+
+            // skip empty chunk, the terminal chunk carries `finish_reason` and `stop_reason` with
+            // empty delta, it must be kept
+            if choice.finish_reason.is_none()
+                && choice.stop_reason.is_none()
+                && delta
+                    .reasoning_content
+                    .as_ref()
+                    .map(|s| s.is_empty())
+                    .unwrap_or_default()
+                && delta
+                    .content
+                    .as_ref()
+                    .map(|s| s.is_empty())
+                    .unwrap_or_default()
+                && delta.annotations.is_none()
+            {
+                continue;
+            }
 
-                    yield Ok(chunk);
-                    continue;
+            // some backends send the reasoning tail and the first content in one delta
+            if matches!(
+                *state,
+                ThinkTagState::Init | ThinkTagState::End | ThinkTagState::Field
+            ) && delta
+                .reasoning_content
+                .as_ref()
+                .is_some_and(|s| !s.is_empty())
+                && delta.content.as_ref().is_some_and(|s| !s.is_empty())
+            {
+                if *state == ThinkTagState::Init {
+                    *state = ThinkTagState::Field;
                 }
 
-                match &delta.content {
-                    None => {
-                        yield Err(anyhow::anyhow!("reasoning_content or content is empty"));
-                        return;
-                    }
+                let reasoning_content = chunk.choices[0].delta.reasoning_content.take().unwrap();
+                yield Ok(split_reasoning_chunk(&chunk, reasoning_content));
 
-                    Some(content) => {
-                        match content.strip_prefix(THINK_BEGIN_TAG) {
-                            None => {
-                                state = ThinkTagState::NoTag;
+                chunk.choices[0].delta.role = None;
+                clear_prompt_logprobs(&mut chunk);
+                yield Ok(chunk);
+                continue;
+            }
 
-                                yield Ok(chunk);
-                                continue;
-                            }
+            match *state {
+                ThinkTagState::Init => {
+                    // an empty `reasoning_content` doesn't decide the CoT format, some backends
+                    // send it along with the `<think>` tagged content
+                    if delta
+                        .reasoning_content
+                        .as_ref()
+                        .is_some_and(|s| !s.is_empty())
+                    {
+                        *state = ThinkTagState::Field;
 
-                            Some(mut content) => {
-                                state = ThinkTagState::Begin {
-                                    trimmed_follow_new_line: false,
-                                };
+                        yield Ok(chunk);
+                        continue;
+                    }
 
-                                let trimmed_content = content.trim_start();
-                                if trimmed_content != content {
-                                    content = trimmed_content;
-                                    state = ThinkTagState::Begin {
-                                        trimmed_follow_new_line: true,
-                                    };
-                                }
+                    match &delta.content {
+                        // the leading role only chunk, pass through and wait for the first text,
+                        // some backends send it with an empty content
+                        None => {
+                            yield Ok(chunk);
+                            continue;
+                        }
 
-                                if !content.contains(THINK_END_TAG) {
-                                    chunk.choices[0].delta = Delta {
-                                        reasoning_content: Some(content.to_string()),
-                                        content: None,
-                                    };
+                        Some(content) if content.is_empty() => {
+                            yield Ok(chunk);
+                            continue;
+                        }
+
+                        Some(content) => {
+                            match content.strip_prefix(THINK_BEGIN_TAG) {
+                                None => {
+                                    *state = ThinkTagState::NoTag;
 
                                     yield Ok(chunk);
                                     continue;
                                 }
 
-                                // for too short cot
-                                state = ThinkTagState::End;
-
-                                // ["reasoning_content", "content"]
-                                let mut split_contents = content.splitn(2, THINK_END_TAG);
-                                let reasoning_content = split_contents.next().unwrap().to_string();
-
-                                let mut reasoning_chunk = chunk.clone();
-                                reasoning_chunk.choices[0].delta = Delta {
-                                    reasoning_content: Some(reasoning_content),
-                                    content: None,
-                                };
+                                Some(mut content) => {
+                                    *state = ThinkTagState::Begin {
+                                        trimmed_follow_new_line: false,
+                                    };
 
-                                yield Ok(reasoning_chunk);
+                                    let trimmed_content = content.trim_start();
+                                    if trimmed_content != content {
+                                        content = trimmed_content;
+                                        *state = ThinkTagState::Begin {
+                                            trimmed_follow_new_line: true,
+                                        };
+                                    }
 
-                                match split_contents.next() {
-                                    Some(content) => {
+                                    if !content.contains(THINK_END_TAG) {
                                         chunk.choices[0].delta = Delta {
-                                            reasoning_content: None,
-                                            content: Some(content.trim_start().to_string()),
+                                            role: delta.role.clone(),
+                                            reasoning_content: Some(content.to_string()),
+                                            content: None,
+                                            annotations: delta.annotations.clone(),
                                         };
+
+                                        yield Ok(chunk);
+                                        continue;
                                     }
 
-                                    None => continue,
-                                }
+                                    // for too short cot
+                                    *state = ThinkTagState::End;
+
+                                    // ["reasoning_content", "content"]
+                                    let mut split_contents = content.splitn(2, THINK_END_TAG);
+                                    let reasoning_content =
+                                        split_contents.next().unwrap().to_string();
+
+                                    yield Ok(split_reasoning_chunk(&chunk, reasoning_content));
+
+                                    match split_contents.next() {
+                                        Some(content) => {
+                                            chunk.choices[0].delta = Delta {
+                                                role: None,
+                                                reasoning_content: None,
+                                                content: Some(content.trim_start().to_string()),
+                                                annotations: chunk.choices[0]
+                                                    .delta
+                                                    .annotations
+                                                    .take(),
+                                            };
+                                            clear_prompt_logprobs(&mut chunk);
+                                        }
+
+                                        None => continue,
+                                    }
 
-                                yield Ok(chunk);
+                                    yield Ok(chunk);
+                                }
                             }
                         }
                     }
                 }
-            }
 
-            ThinkTagState::Begin {
-                trimmed_follow_new_line,
-            } => {
-                // ignore found think tag but content is null case, let client handle it
-                if let Some(content) = &delta.content {
-                    if !content.contains(THINK_END_TAG) {
-                        let mut content = chunk.choices[0].delta.content.take();
-                        if let Some(content) = content.as_mut() {
-                            if !trimmed_follow_new_line {
-                                state = ThinkTagState::Begin {
-                                    trimmed_follow_new_line: true,
-                                };
-                                *content = content.trim_start().to_string();
+                ThinkTagState::Begin {
+                    trimmed_follow_new_line,
+                } => {
+                    // ignore found think tag but content is null case, let client handle it
+                    if let Some(content) = &delta.content {
+                        // the reasoning sent in `reasoning_content` inside the tag goes first
+                        let field_reasoning = delta.reasoning_content.clone().unwrap_or_default();
+
+                        if !content.contains(THINK_END_TAG) {
+                            let mut content = chunk.choices[0].delta.content.take();
+                            if let Some(content) = content.as_mut() {
+                                if !trimmed_follow_new_line {
+                                    *state = ThinkTagState::Begin {
+                                        trimmed_follow_new_line: true,
+                                    };
+                                    *content = content.trim_start().to_string();
+                                }
+
+                                content.insert_str(0, &field_reasoning);
                             }
+
+                            chunk.choices[0].delta.reasoning_content = content;
+
+                            yield Ok(chunk);
+                            continue;
                         }
 
-                        chunk.choices[0].delta.reasoning_content = content;
+                        *state = ThinkTagState::End;
+
+                        // ["reasoning_content", "content"]
+                        let mut split_contents = content.splitn(2, THINK_END_TAG);
+                        let reasoning_content = split_contents.next().unwrap();
+
+                        yield Ok(split_reasoning_chunk(
+                            &chunk,
+                            field_reasoning + reasoning_content,
+                        ));
+
+                        // a trailing `</think>` leaves an empty content, not none, so the content
+                        // half carrying `finish_reason` is still sent when the stream ends here
+                        match split_contents.next() {
+                            Some(content) => {
+                                chunk.choices[0].delta = Delta {
+                                    role: None,
+                                    reasoning_content: None,
+                                    content: Some(content.to_string()),
+                                    annotations: chunk.choices[0].delta.annotations.take(),
+                                };
+                                clear_prompt_logprobs(&mut chunk);
+                            }
 
-                        yield Ok(chunk);
-                        continue;
+                            None => continue,
+                        }
                     }
 
-                    state = ThinkTagState::End;
+                    yield Ok(chunk);
+                    continue;
+                }
 
-                    // ["reasoning_content", "content"]
-                    let mut split_contents = content.splitn(2, THINK_END_TAG);
-                    let reasoning_content = split_contents.next().unwrap();
+                ThinkTagState::End | ThinkTagState::NoTag | ThinkTagState::Field => {
+                    yield Ok(chunk);
+                    continue;
+                }
+            }
+        }
+    }
+}
 
-                    let mut reasoning_chunk = chunk.clone();
-                    reasoning_chunk.choices[0].delta = Delta {
-                        reasoning_content: Some(reasoning_content.to_string()),
-                        content: None,
-                    };
+#[cfg(test)]
+mod tests {
+    use futures_util::{StreamExt, TryStreamExt, stream};
+    use serde_json::json;
 
-                    yield Ok(reasoning_chunk);
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+    use crate::sse::FinishReason;
 
-                    match split_contents.next() {
-                        Some(content) => {
-                            chunk.choices[0].delta = Delta {
-                                reasoning_content: None,
-                                content: Some(content.to_string()),
-                            };
-                        }
+    async fn extract(deltas: &[&str]) -> Vec<Chunk> {
+        let st = stream::iter(build_chunks(deltas).unwrap().into_iter().map(Ok));
 
-                        None => continue,
-                    }
-                }
+        StreamAsyncIterAdapter(extract_cot(st, false))
+            .try_collect()
+            .await
+            .unwrap()
+    }
 
-                yield Ok(chunk);
-                continue;
-            }
+    /// the reasoning and the content of each choice index
+    fn texts(chunks: &[Chunk], choices: usize) -> (Vec<String>, Vec<String>) {
+        let mut reasoning = vec![String::new(); choices];
+        let mut content = vec![String::new(); choices];
+        for choice in chunks.iter().flat_map(|chunk| &chunk.choices) {
+            let index = choice.index as usize;
+            reasoning[index].push_str(
+                choice
+                    .delta
+                    .reasoning_content
+                    .as_deref()
+                    .unwrap_or_default(),
+            );
+            content[index].push_str(choice.delta.content.as_deref().unwrap_or_default());
+        }
 
-            ThinkTagState::End | ThinkTagState::NoTag => {
-                yield Ok(chunk);
-                continue;
-            }
+        (reasoning, content)
+    }
+
+    #[tokio::test]
+    async fn keep_logprobs_and_finish_reason_on_content_half() {
+        let chunks = extract(&[
+            r#"{"content":"<think>\nfirst"}"#,
+            r#"[{"index":0,"delta":{"content":" second</think>answer"},"logprobs":{"content":[]},"finish_reason":"stop","stop_reason":"</s>"}]"#,
+        ])
+        .await;
+        assert_eq!(chunks.len(), 3);
+
+        let reasoning = &chunks[1].choices[0];
+        assert_eq!(
+            reasoning.delta.reasoning_content.as_deref(),
+            Some(" second")
+        );
+        assert!(reasoning.delta.content.is_none());
+        assert!(reasoning.logprobs.is_none());
+        assert!(reasoning.finish_reason.is_none());
+        assert!(reasoning.stop_reason.is_none());
+
+        let content = &chunks[2].choices[0];
+        assert_eq!(content.delta.content.as_deref(), Some("answer"));
+        assert!(content.delta.reasoning_content.is_none());
+        assert_eq!(content.logprobs, Some(json!({"content": []})));
+        assert_eq!(content.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(content.stop_reason, Some(json!("</s>")));
+    }
+
+    #[tokio::test]
+    async fn keep_finish_reason_of_short_think() {
+        let chunks = extract(&[
+            r#"[{"index":0,"delta":{"role":"assistant","content":"<think>short</think>answer"},"logprobs":{"content":[]},"finish_reason":"length"}]"#,
+        ])
+        .await;
+        assert_eq!(chunks.len(), 2);
+
+        assert_eq!(
+            chunks[0].choices[0].delta.role.as_deref(),
+            Some("assistant")
+        );
+        assert!(chunks[0].choices[0].logprobs.is_none());
+        assert!(chunks[0].choices[0].finish_reason.is_none());
+
+        assert!(chunks[1].choices[0].delta.role.is_none());
+        assert!(chunks[1].choices[0].logprobs.is_some());
+        assert_eq!(
+            chunks[1].choices[0].finish_reason,
+            Some(FinishReason::Length)
+        );
+    }
+
+    #[tokio::test]
+    async fn pass_through_delta_role() {
+        let chunks = extract(&[
+            r#"{"role":"assistant"}"#,
+            r#"{"content":"<think>think"}"#,
+            r#"{"content":"</think>answer"}"#,
+        ])
+        .await;
+        let roles = chunks
+            .iter()
+            .map(|chunk| chunk.choices[0].delta.role.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(roles, [Some("assistant"), None, None, None]);
+
+        // the role of the first text stays on the reasoning half
+        let chunks = extract(&[r#"{"role":"assistant","content":"<think>think"}"#]).await;
+        assert_eq!(
+            chunks[0].choices[0].delta.role.as_deref(),
+            Some("assistant")
+        );
+        assert_eq!(
+            chunks[0].choices[0].delta.reasoning_content.as_deref(),
+            Some("think")
+        );
+    }
+
+    #[tokio::test]
+    async fn split_mixed_reasoning_and_content_delta() {
+        let chunks = extract(&[
+            r#"{"role":"assistant","reasoning_content":"think"}"#,
+            r#"[{"index":0,"delta":{"reasoning_content":" tail","content":"answer"},"finish_reason":"stop"}]"#,
+        ])
+        .await;
+        assert_eq!(chunks.len(), 3);
+
+        // the reasoning half goes first, the content half keeps the finish reason
+        assert_eq!(
+            chunks[1].choices[0].delta.reasoning_content.as_deref(),
+            Some(" tail")
+        );
+        assert!(chunks[1].choices[0].delta.content.is_none());
+        assert!(chunks[1].choices[0].finish_reason.is_none());
+
+        assert_eq!(
+            chunks[2].choices[0].delta.content.as_deref(),
+            Some("answer")
+        );
+        assert!(chunks[2].choices[0].delta.reasoning_content.is_none());
+        assert_eq!(chunks[2].choices[0].finish_reason, Some(FinishReason::Stop));
+
+        // the first text is mixed
+        let chunks =
+            extract(&[r#"{"role":"assistant","reasoning_content":"think","content":"answer"}"#])
+                .await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(
+            chunks[0].choices[0].delta.role.as_deref(),
+            Some("assistant")
+        );
+        assert_eq!(
+            chunks[0].choices[0].delta.reasoning_content.as_deref(),
+            Some("think")
+        );
+        assert!(chunks[1].choices[0].delta.role.is_none());
+        assert_eq!(
+            chunks[1].choices[0].delta.content.as_deref(),
+            Some("answer")
+        );
+    }
+
+    #[tokio::test]
+    async fn pass_through_annotations() {
+        let chunks = extract(&[
+            r#"{"content":"<think>think","annotations":[{"type":"url_citation","url":"a"}]}"#,
+            r#"{"annotations":[{"type":"url_citation","url":"b"}]}"#,
+            r#"{"content":" more</think>answer","annotations":[{"type":"url_citation","url":"c"}]}"#,
+            r#"{"content":" tail","annotations":[{"type":"url_citation","url":"d"}]}"#,
+        ])
+        .await;
+
+        let annotations = chunks
+            .iter()
+            .filter_map(|chunk| chunk.choices[0].delta.annotations.as_ref())
+            .map(|annotations| annotations[0]["url"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(annotations, ["a", "b", "c", "d"]);
+
+        assert_eq!(
+            chunks[0].choices[0].delta.reasoning_content.as_deref(),
+            Some("think")
+        );
+        // the annotations of the split chunk stay on the content half
+        let split = chunks
+            .iter()
+            .find(|chunk| chunk.choices[0].delta.content.as_deref() == Some("answer"))
+            .unwrap();
+        assert!(split.choices[0].delta.annotations.is_some());
+    }
+
+    #[tokio::test]
+    async fn keep_terminal_chunk_with_stop_reason() {
+        for first in [r#"{"content":"<think>think"}"#, r#"{"content":"answer"}"#] {
+            let chunks = extract(&[
+                first,
+                r#"[{"index":0,"delta":{"content":""},"finish_reason":"stop","stop_reason":128}]"#,
+            ])
+            .await;
+            assert_eq!(chunks.len(), 2);
+
+            let terminal = &chunks[1].choices[0];
+            assert_eq!(terminal.finish_reason, Some(FinishReason::Stop));
+            assert_eq!(terminal.stop_reason, Some(json!(128)));
+        }
+    }
+
+    #[tokio::test]
+    async fn keep_content_after_finish_reason() {
+        // the parser doesn't drop it, `--drop-after-finish` does
+        let chunks = extract(&[
+            r#"{"role":"assistant","content":"<think>\nshort</think>"}"#,
+            r#"[{"index":0,"delta":{"content":"answer"},"finish_reason":"stop"}]"#,
+            r#"{"content":" late"}"#,
+        ])
+        .await;
+
+        let (reasoning, content) = texts(&chunks, 1);
+        assert_eq!(reasoning, ["short"]);
+        assert_eq!(content, ["answer late"]);
+    }
+
+    #[tokio::test]
+    async fn strict_choices_less_chunk() {
+        let deltas = [
+            r#"{"content":"answer"}"#,
+            r#"{"choices":[],"diagnostics":"slow"}"#,
+            r#"{"choices":[],"usage":{"total_tokens":1}}"#,
+        ];
+
+        // the lenient mode passes it through
+        let chunks = extract(&deltas).await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].other_fields["diagnostics"], "slow");
+
+        let st = stream::iter(build_chunks(&deltas).unwrap().into_iter().map(Ok));
+        let results = StreamAsyncIterAdapter(extract_cot(st, true))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().to_string(), "empty choice");
+
+        // the usage chunk is allowed in the strict mode
+        let st = stream::iter(
+            build_chunks(&[deltas[0], deltas[2]])
+                .unwrap()
+                .into_iter()
+                .map(Ok),
+        );
+        let chunks = StreamAsyncIterAdapter(extract_cot(st, true))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[1].usage.is_some());
+    }
+
+    #[tokio::test]
+    async fn interleave_multiple_choices() {
+        let chunks = extract(&[
+            r#"[{"index":0,"delta":{"role":"assistant","content":"<think>"}},{"index":1,"delta":{"role":"assistant","content":"no think"}}]"#,
+            r#"[{"index":1,"delta":{"content":" at all"}}]"#,
+            r#"[{"index":0,"delta":{"content":"think 0</think>answer"}},{"index":1,"delta":{"content":"</think>"}}]"#,
+            r#"[{"index":0,"delta":{"content":" 0"},"finish_reason":"stop"}]"#,
+            r#"[{"index":1,"delta":{},"finish_reason":"stop"}]"#,
+        ])
+        .await;
+
+        let (reasoning, content) = texts(&chunks, 2);
+        assert_eq!(reasoning, ["think 0", ""]);
+        assert_eq!(content, ["answer 0", "no think at all</think>"]);
+
+        let finish_reasons = chunks
+            .iter()
+            .flat_map(|chunk| &chunk.choices)
+            .filter_map(|choice| Some((choice.index, choice.finish_reason?)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            finish_reasons,
+            [(0, FinishReason::Stop), (1, FinishReason::Stop)]
+        );
+    }
+
+    #[tokio::test]
+    async fn finish_after_think_end_tag() {
+        // the stream ends right after the end tag
+        let chunks = extract(&[
+            r#"{"role":"assistant","content":"<think>\nonly"}"#,
+            r#"{"content":" reasoning"}"#,
+            r#"[{"index":0,"delta":{"content":"</think>"},"finish_reason":"length"}]"#,
+        ])
+        .await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (vec!["only reasoning".to_string()], vec![String::new()])
+        );
+        assert_eq!(
+            chunks.last().unwrap().choices[0].finish_reason,
+            Some(FinishReason::Length)
+        );
+
+        // the finish reason comes in a separate chunk
+        let chunks = extract(&[
+            r#"{"content":"<think>\nonly reasoning\n</think>"}"#,
+            r#"[{"index":0,"delta":{},"finish_reason":"stop"}]"#,
+        ])
+        .await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (vec!["only reasoning\n".to_string()], vec![String::new()])
+        );
+        assert_eq!(
+            chunks.last().unwrap().choices[0].finish_reason,
+            Some(FinishReason::Stop)
+        );
+    }
+
+    #[tokio::test]
+    async fn mix_native_reasoning_and_think_tag() {
+        // the think tag after the native reasoning is content
+        let chunks = extract(&[
+            r#"{"role":"assistant","reasoning_content":"plan"}"#,
+            r#"{"reasoning_content":" done"}"#,
+            r#"{"content":"<think>"}"#,
+            r#"{"content":"literal</think> answer"}"#,
+        ])
+        .await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (
+                vec!["plan done".to_string()],
+                vec!["<think>literal</think> answer".to_string()]
+            )
+        );
+
+        // the empty native reasoning doesn't stop the think tag parsing
+        let chunks = extract(&[
+            r#"{"role":"assistant","reasoning_content":"","content":"<think>\nidea"}"#,
+            r#"{"reasoning_content":"","content":" more"}"#,
+            r#"{"reasoning_content":"","content":"</think>answer"}"#,
+        ])
+        .await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (vec!["idea more".to_string()], vec!["answer".to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn send_prompt_logprobs_once() {
+        let chunks = extract(&[
+            r#"{"prompt_logprobs":[null,{"1":{"logprob":-0.1}}],"choices":[{"index":0,"delta":{"role":"assistant","content":"<think>\nshort</think>answer"},"prompt_logprobs":[null]}]}"#,
+            r#"{"content":" done"}"#,
+        ])
+        .await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (vec!["short".to_string()], vec!["answer done".to_string()])
+        );
+
+        // the prompt logprobs stay on the reasoning half of the split chunk
+        assert_eq!(
+            chunks[0].prompt_logprobs,
+            Some(json!([null, {"1": {"logprob": -0.1}}]))
+        );
+        assert_eq!(chunks[0].choices[0].prompt_logprobs, Some(json!([null])));
+        for chunk in &chunks[1..] {
+            assert!(chunk.prompt_logprobs.is_none());
+            assert!(chunk.choices[0].prompt_logprobs.is_none());
         }
     }
 }
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::mem;
+use std::pin::pin;
+
+use futures_util::{Stream, StreamExt};
+
+use super::{clear_prompt_logprobs, split_choices, split_reasoning_chunk};
+use crate::sse::{Choice, Chunk, Delta};
+
+const FENCE: &str = "```";
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Phase {
+    /// waiting for the first text, it may start with the opening fence
+    Init,
+    /// inside the fenced block, `depth` counts the nested code fences too
+    Reasoning { depth: usize, plain_line: bool },
+    /// after the closing fence, or the text doesn't start with the opening fence
+    Content,
+}
+
+/// the fence state of a choice, `pending` holds the text which may still be a fence line
+#[derive(Debug)]
+struct FenceState {
+    phase: Phase,
+    pending: String,
+}
+
+impl Default for FenceState {
+    fn default() -> Self {
+        Self {
+            phase: Phase::Init,
+            pending: String::new(),
+        }
+    }
+}
+
+impl FenceState {
+    /// return the reasoning and the content part of the text
+    fn push(&mut self, label: &str, text: &str) -> (String, String) {
+        let mut reasoning = String::new();
+        let mut content = String::new();
+
+        let rest;
+        let mut text = text;
+        if self.phase == Phase::Init {
+            self.pending.push_str(text);
+            match self.open(label) {
+                None => return (reasoning, content),
+                Some(held) => {
+                    rest = held;
+                    text = &rest;
+                }
+            }
+        }
+
+        while !text.is_empty() {
+            let Phase::Reasoning { depth, plain_line } = &mut self.phase else {
+                content.push_str(text);
+                break;
+            };
+
+            let (segment, rest) = match text.find('\n') {
+                None => (text, ""),
+                Some(i) => text.split_at(i + 1),
+            };
+            text = rest;
+
+            let complete = segment.ends_with('\n');
+            if *plain_line {
+                reasoning.push_str(segment);
+                *plain_line = !complete;
+                continue;
+            }
+
+            self.pending.push_str(segment);
+            let line = self.pending.trim_start_matches([' ', '\t']);
+            let ticks = line.len() - line.trim_start_matches('`').len();
+            let fence_line = ticks >= FENCE.len() || (!complete && ticks == line.len());
+            if !fence_line {
+                reasoning.push_str(&self.pending);
+                self.pending.clear();
+                *plain_line = !complete;
+                continue;
+            }
+
+            if !complete {
+                continue;
+            }
+
+            // a bare fence closes the innermost block, a fence with a language label opens a
+            // nested code block
+            if line[ticks..].trim().is_empty() {
+                *depth -= 1;
+                if *depth == 0 {
+                    self.phase = Phase::Content;
+                    self.pending.clear();
+                    continue;
+                }
+            } else {
+                *depth += 1;
+            }
+
+            reasoning.push_str(&self.pending);
+            self.pending.clear();
+        }
+
+        (reasoning, content)
+    }
+
+    /// check whether the held text starts with the opening fence, return [`None`] when it is not
+    /// decided yet, otherwise the text after the opening fence, or all held text when there is no
+    /// fence
+    fn open(&mut self, label: &str) -> Option<String> {
+        let opening = format!("{FENCE}{label}");
+        let head = self.pending.trim_start();
+        if head.len() < opening.len() && opening.starts_with(head) {
+            return None;
+        }
+
+        if let Some(info) = head.strip_prefix(&opening) {
+            match info.split_once('\n') {
+                // wait for the end of the opening fence line
+                None if info.trim().is_empty() => return None,
+
+                Some((info, rest)) if info.trim().is_empty() => {
+                    let rest = rest.to_string();
+                    self.phase = Phase::Reasoning {
+                        depth: 1,
+                        plain_line: false,
+                    };
+                    self.pending.clear();
+
+                    return Some(rest);
+                }
+
+                // another language label, e.g. ```thinking_process
+                _ => {}
+            }
+        }
+
+        self.phase = Phase::Content;
+
+        Some(mem::take(&mut self.pending))
+    }
+
+    /// flush the held text when the choice finishes
+    fn finish(&mut self) -> (String, String) {
+        let pending = mem::take(&mut self.pending);
+
+        match self.phase {
+            Phase::Init | Phase::Content => (String::new(), pending),
+
+            Phase::Reasoning { depth, .. } => {
+                self.phase = Phase::Content;
+
+                // the closing fence without trailing new line
+                let line = pending.trim();
+                if depth == 1 && line.len() >= FENCE.len() && line.chars().all(|c| c == '`') {
+                    return (String::new(), String::new());
+                }
+
+                (pending, String::new())
+            }
+        }
+    }
+}
+
+/// move the leading markdown fenced block labeled `label`, e.g. ```` ```thinking ````, of
+/// streaming `content` to `reasoning_content`, the nested code fences inside the block are kept,
+/// chunks which already carry `reasoning_content` are passed through, when `strict` is set, a
+/// chunk without choice is an error unless it carries `usage`
+pub async gen fn extract_cot<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    strict: bool,
+    label: String,
+) -> anyhow::Result<Chunk> {
+    let mut states = HashMap::<i64, FenceState>::new();
+    // the id and the model of the stream, for the chunk flushing the held text at the end
+    let mut template = None;
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        if template.is_none() {
+            template = Some(Chunk {
+                choices: vec![],
+                usage: None,
+                prompt_logprobs: None,
+                other_fields: Default::default(),
+                ..chunk.clone()
+            });
+        }
+
+        if chunk.choices.is_empty() {
+            if chunk.usage.is_some() || !strict {
+                yield Ok(chunk);
+                continue;
+            }
+
+            yield Err(anyhow::anyhow!("empty choice"));
+            return;
+        }
+
+        let chunks = if chunk.choices.len() == 1 {
+            vec![chunk]
+        } else {
+            split_choices(chunk)
+        };
+
+        for mut chunk in chunks {
+            let state = states.entry(chunk.choices[0].index).or_default();
+            let choice = &mut chunk.choices[0];
+
+            if state.phase == Phase::Content {
+                yield Ok(chunk);
+                continue;
+            }
+
+            if state.phase == Phase::Init
+                && choice
+                    .delta
+                    .reasoning_content
+                    .as_ref()
+                    .is_some_and(|s| !s.is_empty())
+            {
+                state.phase = Phase::Content;
+
+                // the held text is not a fence, it goes before the content of this chunk
+                if !state.pending.is_empty() {
+                    let pending = mem::take(&mut state.pending);
+                    let content = choice.delta.content.get_or_insert_default();
+                    content.insert_str(0, &pending);
+                }
+
+                yield Ok(chunk);
+                continue;
+            }
+
+            let (mut reasoning, mut content) = match choice.delta.content.take() {
+                None => Default::default(),
+                Some(text) => state.push(&label, &text),
+            };
+            if choice.finish_reason.is_some() || choice.stop_reason.is_some() {
+                let (more_reasoning, more_content) = state.finish();
+                reasoning.push_str(&more_reasoning);
+                content.push_str(&more_content);
+            }
+
+            match (reasoning.is_empty(), content.is_empty()) {
+                (false, false) => {
+                    yield Ok(split_reasoning_chunk(&chunk, reasoning));
+
+                    chunk.choices[0].delta = Delta {
+                        role: None,
+                        reasoning_content: None,
+                        content: Some(content),
+                        annotations: chunk.choices[0].delta.annotations.take(),
+                    };
+                    clear_prompt_logprobs(&mut chunk);
+                }
+
+                (false, true) => choice.delta.reasoning_content = Some(reasoning),
+
+                (true, false) => choice.delta.content = Some(content),
+
+                // the text is held, keep the chunk only when it carries something else
+                (true, true) => {
+                    if choice.delta.role.is_none()
+                        && choice.delta.annotations.is_none()
+                        && choice.finish_reason.is_none()
+                        && choice.stop_reason.is_none()
+                        && choice.prompt_logprobs.is_none()
+                        && chunk.prompt_logprobs.is_none()
+                    {
+                        continue;
+                    }
+                }
+            }
+
+            yield Ok(chunk);
+        }
+    }
+
+    // the stream may end without a finish reason, flush the held text of the unfinished choices
+    let Some(template) = template else {
+        return;
+    };
+    let mut indexes = states.keys().copied().collect::<Vec<_>>();
+    indexes.sort_unstable();
+    for index in indexes {
+        let (reasoning, content) = states.get_mut(&index).unwrap().finish();
+        if reasoning.is_empty() && content.is_empty() {
+            continue;
+        }
+
+        let mut chunk = template.clone();
+        chunk.choices.push(Choice {
+            index,
+            delta: Delta {
+                role: None,
+                reasoning_content: Some(reasoning).filter(|s| !s.is_empty()),
+                content: Some(content).filter(|s| !s.is_empty()),
+                annotations: None,
+            },
+            logprobs: None,
+            finish_reason: None,
+            stop_reason: None,
+            prompt_logprobs: None,
+        });
+
+        yield Ok(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{TryStreamExt, stream};
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+    use crate::sse::FinishReason;
+
+    async fn extract(deltas: &[&str]) -> Vec<Chunk> {
+        let st = stream::iter(build_chunks(deltas).unwrap().into_iter().map(Ok));
+
+        StreamAsyncIterAdapter(extract_cot(st, false, "thinking".to_string()))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    /// the reasoning and the content of each choice index
+    fn texts(chunks: &[Chunk], choices: usize) -> (Vec<String>, Vec<String>) {
+        let mut reasoning = vec![String::new(); choices];
+        let mut content = vec![String::new(); choices];
+        for choice in chunks.iter().flat_map(|chunk| &chunk.choices) {
+            let index = choice.index as usize;
+            reasoning[index].push_str(
+                choice
+                    .delta
+                    .reasoning_content
+                    .as_deref()
+                    .unwrap_or_default(),
+            );
+            content[index].push_str(choice.delta.content.as_deref().unwrap_or_default());
+        }
+
+        (reasoning, content)
+    }
+
+    #[tokio::test]
+    async fn interleave_multiple_choices() {
+        let chunks = extract(&[
+            r#"[{"index":0,"delta":{"role":"assistant","content":"```thinking\nthink"}},{"index":1,"delta":{"role":"assistant","content":"no fence"}}]"#,
+            r#"[{"index":0,"delta":{"content":" 0\n```\nanswer"}},{"index":1,"delta":{"content":"\n```"}}]"#,
+            r#"[{"index":0,"delta":{"content":" 0"},"finish_reason":"stop"}]"#,
+            r#"[{"index":1,"delta":{},"finish_reason":"stop"}]"#,
+        ])
+        .await;
+
+        let (reasoning, content) = texts(&chunks, 2);
+        assert_eq!(reasoning, ["think 0\n", ""]);
+        assert_eq!(content, ["answer 0", "no fence\n```"]);
+
+        let finish_reasons = chunks
+            .iter()
+            .flat_map(|chunk| &chunk.choices)
+            .filter_map(|choice| Some((choice.index, choice.finish_reason?)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            finish_reasons,
+            [(0, FinishReason::Stop), (1, FinishReason::Stop)]
+        );
+    }
+
+    #[tokio::test]
+    async fn flush_held_text_at_stream_end() {
+        // the partial closing fence is held when the stream ends without a finish reason
+        let chunks = extract(&[
+            r#"{"role":"assistant","content":"```thinking\nplan\n"}"#,
+            r#"{"content":"``"}"#,
+        ])
+        .await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (vec!["plan\n``".to_string()], vec![String::new()])
+        );
+        assert_eq!(chunks.last().unwrap().id, "selftest");
+
+        // the undecided opening fence is content
+        let chunks = extract(&[r#"{"content":"``"}"#]).await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (vec![String::new()], vec!["``".to_string()])
+        );
+
+        // the finished choice is not flushed twice
+        let chunks = extract(&[
+            r#"{"content":"```thinking\nplan\n``"}"#,
+            r#"[{"index":0,"delta":{},"finish_reason":"stop"}]"#,
+        ])
+        .await;
+        assert_eq!(
+            texts(&chunks, 1),
+            (vec!["plan\n``".to_string()], vec![String::new()])
+        );
+        assert_eq!(
+            chunks.last().unwrap().choices[0].finish_reason,
+            Some(FinishReason::Stop)
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_fence_in_one_chunk() {
+        let chunks = extract(&[
+            r#"{"role":"assistant","content":"```thinking\nrun:\n```sh\nls\n```\n```bash\npwd\n```\nok\n```\nanswer"}"#,
+        ])
+        .await;
+
+        let (reasoning, content) = texts(&chunks, 1);
+        assert_eq!(reasoning, ["run:\n```sh\nls\n```\n```bash\npwd\n```\nok\n"]);
+        assert_eq!(content, ["answer"]);
+    }
+
+    #[tokio::test]
+    async fn nested_fence_split_across_chunks() {
+        // the fences are split between the backticks
+        let chunks = extract(&[
+            r#"{"content":"```thinking\nrun:\n"}"#,
+            r#"{"content":"`"}"#,
+            r#"{"content":"`"}"#,
+            r#"{"content":"`sh\nls\n"}"#,
+            r#"{"content":"`"}"#,
+            r#"{"content":"``"}"#,
+            r#"{"content":"\nok\n``"}"#,
+            r#"{"content":"`"}"#,
+            r#"{"content":"\nanswer"}"#,
+        ])
+        .await;
+
+        let (reasoning, content) = texts(&chunks, 1);
+        assert_eq!(reasoning, ["run:\n```sh\nls\n```\nok\n"]);
+        assert_eq!(content, ["answer"]);
+    }
+}
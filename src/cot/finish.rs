@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::pin::pin;
+
+use futures_util::{Stream, StreamExt};
+use tracing::warn;
+
+use crate::sse::Chunk;
+
+/// drop the choices arriving after the `finish_reason` of the same index, some backends keep
+/// sending content after it, the content of the finishing chunk itself is kept, a chunk left
+/// without choice is dropped unless it carries `usage`
+pub async gen fn drop_after_finish<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+) -> anyhow::Result<Chunk> {
+    let mut finished = HashSet::<i64>::new();
+    let mut warned = HashSet::<i64>::new();
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let mut chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        // the usage chunk has no choice
+        if chunk.choices.is_empty() {
+            yield Ok(chunk);
+            continue;
+        }
+
+        chunk.choices.retain(|choice| {
+            if !finished.contains(&choice.index) {
+                if choice.finish_reason.is_some() {
+                    finished.insert(choice.index);
+                }
+
+                return true;
+            }
+
+            if warned.insert(choice.index) {
+                warn!(
+                    index = choice.index,
+                    "drop the choice chunk after finish_reason"
+                );
+            }
+
+            false
+        });
+
+        if !chunk.choices.is_empty() || chunk.usage.is_some() {
+            yield Ok(chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{TryStreamExt, stream};
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::cot::deepseek;
+    use crate::selftest::build_chunks;
+
+    #[tokio::test]
+    async fn drop_before_strict_parser() {
+        let deltas = [
+            r#"{"role":"assistant","content":"<think>\nshort</think>"}"#,
+            r#"[{"index":0,"delta":{"content":"answer"},"finish_reason":"stop"}]"#,
+            r#"{"content":" late"}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":" later"}}],"usage":{"total_tokens":1}}"#,
+        ];
+        let st = stream::iter(build_chunks(&deltas).unwrap().into_iter().map(Ok));
+
+        // the emptied chunk is dropped, so the strict parser doesn't take it as an error
+        let chunks = StreamAsyncIterAdapter(deepseek::extract_cot(
+            StreamAsyncIterAdapter(drop_after_finish(st)),
+            true,
+        ))
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+
+        let content = chunks
+            .iter()
+            .flat_map(|chunk| &chunk.choices)
+            .filter_map(|choice| choice.delta.content.as_deref())
+            .collect::<String>();
+        assert_eq!(content, "answer");
+
+        // the usage is kept without the late choice
+        let last = chunks.last().unwrap();
+        assert!(last.choices.is_empty());
+        assert!(last.usage.is_some());
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::pin::pin;
+
+use futures_util::{Stream, StreamExt};
+
+use super::{clear_prompt_logprobs, split_choices, split_reasoning_chunk};
+use crate::sse::Chunk;
+
+/// the strings wrapping the streamed reasoning
+#[derive(Debug, Clone, Default)]
+pub struct ReasoningMarker {
+    /// inserted at the start of each reasoning line, e.g. `> ` for the markdown blockquote
+    pub prefix: String,
+    /// appended once the reasoning ends
+    pub suffix: String,
+}
+
+#[derive(Debug, Default)]
+struct MarkerState {
+    /// the reasoning is started and not ended yet
+    reasoning: bool,
+    /// the next reasoning char starts a line
+    line_start: bool,
+}
+
+impl ReasoningMarker {
+    /// the prefix is inserted before the first char of each line, so a line split across chunks
+    /// is prefixed once, and a trailing new line doesn't leave a dangling prefix
+    fn prefix_lines(&self, state: &mut MarkerState, reasoning: &str) -> String {
+        if !state.reasoning {
+            state.reasoning = true;
+            state.line_start = true;
+        }
+        if self.prefix.is_empty() {
+            return reasoning.to_string();
+        }
+
+        let mut output = String::with_capacity(reasoning.len() + self.prefix.len());
+        for c in reasoning.chars() {
+            if state.line_start {
+                output.push_str(&self.prefix);
+            }
+            output.push(c);
+            state.line_start = c == '\n';
+        }
+
+        output
+    }
+}
+
+/// wrap the `reasoning_content` of the CoT parsed stream with the marker, the suffix is sent
+/// before the first content after the reasoning, or with the `finish_reason`
+pub async gen fn mark_reasoning<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    marker: ReasoningMarker,
+) -> anyhow::Result<Chunk> {
+    let mut states = HashMap::<i64, MarkerState>::new();
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        // the usage chunk has no choice
+        if chunk.choices.is_empty() {
+            yield Ok(chunk);
+            continue;
+        }
+
+        let chunks = if chunk.choices.len() == 1 {
+            vec![chunk]
+        } else {
+            split_choices(chunk)
+        };
+
+        for mut chunk in chunks {
+            let state = states.entry(chunk.choices[0].index).or_default();
+            let choice = &mut chunk.choices[0];
+
+            if let Some(reasoning) = &mut choice.delta.reasoning_content
+                && !reasoning.is_empty()
+            {
+                *reasoning = marker.prefix_lines(state, reasoning);
+            }
+
+            let ended = choice.delta.content.as_ref().is_some_and(|s| !s.is_empty())
+                || choice.finish_reason.is_some()
+                || choice.stop_reason.is_some();
+            if !state.reasoning || !ended {
+                yield Ok(chunk);
+                continue;
+            }
+
+            state.reasoning = false;
+            if marker.suffix.is_empty() {
+                yield Ok(chunk);
+                continue;
+            }
+
+            match &mut choice.delta.reasoning_content {
+                Some(reasoning) if choice.delta.content.is_none() => {
+                    reasoning.push_str(&marker.suffix);
+                }
+
+                // the suffix goes before the content
+                _ => {
+                    let mut reasoning = choice.delta.reasoning_content.take().unwrap_or_default();
+                    reasoning.push_str(&marker.suffix);
+
+                    yield Ok(split_reasoning_chunk(&chunk, reasoning));
+
+                    chunk.choices[0].delta.role = None;
+                    clear_prompt_logprobs(&mut chunk);
+                }
+            }
+
+            yield Ok(chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{TryStreamExt, stream};
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+
+    fn marker() -> ReasoningMarker {
+        ReasoningMarker {
+            prefix: "> ".to_string(),
+            suffix: "\n\n".to_string(),
+        }
+    }
+
+    async fn mark(deltas: &[&str]) -> Vec<Chunk> {
+        let st = stream::iter(build_chunks(deltas).unwrap().into_iter().map(Ok));
+
+        StreamAsyncIterAdapter(mark_reasoning(st, marker()))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    /// the reasoning and the content of each chunk
+    fn deltas(chunks: &[Chunk]) -> Vec<(Option<&str>, Option<&str>)> {
+        chunks
+            .iter()
+            .map(|chunk| {
+                let delta = &chunk.choices[0].delta;
+
+                (delta.reasoning_content.as_deref(), delta.content.as_deref())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prefix_lines_across_chunks() {
+        let marker = marker();
+        let mut state = MarkerState::default();
+
+        let lines = ["first\nsec", "ond\n", "", "\nthird"]
+            .map(|reasoning| marker.prefix_lines(&mut state, reasoning));
+        assert_eq!(lines, ["> first\n> sec", "ond\n", "", "> \n> third"]);
+        assert!(state.reasoning);
+    }
+
+    #[test]
+    fn empty_prefix() {
+        let marker = ReasoningMarker::default();
+        let mut state = MarkerState::default();
+
+        assert_eq!(marker.prefix_lines(&mut state, "a\nb"), "a\nb");
+        assert!(state.reasoning);
+    }
+
+    #[tokio::test]
+    async fn split_suffix_before_content() {
+        let chunks = mark(&[
+            r#"{"role":"assistant","reasoning_content":"think\nha"}"#,
+            r#"{"reasoning_content":"rd\n"}"#,
+            r#"{"reasoning_content":"done","content":"answer"}"#,
+            r#"{"content":" end"}"#,
+        ])
+        .await;
+
+        assert_eq!(
+            deltas(&chunks),
+            [
+                (Some("> think\n> ha"), None),
+                (Some("rd\n"), None),
+                (Some("> done\n\n"), None),
+                (None, Some("answer")),
+                (None, Some(" end")),
+            ]
+        );
+        assert_eq!(
+            chunks[0].choices[0].delta.role.as_deref(),
+            Some("assistant")
+        );
+        assert_eq!(chunks[3].choices[0].delta.role, None);
+    }
+
+    #[tokio::test]
+    async fn append_suffix_on_finish() {
+        let chunks = mark(&[
+            r#"{"reasoning_content":"think"}"#,
+            r#"[{"index":0,"delta":{"reasoning_content":"\nmore"},"finish_reason":"length"}]"#,
+        ])
+        .await;
+
+        assert_eq!(
+            deltas(&chunks),
+            [(Some("> think"), None), (Some("\n> more\n\n"), None)]
+        );
+        assert!(chunks[1].choices[0].finish_reason.is_some());
+    }
+}
@@ -1 +1,55 @@
 pub mod deepseek;
+pub mod fence;
+pub mod finish;
+pub mod marker;
+pub mod newline;
+pub mod reasoning_loop;
+
+use std::mem;
+
+use crate::sse::{Chunk, Delta};
+
+/// split the chunk to one chunk per choice, the chunk level `prompt_logprobs` is kept in the first
+/// one
+fn split_choices(mut chunk: Chunk) -> Vec<Chunk> {
+    let choices = mem::take(&mut chunk.choices);
+
+    choices
+        .into_iter()
+        .enumerate()
+        .map(|(i, choice)| {
+            let mut chunk = chunk.clone();
+            if i > 0 {
+                chunk.prompt_logprobs = None;
+            }
+            chunk.choices.push(choice);
+
+            chunk
+        })
+        .collect()
+}
+
+/// the prompt logprobs are sent once, with the reasoning half of a split chunk
+fn clear_prompt_logprobs(chunk: &mut Chunk) {
+    chunk.prompt_logprobs = None;
+    chunk.choices[0].prompt_logprobs = None;
+}
+
+/// build the reasoning half of a think tag split chunk, `logprobs`, `finish_reason` and
+/// `annotations` belong to the content half, so they are removed here, `role` and
+/// `prompt_logprobs` belong to the first half
+fn split_reasoning_chunk(chunk: &Chunk, reasoning_content: String) -> Chunk {
+    let mut reasoning_chunk = chunk.clone();
+    let choice = &mut reasoning_chunk.choices[0];
+    choice.delta = Delta {
+        role: choice.delta.role.take(),
+        reasoning_content: Some(reasoning_content),
+        content: None,
+        annotations: None,
+    };
+    choice.logprobs = None;
+    choice.finish_reason = None;
+    choice.stop_reason = None;
+
+    reasoning_chunk
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::pin::pin;
+
+use futures_util::{Stream, StreamExt};
+
+use crate::sse::Chunk;
+
+#[derive(Debug, Default, Copy, Clone)]
+struct PendingCr {
+    reasoning_content: bool,
+    content: bool,
+}
+
+/// normalize CRLF to LF in `reasoning_content` and `content`, a `\r` at the end of chunk is held
+/// until the next chunk of the same choice, in case the `\n` is in the next chunk
+pub async gen fn normalize_newlines<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+) -> anyhow::Result<Chunk> {
+    let mut pending_crs = HashMap::<i64, PendingCr>::new();
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let mut chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        for choice in &mut chunk.choices {
+            let pending_cr = pending_crs.entry(choice.index).or_default();
+
+            if let Some(text) = &mut choice.delta.reasoning_content {
+                normalize(text, &mut pending_cr.reasoning_content);
+            }
+            if let Some(text) = &mut choice.delta.content {
+                normalize(text, &mut pending_cr.content);
+            }
+        }
+
+        yield Ok(chunk);
+    }
+}
+
+fn normalize(text: &mut String, pending_cr: &mut bool) {
+    if text.is_empty() {
+        return;
+    }
+
+    // the held `\r` isn't followed by `\n`, restore it
+    if *pending_cr && !text.starts_with('\n') {
+        text.insert(0, '\r');
+    }
+
+    *pending_cr = text.ends_with('\r');
+    if *pending_cr {
+        text.pop();
+    }
+
+    if text.contains("\r\n") {
+        *text = text.replace("\r\n", "\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{TryStreamExt, stream};
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+
+    async fn normalized(deltas: &[&str]) -> Vec<(String, String)> {
+        let st = stream::iter(build_chunks(deltas).unwrap().into_iter().map(Ok));
+
+        StreamAsyncIterAdapter(normalize_newlines(st))
+            .map_ok(|chunk| {
+                let delta = &chunk.choices[0].delta;
+
+                (
+                    delta.reasoning_content.clone().unwrap_or_default(),
+                    delta.content.clone().unwrap_or_default(),
+                )
+            })
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn normalize_crlf_split_across_chunks() {
+        let texts = normalized(&[
+            r#"{"reasoning_content":"a\r\nb\r"}"#,
+            r#"{"reasoning_content":"\nc\r"}"#,
+            r#"{"reasoning_content":"d"}"#,
+            r#"{"content":"e\r"}"#,
+            r#"{"content":"\n"}"#,
+        ])
+        .await;
+
+        let reasoning = texts.iter().map(|(reasoning, _)| reasoning.as_str());
+        assert_eq!(reasoning.collect::<String>(), "a\nb\nc\rd");
+        let content = texts.iter().map(|(_, content)| content.as_str());
+        assert_eq!(content.collect::<String>(), "e\n");
+    }
+}
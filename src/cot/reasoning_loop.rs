@@ -0,0 +1,279 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::pin;
+
+use futures_util::{Stream, StreamExt};
+use tracing::warn;
+
+use crate::cli::ReasoningLoopAction;
+use crate::sse::{Choice, Chunk, Delta, FinishReason};
+
+/// the repeated phrase is at least this long in bytes, shorter ones are usually separators
+const MIN_PERIOD: usize = 8;
+/// the repeated phrase is at most this long in bytes
+const MAX_PERIOD: usize = 512;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ReasoningLoop {
+    /// the times a phrase repeats back to back in the reasoning tail to be a loop
+    pub repeats: usize,
+    pub action: ReasoningLoopAction,
+}
+
+#[derive(Debug)]
+struct LoopState {
+    /// the newest reasoning bytes, at most [`MAX_PERIOD`]
+    tail: VecDeque<u8>,
+    /// `runs[period]` counts the newest bytes equal to the byte `period` bytes before them, so
+    /// each new byte is compared once per period instead of rescanning the reasoning tail
+    runs: Vec<usize>,
+    cut: bool,
+}
+
+impl Default for LoopState {
+    fn default() -> Self {
+        Self {
+            tail: VecDeque::with_capacity(MAX_PERIOD),
+            runs: vec![0; MAX_PERIOD + 1],
+            cut: false,
+        }
+    }
+}
+
+impl LoopState {
+    /// push the reasoning, return the phrase length when the reasoning ends with a phrase repeated
+    /// `repeats` times
+    fn push(&mut self, reasoning: &str, repeats: usize) -> Option<usize> {
+        for &byte in reasoning.as_bytes() {
+            for period in 1..=self.tail.len() {
+                self.runs[period] = match self.tail[self.tail.len() - period] == byte {
+                    true => self.runs[period] + 1,
+                    false => 0,
+                };
+            }
+
+            if self.tail.len() == MAX_PERIOD {
+                self.tail.pop_front();
+            }
+            self.tail.push_back(byte);
+        }
+
+        // the phrase and its `repeats - 1` copies before it, a run of a shorter phrase, e.g. a
+        // markdown rule, repeats at every longer period too, it is no loop
+        (MIN_PERIOD..=MAX_PERIOD).find(|&period| {
+            self.runs[period] >= period * (repeats - 1)
+                && !(1..MIN_PERIOD).any(|short| self.runs[short] >= period - short)
+        })
+    }
+}
+
+/// cut the reasoning of a choice off when its tail keeps repeating the same phrase, `choices` is
+/// the requested `n`, the stream ends once every choice is aborted, so the backend stops
+/// generating
+pub async gen fn cut_reasoning_loop<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    reasoning_loop: ReasoningLoop,
+    choices: usize,
+) -> anyhow::Result<Chunk> {
+    let mut states = HashMap::<i64, LoopState>::new();
+    let mut aborted = 0;
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let mut chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        // the usage chunk has no choice
+        if chunk.choices.is_empty() {
+            yield Ok(chunk);
+            continue;
+        }
+
+        let mut finish_choices = vec![];
+        chunk.choices.retain_mut(|choice| {
+            let state = states.entry(choice.index).or_default();
+            if state.cut {
+                return match reasoning_loop.action {
+                    ReasoningLoopAction::Abort => false,
+
+                    ReasoningLoopAction::Content => {
+                        choice.delta.reasoning_content = None;
+
+                        choice.delta.content.is_some() || choice.finish_reason.is_some()
+                    }
+                };
+            }
+
+            let Some(reasoning) = &choice.delta.reasoning_content else {
+                return true;
+            };
+
+            let Some(period) = state.push(reasoning, reasoning_loop.repeats) else {
+                return true;
+            };
+
+            warn!(
+                index = choice.index,
+                period,
+                action = ?reasoning_loop.action,
+                "reasoning is looping, cut it off"
+            );
+
+            state.cut = true;
+            state.tail = VecDeque::new();
+            state.runs = vec![];
+
+            if reasoning_loop.action == ReasoningLoopAction::Abort {
+                aborted += 1;
+
+                // the choice may just finish along with the loop
+                if choice.finish_reason.is_some() {
+                    return true;
+                }
+
+                finish_choices.push(Choice {
+                    index: choice.index,
+                    delta: Delta {
+                        role: None,
+                        reasoning_content: None,
+                        content: None,
+                        annotations: None,
+                    },
+                    logprobs: None,
+                    finish_reason: Some(FinishReason::Length),
+                    stop_reason: None,
+                    prompt_logprobs: None,
+                });
+            }
+
+            true
+        });
+
+        let finish_chunk = (!finish_choices.is_empty()).then(|| Chunk {
+            choices: finish_choices,
+            usage: None,
+            prompt_logprobs: None,
+            ..chunk.clone()
+        });
+
+        // all choices of the chunk may be dropped, keep it only for the usage
+        if !chunk.choices.is_empty() || chunk.usage.is_some() {
+            yield Ok(chunk);
+        }
+        if let Some(finish_chunk) = finish_chunk {
+            yield Ok(finish_chunk);
+        }
+
+        if aborted >= choices {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{TryStreamExt, stream};
+    use serde_json::json;
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+
+    async fn cut(deltas: &[String], action: ReasoningLoopAction, choices: usize) -> Vec<Chunk> {
+        let deltas = deltas.iter().map(String::as_str).collect::<Vec<_>>();
+        let st = stream::iter(build_chunks(&deltas).unwrap().into_iter().map(Ok));
+        let reasoning_loop = ReasoningLoop { repeats: 3, action };
+
+        StreamAsyncIterAdapter(cut_reasoning_loop(st, reasoning_loop, choices))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    fn reasoning(text: &str) -> String {
+        json!({ "reasoning_content": text }).to_string()
+    }
+
+    #[test]
+    fn detect_repeated_phrase() {
+        let mut state = LoopState::default();
+        assert_eq!(state.push("intro. ", 3), None);
+        // the phrase is split across the pushes
+        for piece in [
+            "wait, let me",
+            " check again. ",
+            "wait, let me check again. wait,",
+        ] {
+            assert_eq!(state.push(piece, 3), None);
+        }
+        assert_eq!(state.push(" let me check again. ", 3), Some(26));
+
+        // the multibyte phrase, the period is in bytes
+        let mut state = LoopState::default();
+        assert_eq!(state.push(&"再想一想。".repeat(3), 3), Some(15));
+
+        // the short separators are no loop
+        for separator in ["-", "=", " ", "| -- "] {
+            let mut state = LoopState::default();
+            assert_eq!(state.push(&separator.repeat(100), 3), None, "{separator:?}");
+        }
+        let mut state = LoopState::default();
+        assert_eq!(state.push(&"ab".repeat(3), 3), None);
+
+        // the phrase made of a separator still loops
+        let mut state = LoopState::default();
+        assert_eq!(state.push(&"next --------".repeat(3), 3), Some(13));
+
+        // the phrase longer than the max period is no loop
+        let phrase = "x".repeat(MAX_PERIOD) + "y";
+        let mut state = LoopState::default();
+        assert_eq!(state.push(&phrase.repeat(3), 3), None);
+    }
+
+    #[tokio::test]
+    async fn abort_looping_choice() {
+        let mut deltas = vec![reasoning("first, ")];
+        deltas.extend((0..5).map(|_| reasoning("round and round. ")));
+        deltas.push(json!({ "content": "never sent" }).to_string());
+
+        let chunks = cut(&deltas, ReasoningLoopAction::Abort, 1).await;
+        let reasoning = chunks
+            .iter()
+            .filter_map(|chunk| chunk.choices[0].delta.reasoning_content.as_deref())
+            .collect::<String>();
+        assert_eq!(
+            reasoning,
+            format!("first, {}", "round and round. ".repeat(3))
+        );
+
+        // the stream ends after the finish chunk of the only choice
+        let last = &chunks.last().unwrap().choices[0];
+        assert_eq!(last.finish_reason, Some(FinishReason::Length));
+        assert!(last.delta.content.is_none());
+    }
+
+    #[tokio::test]
+    async fn skip_to_content_after_loop() {
+        let mut deltas = (0..5)
+            .map(|_| reasoning("round and round. "))
+            .collect::<Vec<_>>();
+        deltas.push(json!({ "reasoning_content": "more", "content": "answer" }).to_string());
+
+        let chunks = cut(&deltas, ReasoningLoopAction::Content, 1).await;
+        let reasoning = chunks
+            .iter()
+            .filter_map(|chunk| chunk.choices[0].delta.reasoning_content.as_deref())
+            .collect::<String>();
+        assert_eq!(reasoning, "round and round. ".repeat(3));
+
+        let last = &chunks.last().unwrap().choices[0];
+        assert_eq!(last.delta.content.as_deref(), Some("answer"));
+        assert!(last.delta.reasoning_content.is_none());
+        assert!(last.finish_reason.is_none());
+    }
+}
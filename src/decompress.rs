@@ -0,0 +1,116 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use axum::body::{Body, Bytes};
+use axum::extract::{Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::Next;
+use axum::response::Response;
+use futures_util::{StreamExt, TryStreamExt};
+use tracing::warn;
+
+use crate::{ServerState, error};
+
+/// the small bodies compress much better than the ratio limit is meant for, the ratio only
+/// applies to the decompressed body above the size
+const RATIO_MIN_SIZE: usize = 64 * 1024;
+
+/// the compressed bytes of the request body read so far
+#[derive(Debug, Clone, Default)]
+struct CompressedSize(Arc<AtomicUsize>);
+
+/// count the compressed request body, runs before the decompression
+pub async fn count_compressed(
+    state: State<Arc<ServerState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if (state.max_decompress_ratio.is_none() && state.max_decompressed_size.is_none())
+        || !request.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return next.run(request).await;
+    }
+
+    let compressed_size = CompressedSize::default();
+    request.extensions_mut().insert(compressed_size.clone());
+
+    let request = request.map(|body| {
+        Body::from_stream(body.into_data_stream().inspect_ok(move |data| {
+            compressed_size.0.fetch_add(data.len(), Ordering::Relaxed);
+        }))
+    });
+
+    next.run(request).await
+}
+
+/// buffer the decompressed request body, reject it with 413 once it exceeds
+/// `--max-decompressed-size` or the compressed size times `--max-decompress-ratio`, runs after the
+/// decompression
+pub async fn limit_decompressed(
+    state: State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(compressed_size) = request.extensions().get::<CompressedSize>().cloned() else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let mut data = vec![];
+    let mut st = body.into_data_stream();
+    while let Some(chunk) = st.next().await {
+        let chunk = match chunk {
+            Err(err) => {
+                return error::openai_error(
+                    StatusCode::BAD_REQUEST,
+                    format!("decompress request body failed: {err}"),
+                    None,
+                );
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        data.extend_from_slice(&chunk);
+
+        if let Some(max_size) = state.max_decompressed_size
+            && data.len() > max_size.get()
+        {
+            warn!(
+                decompressed = data.len(),
+                max_size, "decompressed request body is too large"
+            );
+
+            return error::openai_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("decompressed request body exceeds {max_size} bytes"),
+                Some("request_too_large"),
+            );
+        }
+
+        // the compressed bytes are read before they are decompressed
+        let compressed = compressed_size.0.load(Ordering::Relaxed);
+        if let Some(ratio) = state.max_decompress_ratio
+            && exceeds(data.len(), compressed, ratio)
+        {
+            warn!(
+                decompressed = data.len(),
+                compressed, ratio, "decompressed request body is too large"
+            );
+
+            return error::openai_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("decompressed request body exceeds {ratio} times the compressed body"),
+                Some("request_too_large"),
+            );
+        }
+    }
+
+    next.run(Request::from_parts(parts, Body::from(Bytes::from(data))))
+        .await
+}
+
+fn exceeds(decompressed: usize, compressed: usize, ratio: NonZeroUsize) -> bool {
+    decompressed > RATIO_MIN_SIZE.max(compressed.saturating_mul(ratio.get()))
+}
@@ -0,0 +1,277 @@
+use std::collections::HashSet;
+use std::mem;
+use std::pin::pin;
+
+use axum::body::{self, Body, Bytes};
+use axum::http::{StatusCode, header};
+use axum::response::Response;
+use futures_util::{Stream, StreamExt};
+use serde_json::Value;
+
+use crate::adapter::StreamAsyncIterAdapter;
+
+const SSE_DATA_FIELD: &str = "data:";
+
+/// prepend the prompt to the completion `text` when the backend doesn't honor `echo`
+pub async fn enforce(
+    response: Response,
+    prompt: String,
+    streaming: bool,
+) -> Result<Response, (StatusCode, String)> {
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+
+    if streaming {
+        let body = Body::from_stream(StreamAsyncIterAdapter(echo_stream(
+            body.into_data_stream(),
+            prompt,
+        )));
+
+        return Ok(Response::from_parts(parts, body));
+    }
+
+    let data = body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+    let mut completion = serde_json::from_slice::<Value>(&data)
+        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+
+    echo_choices(&mut completion, &prompt, &mut HashSet::new());
+
+    // the body size is changed
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    let data = serde_json::to_vec(&completion)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    Ok(Response::from_parts(parts, Body::from(data)))
+}
+
+/// the first text of each choice needs the prompt, the events without it are forwarded as is
+async gen fn echo_stream<S: Stream<Item = Result<Bytes, axum::Error>>>(
+    st: S,
+    prompt: String,
+) -> Result<Bytes, axum::Error> {
+    let mut st = pin!(st);
+    let mut buf = Vec::new();
+    let mut echoed = HashSet::new();
+
+    while let Some(data) = st.next().await {
+        match data {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(data) => buf.extend_from_slice(&data),
+        }
+
+        while let Some(end) = event_end(&buf) {
+            let rest = buf.split_off(end);
+            let event = mem::replace(&mut buf, rest);
+
+            // comments, `[DONE]` and the chunks of the echoed choices are kept
+            let event = echo_event(&event, &prompt, &mut echoed).unwrap_or(event);
+
+            yield Ok(event.into());
+        }
+    }
+
+    if !buf.is_empty() {
+        yield Ok(buf.into());
+    }
+}
+
+/// the end of the first event in `buf`, the event ends with an empty line, the line ends with
+/// `\r\n`, `\n` or `\r`
+fn event_end(buf: &[u8]) -> Option<usize> {
+    let mut line_start = false;
+    let mut i = 0;
+    while i < buf.len() {
+        let line_end = match buf[i] {
+            b'\n' => i + 1,
+            b'\r' => match buf.get(i + 1) {
+                // the `\n` may be in the next data
+                None => return None,
+                Some(b'\n') => i + 2,
+                Some(_) => i + 1,
+            },
+
+            _ => {
+                line_start = false;
+                i += 1;
+
+                continue;
+            }
+        };
+
+        if line_start {
+            return Some(line_end);
+        }
+
+        line_start = true;
+        i = line_end;
+    }
+
+    None
+}
+
+/// rewrite the `data` of the event when a choice is echoed, the other fields are kept
+fn echo_event(event: &[u8], prompt: &str, echoed: &mut HashSet<i64>) -> Option<Vec<u8>> {
+    let event = str::from_utf8(event).ok()?;
+    let lines = event.lines().filter(|line| !line.is_empty());
+
+    let data = lines
+        .clone()
+        .filter_map(|line| line.strip_prefix(SSE_DATA_FIELD))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect::<Vec<_>>();
+    if data.is_empty() {
+        return None;
+    }
+
+    let mut chunk = serde_json::from_str::<Value>(&data.join("\n")).ok()?;
+    if !echo_choices(&mut chunk, prompt, echoed) {
+        return None;
+    }
+
+    let mut new_event = String::with_capacity(event.len() + prompt.len());
+    let mut data_written = false;
+    for line in lines {
+        if !line.starts_with(SSE_DATA_FIELD) {
+            new_event.push_str(line);
+            new_event.push('\n');
+        } else if !data_written {
+            data_written = true;
+            new_event.push_str(SSE_DATA_FIELD);
+            new_event.push(' ');
+            new_event.push_str(&chunk.to_string());
+            new_event.push('\n');
+        }
+    }
+    new_event.push('\n');
+
+    Some(new_event.into_bytes())
+}
+
+/// prepend the prompt to the first text of each choice, `echoed` is the indexes of the echoed
+/// choices, return whether any choice is echoed
+fn echo_choices(completion: &mut Value, prompt: &str, echoed: &mut HashSet<i64>) -> bool {
+    let Some(choices) = completion.get_mut("choices").and_then(Value::as_array_mut) else {
+        return false;
+    };
+
+    let mut changed = false;
+    for choice in choices {
+        let index = choice
+            .get("index")
+            .and_then(Value::as_i64)
+            .unwrap_or_default();
+
+        if let Some(Value::String(text)) = choice.get_mut("text")
+            && echoed.insert(index)
+            && (text.is_empty() || !text.starts_with(prompt) && !prompt.starts_with(text.as_str()))
+        {
+            text.insert_str(0, prompt);
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use futures_util::{TryStreamExt, stream};
+    use serde_json::json;
+
+    use super::*;
+
+    async fn echo(pieces: &[&'static str]) -> String {
+        let st = stream::iter(
+            pieces
+                .iter()
+                .map(|piece| Ok(Bytes::from_static(piece.as_bytes()))),
+        );
+        let data = StreamAsyncIterAdapter(echo_stream(st, "say: ".to_string()))
+            .try_fold(Vec::new(), |mut data, piece| async move {
+                data.extend_from_slice(&piece);
+                Ok(data)
+            })
+            .await
+            .unwrap();
+
+        String::from_utf8(data).unwrap()
+    }
+
+    /// the texts of each choice
+    fn texts(body: &str) -> Vec<String> {
+        let mut texts = vec![String::new(); 2];
+        for data in body.lines().filter_map(|line| line.strip_prefix("data: ")) {
+            let Ok(chunk) = serde_json::from_str::<Value>(data) else {
+                continue;
+            };
+            for choice in chunk["choices"].as_array().unwrap() {
+                texts[choice["index"].as_u64().unwrap() as usize]
+                    .push_str(choice["text"].as_str().unwrap());
+            }
+        }
+
+        texts
+    }
+
+    #[tokio::test]
+    async fn echo_stream_choices() {
+        let body = echo(&[
+            ": comment\n\n",
+            "id: 1\nevent: completion\ndata: {\"choices\":[{\"index\":0,\"text\":\"\"}]}\n\n",
+            "data: {\"choices\":[{\"index\":1,\"text\":\"hi\"}]}\r\n\r",
+            "\ndata: {\"choices\":[{\"index\":0,\"text\":\"hello\"}]}\n\n",
+            "data: [DONE]\n\n",
+        ])
+        .await;
+
+        assert_eq!(texts(&body), ["say: hello", "say: hi"]);
+        assert!(body.starts_with(": comment\n\nid: 1\nevent: completion\ndata: "));
+        assert!(body.ends_with("data: [DONE]\n\n"));
+
+        // the backend honors echo
+        let body = echo(&[
+            "data: {\"choices\":[{\"index\":0,\"text\":\"say\"}]}\n\n",
+            "data: {\"choices\":[{\"index\":0,\"text\":\": hello\"}]}\n\n",
+        ])
+        .await;
+        assert_eq!(texts(&body)[0], "say: hello");
+    }
+
+    #[tokio::test]
+    async fn echo_non_stream_choices() {
+        let completion = json!({
+            "choices": [
+                {"index": 0, "text": "hello"},
+                {"index": 1, "text": ""},
+                {"index": 2, "text": "say: hi"},
+            ],
+        });
+        let response = Response::new(Body::from(completion.to_string()));
+
+        let response = enforce(response, "say: ".to_string(), false).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let data = body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let completion = serde_json::from_slice::<Value>(&data).unwrap();
+
+        let texts = completion["choices"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|choice| choice["text"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["say: hello", "say: ", "say: hi"]);
+    }
+}
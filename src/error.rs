@@ -0,0 +1,38 @@
+use axum::Json;
+use axum::extract::rejection::JsonRejection;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+
+const INVALID_REQUEST_ERROR: &str = "invalid_request_error";
+
+/// build the OpenAI style `{"error": {...}}` response
+pub fn openai_error(
+    status: StatusCode,
+    message: impl Into<String>,
+    code: Option<&str>,
+) -> Response {
+    let body = json!({
+        "error": {
+            "message": message.into(),
+            "type": INVALID_REQUEST_ERROR,
+            "param": null,
+            "code": code,
+        }
+    });
+
+    (status, Json(body)).into_response()
+}
+
+/// convert the JSON body rejection, include wrong `Content-Type`, to the OpenAI style error
+pub fn json_rejection(rejection: JsonRejection) -> Response {
+    match rejection {
+        JsonRejection::MissingJsonContentType(_) => openai_error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "request Content-Type must be application/json",
+            Some("invalid_content_type"),
+        ),
+
+        rejection => openai_error(rejection.status(), rejection.body_text(), None),
+    }
+}
@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tracing::{info, warn};
+
+/// the last seen upstream `system_fingerprint` of each model, a change usually means the backend
+/// swapped the model
+#[derive(Debug, Default)]
+pub struct Fingerprints {
+    fingerprints: Mutex<HashMap<String, String>>,
+}
+
+impl Fingerprints {
+    /// record the fingerprint, warn when it differs from the last seen one of the model
+    pub fn observe(&self, model: &str, fingerprint: &str) {
+        let mut fingerprints = self.fingerprints.lock().unwrap();
+        match fingerprints.get_mut(model) {
+            None => {
+                info!(model, fingerprint, "record system fingerprint");
+
+                fingerprints.insert(model.to_string(), fingerprint.to_string());
+            }
+
+            Some(last) if last != fingerprint => {
+                warn!(
+                    model,
+                    last_fingerprint = %last,
+                    fingerprint,
+                    "system fingerprint changed"
+                );
+
+                *last = fingerprint.to_string();
+            }
+
+            Some(_) => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, String> {
+        self.fingerprints.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,151 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use axum::body::{Body, Bytes};
+use axum::extract::{FromRequest, Request, State};
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::warn;
+
+use crate::{ServerState, error};
+
+/// reject the `POST` request body nesting deeper than `--max-json-depth` or carrying more values
+/// than `--max-json-elements` with 400, it is checked before the body is deserialized
+pub async fn limit_json(state: State<Arc<ServerState>>, request: Request, next: Next) -> Response {
+    if request.method() != Method::POST
+        || (state.max_json_depth.is_none() && state.max_json_elements.is_none())
+    {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+
+    // keep the extensions, so the body limit of the extractor is still applied
+    let mut body_request = Request::new(body);
+    *body_request.extensions_mut() = parts.extensions.clone();
+    let data = match Bytes::from_request(body_request, &()).await {
+        Err(rejection) => {
+            return error::openai_error(rejection.status(), rejection.body_text(), None);
+        }
+
+        Ok(data) => data,
+    };
+
+    if let Err(err) = check(&data, state.max_json_depth, state.max_json_elements) {
+        warn!(%err, "reject request JSON");
+
+        return error::openai_error(StatusCode::BAD_REQUEST, err, Some("json_too_complex"));
+    }
+
+    next.run(Request::from_parts(parts, Body::from(data))).await
+}
+
+/// scan the nesting depth and the values count of the JSON, the invalid JSON is left to the
+/// deserialization
+fn check(
+    data: &[u8],
+    max_depth: Option<NonZeroUsize>,
+    max_elements: Option<NonZeroUsize>,
+) -> Result<(), String> {
+    let max_depth = max_depth.map_or(usize::MAX, NonZeroUsize::get);
+    let max_elements = max_elements.map_or(usize::MAX, NonZeroUsize::get);
+
+    let mut depth = 0usize;
+    let mut elements = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    // the container is just opened, its first value is not counted yet
+    let mut opened = false;
+
+    for &b in data {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+
+            continue;
+        }
+
+        if b.is_ascii_whitespace() {
+            continue;
+        }
+
+        if opened {
+            opened = false;
+            if b != b']' && b != b'}' {
+                elements += 1;
+            }
+        }
+
+        match b {
+            b'"' => in_string = true,
+
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max_depth {
+                    return Err(format!("request JSON nests deeper than {max_depth}"));
+                }
+
+                opened = true;
+            }
+
+            b']' | b'}' => depth = depth.saturating_sub(1),
+
+            b',' => elements += 1,
+
+            _ => {}
+        }
+
+        if elements > max_elements {
+            return Err(format!("request JSON has more than {max_elements} values"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(n: usize) -> Option<NonZeroUsize> {
+        NonZeroUsize::new(n)
+    }
+
+    #[test]
+    fn check_depth() {
+        assert!(check(br#"{"a":[[1]]}"#, limit(3), None).is_ok());
+        assert_eq!(
+            check(br#"{"a":[[[1]]]}"#, limit(3), None).unwrap_err(),
+            "request JSON nests deeper than 3"
+        );
+
+        // the closed containers don't add to the depth
+        assert!(check(br#"[[1],[2],{"a":1}]"#, limit(2), None).is_ok());
+    }
+
+    #[test]
+    fn check_elements() {
+        // the containers and the scalars inside the top level value are counted
+        assert!(check(br#"[[1, 2], [3]]"#, None, limit(5)).is_ok());
+        assert!(check(br#"[[1, 2], [3]]"#, None, limit(4)).is_err());
+
+        // the key value pair is one value, the empty containers have no value
+        assert!(check(br#"{"a": 1, "b": {}, "c": []}"#, None, limit(3)).is_ok());
+        assert!(check(br#"{"a": 1, "b": {}, "c": []}"#, None, limit(2)).is_err());
+    }
+
+    #[test]
+    fn skip_string_content() {
+        let data = br#"{"a": "[[[{,,,\"]]]"}"#;
+        assert!(check(data, limit(1), limit(1)).is_ok());
+
+        // the escaped backslash ends before the quote
+        let data = br#"["\\", [1]]"#;
+        assert!(check(data, limit(1), None).is_err());
+    }
+}
@@ -1,55 +1,242 @@
 #![feature(gen_blocks)]
 #![feature(async_iterator)]
+#![cfg_attr(test, feature(test))]
 
 mod adapter;
+mod admin;
+mod bench;
+mod buffer;
+mod cancel;
+mod check;
 mod cli;
-mod cot;
-mod sse;
+mod client_ip;
+pub mod cot;
+mod decompress;
+mod echo;
+mod error;
+mod fingerprint;
+mod json_limit;
+mod limit;
+mod listener;
+mod logit_bias;
+mod moderation;
+mod number;
+#[cfg(feature = "otel")]
+mod otel;
+mod pace;
+mod price;
+mod ready;
+mod reasoning_ratio;
+mod reasoning_sink;
+mod redact;
+mod request_id;
+mod script;
+mod selftest;
+mod shed;
+mod smooth;
+pub mod sse;
+mod summarize;
+pub mod token_cache;
+mod tokenizer;
+mod transform;
+pub mod truncate;
+mod utf8;
+
+#[cfg(test)]
+extern crate test;
+
+#[cfg(test)]
+mod tests;
 
 use std::collections::{HashMap, VecDeque};
+use std::future::ready;
 use std::io;
-use std::sync::Arc;
-
-use axum::body::Body;
-use axum::extract::State;
+use std::mem;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use axum::body::{Body, Bytes};
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{ConnectInfo, FromRequest, Path, Request, State};
 use axum::http::Uri;
-use axum::response::sse::Event;
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive};
 use axum::response::{IntoResponse, Response, Sse};
 use axum::{
-    Json, Router,
-    http::{HeaderMap, Method, StatusCode, header},
-    routing::post,
+    Extension, Json, Router,
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    routing::{get, post},
 };
 use clap::Parser;
 use educe::Educe;
-use futures_util::{FutureExt, TryStreamExt, select};
-use reqwest::{Client, Url};
+use futures_util::{FutureExt, StreamExt, TryStreamExt, future, select, stream};
+use reqwest::redirect::Policy;
+use reqwest::{Client, NoProxy, Proxy, Url};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tiktoken_rs::{CoreBPE, Rank, o200k_base};
-use tokio::net::TcpListener;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tiktoken_rs::{CoreBPE, Rank, get_bpe_from_tokenizer};
 use tokio::signal::unix::{self, SignalKind};
+use tokio::sync::Notify;
+use tokio::{task, time};
 use tower_http::cors::{AllowHeaders, AllowPrivateNetwork, Any, CorsLayer};
+use tower_http::decompression::RequestDecompressionLayer;
 use tracing::level_filters::LevelFilter;
-use tracing::{error, info, instrument, subscriber};
+use tracing::{Instrument, Span, debug, error, info, info_span, instrument, subscriber, warn};
 use tracing_subscriber::filter::Targets;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{Registry, fmt};
 
 use crate::adapter::StreamAsyncIterAdapter;
-use crate::cli::{Cli, CotParser};
-use crate::cot::deepseek;
-use crate::sse::send_stream_request;
+use crate::cancel::Cancels;
+use crate::cli::{
+    Cli, Command, CotParser, DeniedStreamAction, DuplicateAuth, FollowRedirects, MaxTokensField,
+    ModerationMode, StopOverflow, TEMPLATE_PROMPT, TokenizerFallback,
+};
+use crate::client_ip::ClientIp;
+use crate::cot::marker::{self, ReasoningMarker};
+use crate::cot::reasoning_loop::{self, ReasoningLoop};
+use crate::cot::{deepseek, fence, finish, newline};
+use crate::fingerprint::Fingerprints;
+use crate::limit::ConcurrencyLimiter;
+use crate::listener::{ClientListener, PeerAddr};
+use crate::moderation::Moderator;
+use crate::pace::PaceRate;
+use crate::price::Price;
+use crate::reasoning_sink::ReasoningSink;
+use crate::redact::Redactor;
+use crate::request_id::{REQUEST_ID_HEADER, RequestIdSource};
+use crate::script::ResponseScript;
+use crate::shed::LoadShedder;
+use crate::smooth::Smoother;
+use crate::sse::{Choice, Chunk, Delta, END_SSE_DATA, UpstreamRejected, send_stream_request};
+use crate::summarize::Summarizer;
+use crate::tokenizer::{Encoder, Encoders};
+use crate::truncate::{
+    Message, TruncateOptions, encode, encode_messages, restore_head, truncate_encoded_message,
+    truncate_encoded_messages,
+};
+
+const MAX_REDIRECTS: usize = 10;
+const OUTBOUND_PROXY_SCHEMES: &[&str] = &["http", "https", "socks5", "socks5h"];
+const SSE_INITIAL_COMMENT: &str = "connected";
+const ESTIMATED_COST_HEADER: &str = "x-estimated-cost";
+const NO_TRUNCATE_HEADER: &str = "x-no-truncate";
+const DEBUG_RAW_HEADER: &str = "x-debug-raw";
+const DEBUG_RAW_FIELD: &str = "_raw";
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+/// the methods forwarded without body when the client sends none
+const BODYLESS_METHODS: &[Method] = &[
+    Method::GET,
+    Method::HEAD,
+    Method::DELETE,
+    Method::OPTIONS,
+    Method::TRACE,
+];
+/// the upstream error body is only peeked up to this length for the log
+const ERROR_BODY_PREVIEW_LEN: usize = 1024;
 
 #[derive(Educe)]
 #[educe(Debug)]
 struct ServerState {
     backend: Url,
     client: Client,
+    trust_proxy: bool,
     input_max_token: Option<usize>,
+    truncate_options: TruncateOptions,
+    allow_no_truncate: bool,
+    allow_debug_raw: bool,
+    max_prompt_chars: Option<usize>,
+    max_json_depth: Option<NonZeroUsize>,
+    max_json_elements: Option<NonZeroUsize>,
+    moderator: Option<Moderator>,
+    moderation_mode: ModerationMode,
+    prompt_template: Option<String>,
+    role_map: HashMap<String, String>,
+    user_message_template: Option<String>,
+    output_max_token: Option<usize>,
+    context_window: Option<usize>,
+    context_min_output_token: usize,
+    chat_max_tokens_field: Option<MaxTokensField>,
+    encoders: Encoders,
     #[educe(Debug(ignore))]
-    bpe: CoreBPE,
+    logit_bias_tokenizer: Option<CoreBPE>,
     cot_parser: Option<CotParser>,
+    model_cot_parsers: HashMap<String, Option<CotParser>>,
+    cot_fence_label: String,
+    reasoning_loop: Option<ReasoningLoop>,
+    summarizer: Option<Summarizer>,
+    reasoning_ratio_alert: Option<f64>,
+    reasoning_sink: Option<Arc<ReasoningSink>>,
+    lenient_sse: bool,
+    strict_chunks: bool,
+    drop_after_finish: bool,
+    reasoning_field: Option<String>,
+    normalize_newlines: bool,
+    reasoning_marker: Option<ReasoningMarker>,
+    redactor: Option<Arc<Redactor>>,
+    derive_user_from: Option<HeaderName>,
+    derive_user_salt: Option<String>,
+    duplicate_auth: DuplicateAuth,
+    allowed_proxy_paths: Vec<String>,
+    allowed_proxy_methods: Vec<Method>,
+    downgrade_proxy_stream: bool,
+    default_seed: Option<i64>,
+    inject_stream_usage: bool,
+    max_stop_sequences: Option<usize>,
+    stop_overflow: StopOverflow,
+    deny_streaming: bool,
+    deny_non_streaming: bool,
+    denied_stream_action: DeniedStreamAction,
+    enforce_echo: bool,
+    stream_limiter: Option<ConcurrencyLimiter>,
+    /// the in-flight requests of each client IP, it shares the stream slot counting
+    connection_limiter: Option<ConcurrencyLimiter>,
+    max_decompress_ratio: Option<NonZeroUsize>,
+    max_decompressed_size: Option<NonZeroUsize>,
+    smoother: Option<Smoother>,
+    #[educe(Debug(ignore))]
+    priority_keys: Vec<String>,
+    load_shedder: Option<LoadShedder>,
+    fingerprints: Option<Fingerprints>,
+    strip_store: bool,
+    default_metadata: Vec<(String, String)>,
+    rename_params: Vec<(String, String)>,
+    fallback_models: HashMap<String, String>,
+    prices: HashMap<String, Price>,
+    request_id_source: Option<RequestIdSource>,
+    #[educe(Debug(ignore))]
+    cancels: Option<Cancels>,
+    transform_command: Option<PathBuf>,
+    transform_timeout: Duration,
+    #[educe(Debug(ignore))]
+    response_script: Option<Arc<ResponseScript>>,
+    response_script_stream: bool,
+    sse_initial_comment: bool,
+    forward_upstream_keepalive: bool,
+    reasoning_event_name: Option<String>,
+    strip_response_headers: Vec<HeaderName>,
+    allow_response_headers: Vec<HeaderName>,
+    stream_error_min_text_chunks: Option<usize>,
+    pace_rate: Option<PaceRate>,
+    stream_buffer: Option<usize>,
+    stream_buffer_timeout: Duration,
+    sse_keepalive: Option<Duration>,
+    request_timeout: Option<Duration>,
+    #[educe(Debug(ignore))]
+    admin_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -58,7 +245,11 @@ struct CompletionRequest {
     prompt: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<usize>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        deserialize_with = "number::lenient_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
     temperature: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
@@ -73,139 +264,640 @@ struct ChatCompletionRequest {
     messages: VecDeque<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<usize>,
+    /// newer name of `max_tokens`, merged into `max_tokens` when handling
     #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<usize>,
+    #[serde(
+        default,
+        deserialize_with = "number::lenient_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
     temperature: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
+
+    #[serde(flatten)]
+    other_fields: HashMap<String, Value>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Message {
-    role: String,
-    content: String,
+/// reject too long input before tokenizing, tokenizing a huge input is CPU expensive
+fn check_prompt_chars(
+    max_prompt_chars: Option<usize>,
+    chars: usize,
+) -> Result<(), (StatusCode, String)> {
+    match max_prompt_chars {
+        Some(max_prompt_chars) if chars > max_prompt_chars => Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("input chars {chars} exceeds limit {max_prompt_chars}"),
+        )),
+
+        _ => Ok(()),
+    }
+}
+
+/// the chars of the content wrapped with the template, the content is not copied
+fn wrapped_chars(template: Option<&str>, content: &str) -> usize {
+    let chars = content.chars().count();
+    let Some(template) = template else {
+        return chars;
+    };
+
+    let slots = template.matches(TEMPLATE_PROMPT).count();
+
+    template.chars().count() - slots * TEMPLATE_PROMPT.len() + slots * chars
 }
 
-enum MessageType<'a> {
-    Single(&'a mut String),
-    Multiple(&'a mut VecDeque<Message>),
+/// the template part before the first `{prompt}`, the front truncation must not cut it
+fn template_head(template: Option<&str>) -> Option<String> {
+    template
+        .and_then(|template| template.split(TEMPLATE_PROMPT).next())
+        .filter(|head| !head.is_empty())
+        .map(str::to_string)
 }
 
-fn truncate_messages(bpe: &CoreBPE, messages: MessageType, max_token: usize) {
-    match messages {
-        MessageType::Single(message) => {
-            let tokens = bpe.encode_with_special_tokens(message);
-            if tokens.len() <= max_token {
-                return;
-            }
+/// read the `n` choices count from the flattened request fields
+fn choices_count(other_fields: &HashMap<String, Value>) -> usize {
+    other_fields
+        .get("n")
+        .and_then(Value::as_u64)
+        .map(|n| n.max(1) as usize)
+        .unwrap_or(1)
+}
+
+/// clamp `max_tokens` so `n * max_tokens` doesn't exceed the output token limit
+fn clamp_max_tokens(
+    max_tokens: &mut Option<usize>,
+    n: usize,
+    output_max_token: usize,
+) -> Result<(), (StatusCode, String)> {
+    let per_choice = output_max_token / n;
+    if per_choice == 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("n {n} exceeds output token limit {output_max_token}"),
+        ));
+    }
 
+    match max_tokens {
+        Some(max_tokens) if *max_tokens <= per_choice => {}
+
+        _ => {
             info!(
-                tokens_len = tokens.len(),
-                max_token, "truncating single message"
+                ?max_tokens,
+                n, output_max_token, per_choice, "clamping max_tokens"
             );
 
-            truncate_message(bpe, max_token, message, tokens);
+            *max_tokens = Some(per_choice);
         }
+    }
 
-        MessageType::Multiple(messages) => {
-            let mut token_list = messages
-                .iter()
-                .map(|message| bpe.encode_with_special_tokens(&message.content))
-                .collect::<VecDeque<_>>();
+    Ok(())
+}
 
-            let mut sum = token_list.iter().map(|tokens| tokens.len()).sum::<usize>();
-            if sum <= max_token {
-                return;
-            }
+/// make `prompt_tokens + max_tokens` fit the context window, `max_tokens` is clamped first, when
+/// it can't be lower than `min_output_token`, return the token size which prompt should be
+/// truncated to
+fn fit_context_window(
+    prompt_tokens: usize,
+    max_tokens: &mut Option<usize>,
+    context_window: usize,
+    min_output_token: usize,
+) -> Option<usize> {
+    if prompt_tokens + min_output_token <= context_window {
+        let available = context_window - prompt_tokens;
+        if max_tokens.is_none_or(|max_tokens| max_tokens > available) {
+            info!(
+                target: truncate::LOG_TARGET,
+                ?max_tokens,
+                prompt_tokens,
+                context_window,
+                "clamping max_tokens to fit context window"
+            );
+
+            *max_tokens = Some(available);
+        }
 
-            while sum > max_token {
-                assert!(!token_list.is_empty());
+        return None;
+    }
 
-                let token_len = token_list[0].len();
-                if sum - token_len > max_token {
-                    if token_list.len() > 1 {
-                        sum -= token_len;
-                        messages.pop_front();
-                        token_list.pop_front();
+    let output_token = max_tokens.map_or(min_output_token, |max_tokens| {
+        max_tokens.min(min_output_token)
+    });
+    *max_tokens = Some(output_token);
+    let max_prompt_tokens = context_window.saturating_sub(output_token);
+
+    info!(
+        target: truncate::LOG_TARGET,
+        prompt_tokens,
+        context_window,
+        output_token,
+        max_prompt_tokens,
+        "truncating prompt to fit context window"
+    );
+
+    Some(max_prompt_tokens)
+}
 
-                        info!("drop front message");
+async fn remap_logit_bias(
+    state: &ServerState,
+    model: &str,
+    other_fields: &mut HashMap<String, Value>,
+) -> Result<(), (StatusCode, String)> {
+    let (Some(client_bpe), Some(logit_bias)) = (
+        &state.logit_bias_tokenizer,
+        other_fields.get_mut("logit_bias"),
+    ) else {
+        return Ok(());
+    };
 
-                        continue;
-                    }
+    let encoder = state
+        .encoders
+        .get(model)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let Some(bpe) = encoder.bpe.tiktoken() else {
+        warn!("tokenizer is unavailable, drop logit_bias");
+
+        other_fields.remove("logit_bias");
+
+        return Ok(());
+    };
+    logit_bias::remap(logit_bias, client_bpe, bpe);
+
+    Ok(())
+}
+
+/// set `stream_options.include_usage`, so the backend always sends the usage chunk
+fn inject_stream_usage(other_fields: &mut HashMap<String, Value>) {
+    let stream_options = other_fields
+        .entry("stream_options".to_string())
+        .or_insert_with(|| json!({}));
 
-                    info!(sum, max_token, "truncating multiple message to single");
+    match stream_options.as_object_mut() {
+        Some(stream_options) => {
+            stream_options.insert("include_usage".to_string(), Value::Bool(true));
+        }
+
+        None => *stream_options = json!({"include_usage": true}),
+    }
+}
+
+/// apply `--max-stop-sequences`, truncate the `stop` list or return the error response, a single
+/// string `stop` is one sequence
+fn limit_stop_sequences(
+    state: &ServerState,
+    other_fields: &mut HashMap<String, Value>,
+) -> Option<Response> {
+    let max_stop_sequences = state.max_stop_sequences?;
+    let stop_sequences = match other_fields.get("stop")? {
+        Value::Array(stop) => stop.len(),
+        Value::String(_) => 1,
+        _ => return None,
+    };
+    if stop_sequences <= max_stop_sequences {
+        return None;
+    }
+
+    match state.stop_overflow {
+        StopOverflow::Reject => {
+            warn!(stop_sequences, "too many stop sequences");
 
-                    return truncate_messages(
-                        bpe,
-                        MessageType::Single(&mut messages[0].content),
-                        max_token,
-                    );
+            Some(error::openai_error(
+                StatusCode::BAD_REQUEST,
+                format!("stop sequences {stop_sequences} exceeds limit {max_stop_sequences}"),
+                Some("too_many_stop_sequences"),
+            ))
+        }
+
+        StopOverflow::Truncate => {
+            info!(stop_sequences, "too many stop sequences, truncate them");
+
+            match other_fields.get_mut("stop") {
+                Some(Value::Array(stop)) if max_stop_sequences > 0 => {
+                    stop.truncate(max_stop_sequences)
                 }
 
-                let new_len = sum - max_token;
-                let tokens = token_list.pop_front().unwrap();
+                _ => {
+                    other_fields.remove("stop");
+                }
+            }
 
-                info!(
-                    sum,
-                    max_token,
-                    new_front_len = new_len,
-                    "truncating front multiple message"
-                );
+            None
+        }
+    }
+}
 
-                truncate_message(bpe, new_len, &mut messages[0].content, tokens);
+/// apply `--deny-streaming` and `--deny-non-streaming`, flip the `stream` flag or return the
+/// error response
+fn enforce_stream_mode(
+    state: &ServerState,
+    stream: &mut Option<bool>,
+    other_fields: &mut HashMap<String, Value>,
+) -> Option<Response> {
+    let streaming = stream.unwrap_or_default();
+    let denied = match streaming {
+        true => state.deny_streaming,
+        false => state.deny_non_streaming,
+    };
+    if !denied {
+        return None;
+    }
 
-                return;
+    match state.denied_stream_action {
+        DeniedStreamAction::Reject => {
+            warn!(streaming, "stream mode is denied");
+
+            let message = match streaming {
+                true => "streaming is not allowed",
+                false => "non streaming is not allowed, set `stream: true`",
+            };
+
+            Some(error::openai_error(
+                StatusCode::BAD_REQUEST,
+                message,
+                Some("stream_mode_denied"),
+            ))
+        }
+
+        DeniedStreamAction::Flip => {
+            info!(streaming, "stream mode is denied, flip it");
+
+            *stream = Some(!streaming);
+            // the backend may reject `stream_options` without streaming
+            if streaming {
+                other_fields.remove("stream_options");
             }
+
+            None
+        }
+    }
+}
+
+/// whether the client opts out of the input truncation with `X-No-Truncate: true`, it is only
+/// honored when `--allow-no-truncate` is set
+fn no_truncate(state: &ServerState, headers: &HeaderMap) -> bool {
+    if !header_is_true(headers, NO_TRUNCATE_HEADER) {
+        return false;
+    }
+
+    if !state.allow_no_truncate {
+        debug!("ignore no truncate header, it is not allowed");
+
+        return false;
+    }
+
+    info!("skip input truncation by client request");
+
+    true
+}
+
+/// whether the client asks for the upstream response under `_raw` with `X-Debug-Raw: true`, it is
+/// only honored when `--allow-debug-raw` is set
+fn debug_raw(state: &ServerState, headers: &HeaderMap) -> bool {
+    if !header_is_true(headers, DEBUG_RAW_HEADER) {
+        return false;
+    }
+
+    if !state.allow_debug_raw {
+        debug!("ignore debug raw header, it is not allowed");
+
+        return false;
+    }
+
+    true
+}
+
+fn header_is_true(headers: &HeaderMap, name: &str) -> bool {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// the input truncation and context window options, they are moved to the blocking task
+#[derive(Debug, Clone)]
+struct InputLimits {
+    input_max_token: Option<usize>,
+    context_window: Option<usize>,
+    context_min_output_token: usize,
+    truncate_options: TruncateOptions,
+    /// the head of `--user-message-template`
+    template_head: Option<String>,
+}
+
+impl ServerState {
+    fn input_limits(&self) -> InputLimits {
+        InputLimits {
+            input_max_token: self.input_max_token,
+            context_window: self.context_window,
+            context_min_output_token: self.context_min_output_token,
+            truncate_options: self.truncate_options,
+            template_head: template_head(self.user_message_template.as_deref()),
         }
     }
 }
 
-fn truncate_message(bpe: &CoreBPE, max_token: usize, content: &mut String, tokens: Vec<Rank>) {
-    let mut tokens = VecDeque::from(tokens);
-    tokens.drain(..max_token);
-    content.clear();
+/// truncate the messages to the input token limit, then fit them into the context window, the
+/// messages are encoded once, the truncations share the tokens
+fn limit_chat_input(limits: InputLimits, encoder: &Encoder, payload: &mut ChatCompletionRequest) {
+    let bpe = &encoder.bpe;
+    let mut token_list = encode_messages(bpe, encoder.token_cache.as_ref(), &payload.messages);
+
+    // the front truncated user message gets its template head back
+    let restore_template_head =
+        |messages: &mut VecDeque<Message>, token_list: &mut VecDeque<Vec<Rank>>| {
+            let Some(head) = &limits.template_head else {
+                return;
+            };
+
+            for (message, tokens) in messages.iter_mut().zip(token_list) {
+                if message.role == "user"
+                    && let Some(content) = &mut message.content
+                {
+                    *tokens = restore_head(bpe, head, content, mem::take(tokens));
+                }
+            }
+        };
+
+    if let Some(max_token) = limits.input_max_token {
+        truncate_encoded_messages(
+            bpe,
+            &mut payload.messages,
+            &mut token_list,
+            max_token,
+            limits.truncate_options,
+        );
+        restore_template_head(&mut payload.messages, &mut token_list);
+    }
 
-    for data in bpe._decode_native_and_split(tokens.into()) {
-        content.push_str(&String::from_utf8_lossy(&data));
+    if let Some(context_window) = limits.context_window
+        && let Some(max_token) = fit_context_window(
+            token_list.iter().map(Vec::len).sum(),
+            &mut payload.max_tokens,
+            context_window,
+            limits.context_min_output_token,
+        )
+    {
+        truncate_encoded_messages(
+            bpe,
+            &mut payload.messages,
+            &mut token_list,
+            max_token,
+            limits.truncate_options,
+        );
+        restore_template_head(&mut payload.messages, &mut token_list);
     }
 }
 
 #[instrument(err(Debug))]
 async fn handle_completion(
     state: State<Arc<ServerState>>,
+    Extension(client_ip): Extension<ClientIp>,
     headers: HeaderMap,
-    Json(mut payload): Json<CompletionRequest>,
+    payload: Result<Json<CompletionRequest>, JsonRejection>,
 ) -> Result<Response, (StatusCode, String)> {
-    if let Some(max_token) = state.input_max_token {
-        truncate_messages(
-            &state.bpe,
-            MessageType::Single(&mut payload.prompt),
-            max_token,
-        );
+    let mut payload = match payload {
+        Err(rejection) => return Ok(error::json_rejection(rejection)),
+        Ok(Json(payload)) => payload,
+    };
+
+    if let Some(response) = check_duplicate_auth(&state, &headers) {
+        return Ok(response);
     }
 
-    forward_request(
+    if let Err(err) = number::coerce_sampling_fields(&mut payload.other_fields) {
+        return Ok(error::openai_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            err,
+            None,
+        ));
+    }
+
+    if let Some(response) = limit_stop_sequences(&state, &mut payload.other_fields) {
+        return Ok(response);
+    }
+
+    if let Some(response) =
+        enforce_stream_mode(&state, &mut payload.stream, &mut payload.other_fields)
+    {
+        return Ok(response);
+    }
+
+    check_prompt_chars(
+        state.max_prompt_chars,
+        wrapped_chars(state.prompt_template.as_deref(), &payload.prompt),
+    )?;
+
+    if let Some(response) = moderate(&state, &headers, &[&payload.prompt]).await {
+        return Ok(response);
+    }
+
+    if let Some(template) = &state.prompt_template {
+        payload.prompt = template.replace(TEMPLATE_PROMPT, &payload.prompt);
+    }
+
+    if (state.input_max_token.is_some() || state.context_window.is_some())
+        && !no_truncate(&state, &headers)
+    {
+        let encoder = state
+            .encoders
+            .get(&payload.model)
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        let bpe = &encoder.bpe;
+        let head = template_head(state.prompt_template.as_deref()).unwrap_or_default();
+        let mut tokens = encode(bpe, encoder.token_cache.as_ref(), &payload.prompt);
+        let truncate = !state.encoders.truncation_disabled(&encoder);
+
+        if let Some(max_token) = state.input_max_token.filter(|_| truncate) {
+            tokens = truncate_encoded_message(bpe, &mut payload.prompt, tokens, max_token);
+            tokens = restore_head(bpe, &head, &mut payload.prompt, tokens);
+        }
+
+        if let Some(context_window) = state.context_window.filter(|_| truncate)
+            && let Some(max_token) = fit_context_window(
+                tokens.len(),
+                &mut payload.max_tokens,
+                context_window,
+                state.context_min_output_token,
+            )
+        {
+            let tokens = truncate_encoded_message(bpe, &mut payload.prompt, tokens, max_token);
+            restore_head(bpe, &head, &mut payload.prompt, tokens);
+        }
+    }
+
+    if let Some(output_max_token) = state.output_max_token {
+        let n = choices_count(&payload.other_fields);
+        clamp_max_tokens(&mut payload.max_tokens, n, output_max_token)?;
+    }
+
+    if let Some(seed) = state.default_seed {
+        payload
+            .other_fields
+            .entry("seed".to_string())
+            .or_insert_with(|| seed.into());
+    }
+
+    remap_logit_bias(&state, &payload.model, &mut payload.other_fields).await?;
+
+    let echo_prompt = (state.enforce_echo
+        && payload.other_fields.get("echo") == Some(&Value::Bool(true)))
+    .then(|| payload.prompt.clone());
+    let streaming = payload.stream.unwrap_or_default();
+
+    let response = forward_request(
         state,
         "/v1/completions",
         Method::POST,
         headers,
-        payload.stream.unwrap_or_default(),
+        client_ip,
+        streaming,
         payload,
     )
-    .await
+    .await?;
+
+    match echo_prompt {
+        None => Ok(response),
+        Some(prompt) => echo::enforce(response, prompt, streaming).await,
+    }
 }
 
 #[instrument(err(Debug))]
 async fn handle_chat(
     state: State<Arc<ServerState>>,
+    Extension(client_ip): Extension<ClientIp>,
     headers: HeaderMap,
-    Json(mut payload): Json<ChatCompletionRequest>,
+    payload: Result<Json<ChatCompletionRequest>, JsonRejection>,
 ) -> Result<Response, (StatusCode, String)> {
-    if let Some(max_token) = state.input_max_token {
-        truncate_messages(
-            &state.bpe,
-            MessageType::Multiple(&mut payload.messages),
-            max_token,
-        );
+    let mut payload = match payload {
+        Err(rejection) => return Ok(error::json_rejection(rejection)),
+        Ok(Json(payload)) => payload,
+    };
+
+    if let Some(response) = check_duplicate_auth(&state, &headers) {
+        return Ok(response);
+    }
+
+    if let Err(err) = number::coerce_sampling_fields(&mut payload.other_fields) {
+        return Ok(error::openai_error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            err,
+            None,
+        ));
+    }
+
+    if let Some(response) = limit_stop_sequences(&state, &mut payload.other_fields) {
+        return Ok(response);
+    }
+
+    if let Some(response) =
+        enforce_stream_mode(&state, &mut payload.stream, &mut payload.other_fields)
+    {
+        return Ok(response);
+    }
+
+    for message in &mut payload.messages {
+        if let Some(role) = state.role_map.get(&message.role) {
+            message.role.clone_from(role);
+        }
+    }
+
+    let client_max_tokens_field = match payload.max_completion_tokens.take() {
+        None => MaxTokensField::MaxTokens,
+
+        Some(max_completion_tokens) => {
+            payload.max_tokens = Some(max_completion_tokens);
+
+            MaxTokensField::MaxCompletionTokens
+        }
+    };
+
+    check_prompt_chars(
+        state.max_prompt_chars,
+        payload
+            .messages
+            .iter()
+            .map(|message| match message.role.as_str() {
+                "user" if message.content.is_some() => {
+                    wrapped_chars(state.user_message_template.as_deref(), message.content())
+                }
+                _ => message.content().chars().count(),
+            })
+            .sum(),
+    )?;
+
+    let inputs = payload
+        .messages
+        .iter()
+        .filter(|message| message.role == "user")
+        .map(Message::content)
+        .collect::<Vec<_>>();
+    if let Some(response) = moderate(&state, &headers, &inputs).await {
+        return Ok(response);
+    }
+
+    if let Some(template) = &state.user_message_template {
+        for message in &mut payload.messages {
+            if message.role == "user"
+                && let Some(content) = &mut message.content
+            {
+                *content = template.replace(TEMPLATE_PROMPT, content);
+            }
+        }
+    }
+
+    if (state.input_max_token.is_some() || state.context_window.is_some())
+        && !no_truncate(&state, &headers)
+    {
+        let limits = state.input_limits();
+        // the tokenizer is shared with the blocking task
+        let encoder = state
+            .encoders
+            .get(&payload.model)
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+        if !state.encoders.truncation_disabled(&encoder) {
+            // encoding long messages is CPU heavy, don't block the async task
+            let span = Span::current();
+
+            payload = task::spawn_blocking(move || {
+                let _entered = span.enter();
+
+                limit_chat_input(limits, &encoder, &mut payload);
+
+                payload
+            })
+            .await
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+        }
+    }
+
+    if let Some(output_max_token) = state.output_max_token {
+        let n = choices_count(&payload.other_fields);
+        clamp_max_tokens(&mut payload.max_tokens, n, output_max_token)?;
+    }
+
+    if state.inject_stream_usage && payload.stream.unwrap_or_default() {
+        inject_stream_usage(&mut payload.other_fields);
+    }
+
+    if let Some(seed) = state.default_seed {
+        payload
+            .other_fields
+            .entry("seed".to_string())
+            .or_insert_with(|| seed.into());
+    }
+
+    remap_logit_bias(&state, &payload.model, &mut payload.other_fields).await?;
+
+    if state
+        .chat_max_tokens_field
+        .unwrap_or(client_max_tokens_field)
+        == MaxTokensField::MaxCompletionTokens
+    {
+        payload.max_completion_tokens = payload.max_tokens.take();
     }
 
     forward_request(
@@ -213,118 +905,1145 @@ async fn handle_chat(
         "/v1/chat/completions",
         Method::POST,
         headers,
+        client_ip,
         payload.stream.unwrap_or_default(),
         payload,
     )
     .await
 }
 
-#[instrument(err(Debug), skip(body))]
+/// check the user inputs with the moderation endpoint, return the error response when the
+/// request is rejected
+async fn moderate(state: &ServerState, headers: &HeaderMap, inputs: &[&str]) -> Option<Response> {
+    let moderator = state.moderator.as_ref()?;
+    if inputs.iter().all(|input| input.is_empty()) {
+        return None;
+    }
+
+    match moderator
+        .flagged(&state.client, headers.get(header::AUTHORIZATION), inputs)
+        .await
+    {
+        Ok(false) => None,
+
+        Ok(true) => {
+            warn!("input is flagged by moderation");
+
+            Some(error::openai_error(
+                StatusCode::BAD_REQUEST,
+                "input is flagged by moderation",
+                Some("content_policy_violation"),
+            ))
+        }
+
+        Err(err) if state.moderation_mode == ModerationMode::FailOpen => {
+            warn!(%err, "moderation failed, forward the request");
+
+            None
+        }
+
+        Err(err) => {
+            error!(%err, "moderation failed");
+
+            Some(error::openai_error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "moderation is unavailable",
+                Some("moderation_unavailable"),
+            ))
+        }
+    }
+}
+
 async fn forward_request<T: Serialize + 'static>(
     state: State<Arc<ServerState>>,
     path: &str,
     method: Method,
-    mut headers: HeaderMap,
+    headers: HeaderMap,
+    client_ip: ClientIp,
     streaming: bool,
     body: T,
 ) -> Result<Response, (StatusCode, String)> {
-    headers = retain_headers(headers);
-
-    let url = state
-        .backend
-        .join(path)
-        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
-
-    if streaming {
-        match state.cot_parser {
-            Some(CotParser::Deepseek) => {
-                return match send_stream_request(state.client.clone(), url, body).await {
-                    Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-
-                    Ok(sse_stream_response) => {
-                        let chunks = deepseek::extract_cot(sse_stream_response);
-                        let adapter = StreamAsyncIterAdapter(chunks)
-                            .and_then(async |chunk| Ok(Event::default().json_data(chunk)?))
-                            .inspect_err(|err| {
-                                error!(%err, "sse stream error happened");
-                            });
-
-                        let sse = Sse::new(adapter);
-
-                        Ok(sse.into_response())
-                    }
-                };
-            }
+    if let Some(load_shedder) = &state.load_shedder
+        && load_shedder.should_shed()
+    {
+        warn!("backend latency is too high, shed the request");
 
-            None => {}
-        }
+        return Ok(error::openai_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "backend is overloaded",
+            Some("load_shedding"),
+        ));
     }
 
-    match state
-        .client
-        .request(method, url)
-        .headers(headers)
-        .json(&body)
-        .send()
-        .await
+    if let Some(smoother) = &state.smoother
+        && !smoother.wait(is_priority(&state, &headers)).await
     {
-        Ok(response) => {
-            let status = response.status();
-            let headers = response.headers().clone();
-            let body = response.bytes_stream();
-            let body = Body::from_stream(body);
+        warn!("smooth queue is full");
 
-            let mut builder = Response::builder().status(status);
+        return Ok(error::openai_error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too many queued requests",
+            Some("queue_full"),
+        ));
+    }
 
-            for (k, v) in headers {
-                if let Some(k) = k {
-                    builder = builder.header(k, v);
+    let stream_guard = match &state.stream_limiter {
+        Some(stream_limiter) if streaming => {
+            // the anonymous streams are limited by client ip instead of sharing one key
+            let key = match headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+            {
+                Some(authorization) => authorization.to_string(),
+                None => format!("ip:{}", client_ip.0),
+            };
+
+            match stream_limiter.acquire(&key) {
+                None => {
+                    warn!("too many concurrent streams of the api key");
+
+                    return Ok(error::openai_error(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        "too many concurrent streams of the api key",
+                        Some("too_many_streams"),
+                    ));
                 }
-            }
 
-            builder
-                .body(body)
-                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+                Some(stream_guard) => Some(stream_guard),
+            }
         }
 
-        Err(err) => Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .body(Body::from(err.to_string()))
-            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
-    }
-}
+        _ => None,
+    };
 
-fn retain_headers(headers: HeaderMap) -> HeaderMap {
-    headers
-        .into_iter()
-        .filter_map(|(k, v)| match k {
-            Some(header::AUTHORIZATION) => Some((header::AUTHORIZATION, v)),
-            _ => None,
-        })
-        .collect::<HeaderMap>()
+    let response = send_request(state, path, method, headers, streaming, body).await?;
+
+    Ok(match stream_guard {
+        None => response,
+
+        // release the stream slot when the body ends or the client disconnects
+        Some(stream_guard) => response.map(|body| {
+            Body::from_stream(body.into_data_stream().inspect(move |_| {
+                let _ = &stream_guard;
+            }))
+        }),
+    })
 }
 
 #[instrument(err(Debug), skip(body))]
-async fn proxy_handler(
+async fn send_request<T: Serialize + 'static>(
     state: State<Arc<ServerState>>,
+    path: &str,
     method: Method,
-    req_uri: Uri,
     mut headers: HeaderMap,
-    body: Body,
+    streaming: bool,
+    body: T,
 ) -> Result<Response, (StatusCode, String)> {
+    let derived_user = state
+        .derive_user_from
+        .as_ref()
+        .and_then(|name| derive_user(name, state.derive_user_salt.as_deref(), &headers));
+
+    let request_id = state
+        .request_id_source
+        .as_ref()
+        .map(|source| source.request_id(&headers));
+
+    let debug_raw = debug_raw(&state, &headers);
+
     headers = retain_headers(headers);
 
-    let mut url = state.backend.clone();
-    url.set_path(req_uri.path());
+    if let Some(request_id) = &request_id {
+        info!(request_id, "attach request ID");
 
-    let response = match state
-        .client
-        .request(method, url)
-        .headers(headers)
-        .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+        headers.insert(
+            REQUEST_ID_HEADER,
+            request_id
+                .parse::<HeaderValue>()
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?,
+        );
+    }
+
+    #[cfg(feature = "otel")]
+    otel::inject(&mut headers);
+
+    let url = backend_url(&state.backend, path);
+
+    let mut body = serde_json::to_value(body)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    if let Some(user) = derived_user
+        && let Some(fields) = body.as_object_mut()
+    {
+        fields.entry("user").or_insert(Value::String(user));
+    }
+
+    if let Some(fields) = body.as_object_mut() {
+        if state.strip_store {
+            fields.remove("store");
+            fields.remove("metadata");
+        }
+
+        if !state.default_metadata.is_empty() {
+            let metadata = fields.entry("metadata").or_insert(Value::Null);
+            if metadata.is_null() {
+                *metadata = json!({});
+            }
+            // the client metadata is not replaced silently
+            let metadata = metadata.as_object_mut().ok_or_else(|| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    "metadata must be an object".to_string(),
+                )
+            })?;
+            for (key, value) in &state.default_metadata {
+                metadata
+                    .entry(key)
+                    .or_insert_with(|| Value::String(value.clone()));
+            }
+        }
+
+        for (old, new) in &state.rename_params {
+            if let Some(value) = fields.remove(old) {
+                fields.insert(new.clone(), value);
+            }
+        }
+    }
+
+    if let Some(command) = &state.transform_command {
+        body = transform::transform(command, &body, state.transform_timeout)
+            .await
+            .map_err(|err| {
+                error!(%err, "transform request failed");
+
+                (StatusCode::BAD_GATEWAY, err.to_string())
+            })?;
+    }
+
+    let cot_parser = body
+        .get("model")
+        .and_then(Value::as_str)
+        .and_then(|model| state.model_cot_parsers.get(model))
+        .copied()
+        .unwrap_or(state.cot_parser);
+
+    let price = body
+        .get("model")
+        .and_then(Value::as_str)
+        .and_then(|model| state.prices.get(model))
+        .copied();
+
+    // the redactor only works on the parsed chunks, the stream is never forwarded unredacted
+    if streaming && (cot_parser.is_some() || state.redactor.is_some()) {
+        return send_cot_stream(state, url, headers, body, cot_parser, request_id, price).await;
+    }
+
+    let authorization = headers.get(header::AUTHORIZATION).cloned();
+
+    let mut start = Instant::now();
+    let mut response = state
+        .client
+        .request(method.clone(), url.clone())
+        .headers(headers.clone())
+        .json(&body)
         .send()
-        .await
+        .await;
+
+    if !streaming
+        && let Ok(primary_response) = &response
+        && matches!(
+            primary_response.status(),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        )
+        && let Some(model) = body.get("model").and_then(Value::as_str)
+        && let Some(fallback) = state.fallback_models.get(model)
+    {
+        warn!(
+            model,
+            fallback,
+            status = %primary_response.status(),
+            "model is overloaded, retry with the fallback model"
+        );
+
+        body["model"] = Value::String(fallback.clone());
+        start = Instant::now();
+        response = state
+            .client
+            .request(method, url)
+            .headers(headers)
+            .json(&body)
+            .send()
+            .await;
+    }
+
+    if let Ok(response) = &response
+        && response.status().is_success()
+    {
+        record_latency(&state, start);
+    }
+
+    // the request model, it may be rewritten to the fallback model
+    let price = body
+        .get("model")
+        .and_then(Value::as_str)
+        .and_then(|model| state.prices.get(model))
+        .copied();
+
+    match response {
+        Ok(response) => {
+            let status = response.status();
+            let mut headers = response.headers().clone();
+            let mut estimated_cost = None;
+
+            let body = match &state.response_script {
+                script
+                    if !streaming
+                        && status.is_success()
+                        && (script.is_some()
+                            || state.redactor.is_some()
+                            || state.fingerprints.is_some()
+                            || state.summarizer.is_some()
+                            || state.reasoning_ratio_alert.is_some()
+                            || debug_raw
+                            || price.is_some()) =>
+                {
+                    let data = response
+                        .bytes()
+                        .await
+                        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+                    let mut response = serde_json::from_slice::<Value>(&data)
+                        .map_err(|err| (StatusCode::BAD_GATEWAY, err.to_string()))?;
+                    let mut raw = debug_raw.then(|| response.clone());
+                    // the upstream can't pass its own field off as the raw response
+                    if let Some(response) = response.as_object_mut() {
+                        response.remove(DEBUG_RAW_FIELD);
+                    }
+
+                    if let Some(fingerprints) = &state.fingerprints
+                        && let Some(model) = response.get("model").and_then(Value::as_str)
+                        && let Some(fingerprint) =
+                            response.get("system_fingerprint").and_then(Value::as_str)
+                    {
+                        fingerprints.observe(model, fingerprint);
+                    }
+
+                    if let Some(price) = price
+                        && let Some(cost) =
+                            response.get("usage").and_then(|usage| price.cost(usage))
+                    {
+                        info!(cost, "estimated cost");
+
+                        estimated_cost = Some(price::format_cost(cost));
+                    }
+
+                    // before the summarization, which shortens the reasoning
+                    if let Some(threshold) = state.reasoning_ratio_alert {
+                        let model = body
+                            .get("model")
+                            .and_then(Value::as_str)
+                            .unwrap_or_default();
+                        // the alert is only a log, it doesn't fail the response
+                        match state.encoders.get(model).await {
+                            Err(err) => {
+                                warn!(model, %err, "get encoder failed, skip reasoning ratio check");
+                            }
+
+                            Ok(encoder) => {
+                                reasoning_ratio::check_response(threshold, &encoder, &response);
+                            }
+                        }
+                    }
+
+                    if let Some(summarizer) = &state.summarizer {
+                        summarizer
+                            .summarize_response(
+                                &state.client,
+                                authorization.as_ref(),
+                                &mut response,
+                            )
+                            .await;
+                    }
+
+                    if let Some(redactor) = &state.redactor {
+                        redactor.redact_response(&mut response);
+                        // the raw response must not leak the redacted text
+                        if let Some(raw) = &mut raw {
+                            redactor.redact_response(raw);
+                        }
+                    }
+
+                    if let Some(script) = script {
+                        response = script.process(response).map_err(|err| {
+                            error!(%err, "response script failed");
+
+                            (StatusCode::BAD_GATEWAY, err.to_string())
+                        })?;
+                    }
+
+                    if let Some(raw) = raw
+                        && let Some(response) = response.as_object_mut()
+                    {
+                        response.insert(DEBUG_RAW_FIELD.to_string(), raw);
+                    }
+
+                    // the body size is changed
+                    headers.remove(header::CONTENT_LENGTH);
+
+                    Body::from(
+                        serde_json::to_vec(&response)
+                            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?,
+                    )
+                }
+
+                // no CoT parser, forward the upstream SSE framing untouched
+                _ if streaming && status.is_success() => {
+                    headers.remove(header::CONTENT_LENGTH);
+
+                    let initial_comments = state
+                        .sse_initial_comment
+                        .then(|| SSE_INITIAL_COMMENT.to_string())
+                        .into_iter()
+                        .chain(request_id.as_ref().map(|id| format!("request-id: {id}")))
+                        .collect();
+
+                    Body::from_stream(StreamAsyncIterAdapter(sse::passthrough(
+                        response.bytes_stream(),
+                        initial_comments,
+                        state.sse_keepalive,
+                    )))
+                }
+
+                _ if !status.is_success() => upstream_error_body(response).await,
+
+                _ => Body::from_stream(response.bytes_stream()),
+            };
+
+            let mut builder = Response::builder().status(status);
+
+            for (k, v) in &response_headers(&state, &headers, &request_id) {
+                builder = builder.header(k, v);
+            }
+            if let Some(cost) = estimated_cost {
+                builder = builder.header(ESTIMATED_COST_HEADER, cost);
+            }
+
+            builder
+                .body(body)
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+        }
+
+        Err(err) => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(err.to_string()))
+            .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// send the streaming request and parse the CoT of the stream when the parser is set, then run
+/// the chunks through the enabled stream stages
+async fn send_cot_stream(
+    state: State<Arc<ServerState>>,
+    url: Url,
+    headers: HeaderMap,
+    body: Value,
+    cot_parser: Option<CotParser>,
+    request_id: Option<String>,
+    price: Option<Price>,
+) -> Result<Response, (StatusCode, String)> {
+    // tracked before the request, so a duplicate request ID is rejected without calling the
+    // backend, the stream can only be cancelled by the same api key
+    let tracked = match (&state.cancels, &request_id) {
+        (Some(cancels), Some(request_id)) => {
+            let authorization = headers.get(header::AUTHORIZATION).cloned();
+            let tracked = cancels
+                .track(request_id.clone(), authorization)
+                .ok_or_else(|| {
+                    (
+                        StatusCode::CONFLICT,
+                        format!("request {request_id} is already streaming"),
+                    )
+                })?;
+
+            Some(tracked)
+        }
+
+        _ => None,
+    };
+    // the requested `n`, the stream is ended early once all choices are cut off
+    let choices = body
+        .get("n")
+        .and_then(Value::as_u64)
+        .map(|n| n.max(1) as usize)
+        .unwrap_or(1);
+
+    let model = body
+        .get("model")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+
+    let pace = match state.pace_rate {
+        None => None,
+
+        Some(rate) => {
+            let encoder = state
+                .encoders
+                .get(model)
+                .await
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+            Some((rate, encoder))
+        }
+    };
+
+    // the alert is only a log, it doesn't fail the stream
+    let reasoning_ratio_alert = match state.reasoning_ratio_alert {
+        None => None,
+
+        Some(threshold) => match state.encoders.get(model).await {
+            Err(err) => {
+                warn!(model, %err, "get encoder failed, skip reasoning ratio check");
+
+                None
+            }
+
+            Ok(encoder) => Some((threshold, encoder)),
+        },
+    };
+
+    let upstream_keepalive = state
+        .forward_upstream_keepalive
+        .then(|| Arc::new(Notify::new()));
+
+    let start = Instant::now();
+
+    match send_stream_request(
+        state.client.clone(),
+        url,
+        headers,
+        body,
+        state.reasoning_field.clone(),
+        state.lenient_sse,
+        upstream_keepalive.clone(),
+    )
+    .await
     {
+        Err(err) => match err.downcast::<UpstreamRejected>() {
+            Ok(UpstreamRejected(response)) => {
+                let mut builder = Response::builder().status(response.status());
+                for (k, v) in &response_headers(&state, response.headers(), &request_id) {
+                    builder = builder.header(k, v);
+                }
+
+                builder
+                    .body(upstream_error_body(response).await)
+                    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+            }
+
+            Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+        },
+
+        Ok(sse_stream_response) => {
+            record_latency(&state, start);
+
+            let mut sse_stream_response = sse_stream_response.boxed();
+            if state.drop_after_finish {
+                sse_stream_response =
+                    StreamAsyncIterAdapter(finish::drop_after_finish(sse_stream_response)).boxed();
+            }
+
+            let mut chunks = match cot_parser {
+                None => sse_stream_response,
+
+                Some(CotParser::Deepseek) => StreamAsyncIterAdapter(deepseek::extract_cot(
+                    sse_stream_response,
+                    state.strict_chunks,
+                ))
+                .boxed(),
+
+                Some(CotParser::MarkdownFence) => StreamAsyncIterAdapter(fence::extract_cot(
+                    sse_stream_response,
+                    state.strict_chunks,
+                    state.cot_fence_label.clone(),
+                ))
+                .boxed(),
+            };
+            if let Some(reasoning_loop) = state.reasoning_loop {
+                chunks = StreamAsyncIterAdapter(reasoning_loop::cut_reasoning_loop(
+                    chunks,
+                    reasoning_loop,
+                    choices,
+                ))
+                .boxed();
+            }
+            if state.fingerprints.is_some() {
+                let state = state.0.clone();
+                chunks = chunks
+                    .inspect_ok(move |chunk| {
+                        if let Some(fingerprints) = &state.fingerprints
+                            && let Some(fingerprint) = &chunk.system_fingerprint
+                        {
+                            fingerprints.observe(&chunk.model, fingerprint);
+                        }
+                    })
+                    .boxed();
+            }
+            if let Some((threshold, encoder)) = reasoning_ratio_alert {
+                chunks = StreamAsyncIterAdapter(reasoning_ratio::check_stream(
+                    chunks, threshold, encoder,
+                ))
+                .boxed();
+            }
+            if let Some(reasoning_marker) = &state.reasoning_marker {
+                chunks = StreamAsyncIterAdapter(marker::mark_reasoning(
+                    chunks,
+                    reasoning_marker.clone(),
+                ))
+                .boxed();
+            }
+            let usage = Arc::new(Mutex::new(None));
+            if price.is_some() {
+                let usage = usage.clone();
+                chunks = chunks
+                    .inspect_ok(move |chunk| {
+                        if let Some(chunk_usage) = &chunk.usage {
+                            *usage.lock().unwrap() = Some(chunk_usage.clone());
+                        }
+                    })
+                    .boxed();
+            }
+            if state.normalize_newlines {
+                chunks = StreamAsyncIterAdapter(newline::normalize_newlines(chunks)).boxed();
+            }
+            if let Some(redactor) = &state.redactor {
+                chunks =
+                    StreamAsyncIterAdapter(redact::redact_stream(chunks, redactor.clone())).boxed();
+            }
+            // after the redactor, so the sink never gets the secrets hidden from the client
+            if let Some(reasoning_sink) = &state.reasoning_sink {
+                chunks = StreamAsyncIterAdapter(reasoning_sink::tee(
+                    chunks,
+                    reasoning_sink.clone(),
+                    request_id.clone(),
+                ))
+                .boxed();
+            }
+            if let Some(min_text_chunks) = state.stream_error_min_text_chunks {
+                chunks =
+                    StreamAsyncIterAdapter(sse::end_on_late_error(chunks, min_text_chunks)).boxed();
+            }
+            if let Some(tracked) = tracked {
+                chunks = StreamAsyncIterAdapter(cancel::accumulate(chunks, tracked)).boxed();
+            }
+            // before the buffer, so the pacing delay is not taken as a slow client
+            if let Some((rate, encoder)) = pace {
+                chunks = StreamAsyncIterAdapter(pace::pace(chunks, encoder, rate)).boxed();
+            }
+            if let Some(size) = state.stream_buffer {
+                chunks = buffer::bounded(chunks, size, state.stream_buffer_timeout).boxed();
+            }
+
+            let stream_script = state
+                .response_script
+                .clone()
+                .filter(|_| state.response_script_stream);
+
+            let reasoning_event_name = state.reasoning_event_name.clone();
+
+            let mut adapter = chunks
+                .and_then(move |chunk| {
+                    ready(chunk_event(
+                        stream_script.as_deref(),
+                        reasoning_event_name.as_deref(),
+                        chunk,
+                    ))
+                })
+                .map_ok(|events| stream::iter(events.into_iter().map(Ok)))
+                .try_flatten()
+                .inspect_err(|err| {
+                    error!(%err, "sse stream error happened");
+                })
+                .boxed();
+            if let Some(upstream_keepalive) = upstream_keepalive {
+                adapter =
+                    StreamAsyncIterAdapter(sse::forward_keepalive(adapter, upstream_keepalive))
+                        .boxed();
+            }
+
+            let initial_comments = state
+                .sse_initial_comment
+                .then(|| SSE_INITIAL_COMMENT.to_string())
+                .into_iter()
+                .chain(request_id.as_ref().map(|id| format!("request-id: {id}")))
+                .map(|comment| Ok(Event::default().comment(comment)))
+                .collect::<Vec<_>>();
+            // the usage chunk is the last one, the cost is known after the chunks
+            let cost = stream::once(async move {
+                let cost = price?.cost(usage.lock().unwrap().as_ref()?)?;
+
+                info!(cost, "estimated cost");
+
+                Some(Ok(Event::default().comment(format!(
+                    "estimated-cost: {}",
+                    price::format_cost(cost)
+                ))))
+            })
+            .filter_map(ready);
+            let done = stream::once(ready(Ok(Event::default().data(END_SSE_DATA))));
+            let sse = Sse::new(
+                stream::iter(initial_comments)
+                    .chain(adapter)
+                    .chain(cost)
+                    .chain(done),
+            );
+
+            let mut response = match state.sse_keepalive {
+                None => sse.into_response(),
+                Some(interval) => sse
+                    .keep_alive(KeepAlive::new().interval(interval))
+                    .into_response(),
+            };
+            response
+                .headers_mut()
+                .extend(response_headers(&state, &HeaderMap::new(), &request_id));
+
+            Ok(response)
+        }
+    }
+}
+
+/// record the backend latency until the response head of the successful request, the failed
+/// requests may fail fast and hide the slow backend, the stream body is not counted
+fn record_latency(state: &ServerState, start: Instant) {
+    if let Some(load_shedder) = &state.load_shedder {
+        load_shedder.record(start.elapsed());
+    }
+}
+
+/// log the head of the upstream error body and forward the body untouched, the body may be binary
+/// or not valid UTF-8, so it is only decoded lossily for the log when the content type is text
+async fn upstream_error_body(response: reqwest::Response) -> Body {
+    let status = response.status();
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut st = response.bytes_stream();
+    let mut head = Vec::new();
+    let mut read_err = None;
+    while head.len() < ERROR_BODY_PREVIEW_LEN {
+        match st.next().await {
+            None => break,
+            Some(Ok(data)) => head.extend_from_slice(&data),
+            Some(Err(err)) => {
+                read_err = Some(err);
+                break;
+            }
+        }
+    }
+
+    if content_type.starts_with("text/") || content_type.contains("json") {
+        let preview = String::from_utf8_lossy(&head[..head.len().min(ERROR_BODY_PREVIEW_LEN)]);
+
+        warn!(%status, body = %preview, "upstream error");
+    } else {
+        warn!(%status, content_type, "upstream error with non text body");
+    }
+
+    let head = stream::once(ready(Ok(Bytes::from(head))));
+
+    Body::from_stream(head.chain(stream::iter(read_err.map(Err))).chain(st))
+}
+
+/// the chunk carrying `reasoning_content` is named `reasoning_event_name` when it is set
+fn chunk_event(
+    script: Option<&ResponseScript>,
+    reasoning_event_name: Option<&str>,
+    chunk: Chunk,
+) -> anyhow::Result<Vec<Event>> {
+    let chunk: Chunk = match script {
+        None => chunk,
+        Some(script) => script.process(chunk)?,
+    };
+
+    let Some(name) = reasoning_event_name else {
+        return Ok(vec![Event::default().json_data(chunk)?]);
+    };
+
+    let (reasoning, content) = split_reasoning(chunk);
+    let mut events = vec![];
+    if let Some(reasoning) = reasoning {
+        events.push(Event::default().event(name).json_data(reasoning)?);
+    }
+    if let Some(content) = content {
+        events.push(Event::default().json_data(content)?);
+    }
+
+    Ok(events)
+}
+
+/// split the `reasoning_content` of the chunk into its own chunk, so a chunk with both reasoning
+/// and content is not named as reasoning entirely
+///
+/// the rest chunk keeps the content, the finish reason and the usage, it is [`None`] when nothing
+/// is left
+fn split_reasoning(mut chunk: Chunk) -> (Option<Chunk>, Option<Chunk>) {
+    let choices = chunk
+        .choices
+        .iter_mut()
+        .filter_map(|choice| {
+            let reasoning_content = choice.delta.reasoning_content.take()?;
+
+            Some(Choice {
+                index: choice.index,
+                delta: Delta {
+                    role: choice.delta.role.take(),
+                    reasoning_content: Some(reasoning_content),
+                    content: None,
+                    annotations: None,
+                },
+                logprobs: None,
+                finish_reason: None,
+                stop_reason: None,
+                prompt_logprobs: None,
+            })
+        })
+        .collect::<Vec<_>>();
+    if choices.is_empty() {
+        return (None, Some(chunk));
+    }
+
+    let reasoning = Chunk {
+        id: chunk.id.clone(),
+        object: chunk.object.clone(),
+        created: chunk.created,
+        model: chunk.model.clone(),
+        choices,
+        usage: None,
+        system_fingerprint: chunk.system_fingerprint.clone(),
+        prompt_logprobs: None,
+        other_fields: Default::default(),
+    };
+
+    chunk.choices.retain(|choice| {
+        choice.delta.role.is_some()
+            || choice.delta.content.is_some()
+            || choice.delta.annotations.is_some()
+            || choice.logprobs.is_some()
+            || choice.finish_reason.is_some()
+            || choice.stop_reason.is_some()
+            || choice.prompt_logprobs.is_some()
+    });
+    let rest = (!chunk.choices.is_empty()
+        || chunk.usage.is_some()
+        || chunk.prompt_logprobs.is_some()
+        || !chunk.other_fields.is_empty())
+    .then_some(chunk);
+
+    (Some(reasoning), rest)
+}
+
+/// read the `user` from the client header, the api key is hashed with the salt, don't leak it to
+/// the body
+fn derive_user(name: &HeaderName, salt: Option<&str>, headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?;
+
+    if *name == header::AUTHORIZATION {
+        let mut hasher = Sha256::new();
+        hasher.update(salt.unwrap_or_default());
+        hasher.update(value);
+
+        return Some(
+            hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        );
+    }
+
+    Some(value.to_string())
+}
+
+/// append the API path to the backend, the path prefix of the backend is kept, unlike
+/// [`Url::join`] with an absolute path
+fn backend_url(backend: &Url, path: &str) -> Url {
+    let mut url = backend.clone();
+    url.set_path(&format!(
+        "{}/{}",
+        backend.path().trim_end_matches('/'),
+        path.trim_start_matches('/')
+    ));
+
+    url
+}
+
+/// only the first `Authorization` is kept, see [`check_duplicate_auth`]
+fn retain_headers(headers: HeaderMap) -> HeaderMap {
+    headers
+        .get(header::AUTHORIZATION)
+        .map(|value| (header::AUTHORIZATION, value.clone()))
+        .into_iter()
+        .collect::<HeaderMap>()
+}
+
+/// whether the request bearer token is one of the `--priority-key`
+fn is_priority(state: &ServerState, headers: &HeaderMap) -> bool {
+    let Some(token) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    state
+        .priority_keys
+        .iter()
+        .any(|key| admin::constant_time_eq(key.as_bytes(), token.as_bytes()))
+}
+
+/// return the error response when the client sends several `Authorization` headers and
+/// `--duplicate-auth reject` is set
+fn check_duplicate_auth(state: &ServerState, headers: &HeaderMap) -> Option<Response> {
+    if state.duplicate_auth != DuplicateAuth::Reject
+        || headers
+            .get_all(header::AUTHORIZATION)
+            .iter()
+            .nth(1)
+            .is_none()
+    {
+        return None;
+    }
+
+    warn!("duplicate authorization headers");
+
+    Some(error::openai_error(
+        StatusCode::BAD_REQUEST,
+        "duplicate authorization headers",
+        Some("duplicate_authorization"),
+    ))
+}
+
+/// drop the hop-by-hop headers and the configured headers of the upstream response, the upstream
+/// `X-Request-Id` is replaced by the proxy request ID when it is set
+fn response_headers(
+    state: &ServerState,
+    headers: &HeaderMap,
+    request_id: &Option<String>,
+) -> HeaderMap {
+    // the headers listed in `Connection` are hop-by-hop too
+    let connection_headers = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+
+    let mut response_headers = headers
+        .iter()
+        .filter(|(name, _)| {
+            !HOP_BY_HOP_HEADERS.contains(&name.as_str())
+                && !connection_headers
+                    .iter()
+                    .any(|header| header == name.as_str())
+                && !state.strip_response_headers.contains(name)
+                && (state.allow_response_headers.is_empty()
+                    || *name == header::CONTENT_TYPE
+                    || *name == header::CONTENT_LENGTH
+                    || state.allow_response_headers.contains(name))
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<HeaderMap>();
+
+    // it was checked when forwarding to the backend
+    if let Some(request_id) = request_id
+        && let Ok(value) = request_id.parse()
+    {
+        response_headers.insert(REQUEST_ID_HEADER, value);
+    }
+
+    response_headers
+}
+
+async fn fingerprints_handler(state: State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    if let Some(response) = admin::authorize(&state, &headers) {
+        return response;
+    }
+
+    Json(
+        state
+            .fingerprints
+            .as_ref()
+            .map(Fingerprints::snapshot)
+            .unwrap_or_default(),
+    )
+    .into_response()
+}
+
+/// `POST /v1/cancel/{request_id}`, stop the CoT parsed stream and return its partial result
+async fn cancel_handler(
+    state: State<Arc<ServerState>>,
+    Path(request_id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    let completion = state.cancels.as_ref().and_then(|cancels| {
+        cancels.cancel(&request_id, headers.get(header::AUTHORIZATION), || {
+            admin::authorize(&state, &headers).is_none()
+        })
+    });
+
+    match completion {
+        None => error::openai_error(
+            StatusCode::NOT_FOUND,
+            format!("no stream of request {request_id}"),
+            None,
+        ),
+
+        Some(completion) => Json(completion).into_response(),
+    }
+}
+
+async fn shed_handler(state: State<Arc<ServerState>>, headers: HeaderMap) -> Response {
+    if let Some(response) = admin::authorize(&state, &headers) {
+        return response;
+    }
+
+    // the route is only registered with the load shedder
+    let load_shedder = state
+        .load_shedder
+        .as_ref()
+        .expect("load shedder is enabled");
+
+    Json(load_shedder.status()).into_response()
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type
+                .as_bytes()
+                .get(.."application/json".len())
+                .is_some_and(|mime| mime.eq_ignore_ascii_case(b"application/json"))
+        })
+}
+
+/// set `stream: false` of the JSON object body, other bodies are returned unchanged
+fn downgrade_stream(data: Bytes) -> Bytes {
+    let Ok(Value::Object(mut body)) = serde_json::from_slice::<Value>(&data) else {
+        return data;
+    };
+    if body.get("stream") != Some(&Value::Bool(true)) {
+        return data;
+    }
+
+    warn!("downgrade the proxy request to non streaming");
+
+    body.insert("stream".to_string(), Value::Bool(false));
+    // it is rejected without `stream: true`
+    body.remove("stream_options");
+
+    serde_json::to_vec(&body).map(Bytes::from).unwrap_or(data)
+}
+
+/// whether the fallback proxy forwards the request, an empty allow list allows all
+fn proxy_allowed(state: &ServerState, method: &Method, path: &str) -> bool {
+    (state.allowed_proxy_methods.is_empty() || state.allowed_proxy_methods.contains(method))
+        && (state.allowed_proxy_paths.is_empty()
+            || (!escapes_prefix(path)
+                && state
+                    .allowed_proxy_paths
+                    .iter()
+                    .any(|prefix| has_path_prefix(path, prefix))))
+}
+
+/// whether the path has a dot segment or an encoded separator, the backend may resolve it out of
+/// the allowed prefix, e.g. `/v1/%2e%2e/internal`
+fn escapes_prefix(path: &str) -> bool {
+    path.split('/').any(|segment| {
+        let lower = segment.to_ascii_lowercase();
+        let decoded = lower.replace("%2e", ".");
+
+        decoded == "." || decoded == ".." || lower.contains("%2f") || lower.contains("%5c")
+    })
+}
+
+/// match the prefix by whole segments, `/v1/models` doesn't match `/v1/modelsX`
+fn has_path_prefix(path: &str, prefix: &str) -> bool {
+    let Some(rest) = path.strip_prefix(prefix) else {
+        return false;
+    };
+
+    prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/')
+}
+
+#[instrument(err(Debug), skip(incoming))]
+async fn proxy_handler(
+    state: State<Arc<ServerState>>,
+    method: Method,
+    req_uri: Uri,
+    mut headers: HeaderMap,
+    incoming: Request,
+) -> Result<Response, (StatusCode, String)> {
+    if let Some(response) = check_duplicate_auth(&state, &headers) {
+        return Ok(response);
+    }
+
+    if !proxy_allowed(&state, &method, req_uri.path()) {
+        warn!(%method, path = req_uri.path(), "proxy request is not allowed");
+
+        return Ok(error::openai_error(
+            StatusCode::FORBIDDEN,
+            format!("{method} {} is not allowed", req_uri.path()),
+            Some("proxy_not_allowed"),
+        ));
+    }
+
+    // an empty chunked body of GET or DELETE is rejected by some backends
+    let bodyless = BODYLESS_METHODS.contains(&method)
+        && !headers.contains_key(header::TRANSFER_ENCODING)
+        && headers
+            .get(header::CONTENT_LENGTH)
+            .is_none_or(|len| len.as_bytes() == b"0");
+    let downgrade = state.downgrade_proxy_stream && is_json(&headers);
+
+    headers = retain_headers(headers);
+
+    #[cfg(feature = "otel")]
+    otel::inject(&mut headers);
+
+    let url = backend_url(&state.backend, req_uri.path());
+
+    let mut request = state.client.request(method, url).headers(headers);
+    if !bodyless {
+        let body = if downgrade {
+            // the body limit of the extractor applies to the buffered body
+            let data = match Bytes::from_request(incoming, &()).await {
+                Err(rejection) => {
+                    return Ok(error::openai_error(
+                        rejection.status(),
+                        rejection.body_text(),
+                        None,
+                    ));
+                }
+
+                Ok(data) => data,
+            };
+
+            reqwest::Body::from(downgrade_stream(data))
+        } else {
+            reqwest::Body::wrap_stream(incoming.into_body().into_data_stream())
+        };
+
+        request = request.body(body);
+    }
+
+    let response = match request.send().await {
         Err(err) => {
             return Response::builder()
                 .status(StatusCode::BAD_REQUEST)
@@ -337,13 +2056,20 @@ async fn proxy_handler(
 
     let status = response.status();
     let headers = response.headers().clone();
-    let body = Body::from_stream(response.bytes_stream());
+    // the SSE of other endpoints is kept alive inside the client idle timeout too
+    let body = if status.is_success() && sse::is_event_stream(&headers) {
+        Body::from_stream(StreamAsyncIterAdapter(sse::passthrough(
+            response.bytes_stream(),
+            vec![],
+            state.sse_keepalive,
+        )))
+    } else {
+        Body::from_stream(response.bytes_stream())
+    };
     let mut builder = Response::builder().status(status);
 
-    for (k, v) in headers {
-        if let Some(k) = k {
-            builder = builder.header(k, v);
-        }
+    for (k, v) in &response_headers(&state, &headers, &None) {
+        builder = builder.header(k, v);
     }
 
     builder
@@ -352,13 +2078,158 @@ async fn proxy_handler(
 }
 
 pub async fn run() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let mut cli = Cli::parse();
+
+    init_log(&cli)?;
+
+    let client = build_client(&cli)?;
+    let command = cli.command.take();
+    let backend = cli
+        .backend
+        .as_deref()
+        .map(|backend| {
+            backend
+                .parse::<Url>()
+                .with_context(|| format!("invalid backend {backend}"))
+        })
+        .transpose()?;
+
+    let backend = match &command {
+        Some(Command::Check { api_key }) => {
+            let backend = backend.context("backend is required by check")?;
 
-    init_log(cli.debug);
+            return check::check(&backend, &client, api_key.as_deref()).await;
+        }
+
+        Some(Command::Selftest) => {
+            let mut parsers = cli
+                .model_cot_parser
+                .iter()
+                .filter_map(|(_, parser)| *parser)
+                .chain(cli.cot_parser)
+                .collect::<Vec<_>>();
+            parsers.sort();
+            parsers.dedup();
+
+            if parsers.is_empty() {
+                anyhow::bail!("no CoT parser configured");
+            }
+
+            for parser in parsers {
+                println!("cot parser {parser:?}");
+
+                selftest::selftest(parser, cli.strict_chunks).await?;
+            }
+
+            return Ok(());
+        }
+
+        Some(Command::Bench(args)) if args.echo => bench::spawn_echo_backend().await?,
+
+        // clap requires it without subcommand
+        _ => backend.context("backend is required")?,
+    };
+
+    if cli.require_backend_at_startup {
+        check::probe(&backend, &client).await?;
+    }
 
     info!("starting openai limiter");
 
-    let bpe = o200k_base()?;
+    let listens = mem::take(&mut cli.listen);
+    let dual_stack = cli.dual_stack;
+    let client_keepalive = cli.client_keepalive.map(Duration::from_secs);
+    let client_idle_timeout = cli.client_idle_timeout.map(Duration::from_secs);
+
+    let app = build_app(cli, backend, client)?;
+
+    if let Some(Command::Bench(args)) = &command {
+        return bench::bench(app, args).await;
+    }
+
+    let mut listeners = Vec::with_capacity(listens.len());
+    for listen in &listens {
+        listeners.push(ClientListener::new(
+            listener::bind(listen, dual_stack).await?,
+            client_keepalive,
+            client_idle_timeout,
+        ));
+    }
+
+    select! {
+        res = serve(app, listeners).fuse() => {
+            res?;
+        }
+        _ = signal_stop().fuse() => {}
+    }
+
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+
+    Ok(())
+}
+
+/// serve the app on all listeners, the first failed listener stops the others
+async fn serve(app: Router, listeners: Vec<ClientListener>) -> anyhow::Result<()> {
+    let app = app.into_make_service_with_connect_info::<PeerAddr>();
+    let servers = listeners
+        .into_iter()
+        .map(|listener| axum::serve(listener, app.clone()).into_future());
+
+    future::try_join_all(servers).await?;
+
+    Ok(())
+}
+
+/// build the server state and the router from the command line
+fn build_app(cli: Cli, backend: Url, client: Client) -> anyhow::Result<Router> {
+    let encoders = Encoders::new(
+        cli.auto_tokenizer,
+        cli.token_cache_size
+            .map(|size| (size, cli.token_cache_min_len)),
+        &cli.preload_tokenizer,
+        cli.tokenizer_fallback,
+    )?;
+    let truncation_disabled =
+        encoders.estimating() && cli.tokenizer_fallback == TokenizerFallback::Disable;
+    if truncation_disabled {
+        error!("tokenizer is unavailable, the input truncation and context window are disabled");
+    }
+
+    if cli.derive_user_from == Some(header::AUTHORIZATION) && cli.derive_user_salt.is_none() {
+        anyhow::bail!("--derive-user-salt is required to derive the user from Authorization");
+    }
+
+    let response_script = cli
+        .response_script
+        .as_deref()
+        .map(|path| ResponseScript::load(path, cli.response_script_max_operations))
+        .transpose()?
+        .map(Arc::new);
+
+    let summarizer = cli
+        .summarize_reasoning
+        .map(|model| {
+            let endpoint = match cli.summarize_reasoning_endpoint {
+                None => backend_url(&backend, "/v1/chat/completions"),
+                Some(endpoint) => endpoint,
+            };
+
+            Summarizer::new(
+                endpoint,
+                &backend,
+                cli.summarize_reasoning_api_key.as_deref(),
+                model,
+                cli.summarize_reasoning_min_chars,
+            )
+        })
+        .transpose()?;
+
+    let reasoning_sink = cli
+        .reasoning_sink
+        .map(|target| ReasoningSink::new(target, client.clone()))
+        .transpose()?
+        .map(Arc::new);
 
     let cors = CorsLayer::new()
         // allow `GET` and `POST` when accessing the resource
@@ -368,7 +2239,127 @@ pub async fn run() -> anyhow::Result<()> {
         // allow requests from any origin
         .allow_origin(Any);
 
-    let app = Router::new()
+    let state = Arc::new(ServerState {
+        backend,
+        client,
+        trust_proxy: cli.trust_proxy,
+        input_max_token: cli.input_max_token.filter(|_| !truncation_disabled),
+        truncate_options: TruncateOptions {
+            protect_last_user: cli.protect_last_user,
+            min_message_tokens: cli.min_message_tokens.unwrap_or_default(),
+        },
+        allow_no_truncate: cli.allow_no_truncate,
+        allow_debug_raw: cli.allow_debug_raw,
+        max_prompt_chars: cli.max_prompt_chars,
+        max_json_depth: cli.max_json_depth,
+        max_json_elements: cli.max_json_elements,
+        moderator: cli.moderation_endpoint.clone().map(Moderator::new),
+        moderation_mode: cli.moderation_mode,
+        prompt_template: cli.prompt_template,
+        role_map: cli.role_map.into_iter().collect(),
+        user_message_template: cli.user_message_template,
+        output_max_token: cli.output_max_token,
+        context_window: cli.context_window.filter(|_| !truncation_disabled),
+        context_min_output_token: cli.context_min_output_token,
+        chat_max_tokens_field: cli.chat_max_tokens_field,
+        encoders,
+        logit_bias_tokenizer: cli
+            .remap_logit_bias
+            .map(|name| get_bpe_from_tokenizer(name.into()))
+            .transpose()?,
+        cot_parser: cli.cot_parser,
+        model_cot_parsers: cli.model_cot_parser.into_iter().collect(),
+        cot_fence_label: cli.cot_fence_label,
+        summarizer,
+        reasoning_ratio_alert: cli.reasoning_ratio_alert,
+        reasoning_sink,
+        reasoning_loop: cli.detect_reasoning_loop.then_some(ReasoningLoop {
+            repeats: cli.reasoning_loop_repeats.into(),
+            action: cli.reasoning_loop_action,
+        }),
+        lenient_sse: cli.lenient_sse,
+        strict_chunks: cli.strict_chunks,
+        drop_after_finish: cli.drop_after_finish,
+        reasoning_field: cli.reasoning_field,
+        normalize_newlines: cli.normalize_newlines,
+        reasoning_marker: (cli.reasoning_prefix.is_some() || cli.reasoning_suffix.is_some()).then(
+            || ReasoningMarker {
+                prefix: cli.reasoning_prefix.unwrap_or_default(),
+                suffix: cli.reasoning_suffix.unwrap_or_default(),
+            },
+        ),
+        redactor: (!cli.output_redact.is_empty())
+            .then(|| {
+                Redactor::new(
+                    &cli.output_redact,
+                    cli.output_redact_reasoning,
+                    cli.output_redact_window,
+                )
+            })
+            .transpose()?
+            .map(Arc::new),
+        derive_user_from: cli.derive_user_from,
+        derive_user_salt: cli.derive_user_salt,
+        duplicate_auth: cli.duplicate_auth,
+        allowed_proxy_paths: cli.allowed_proxy_path,
+        allowed_proxy_methods: cli.allowed_proxy_method,
+        downgrade_proxy_stream: cli.downgrade_proxy_stream,
+        default_seed: cli.default_seed,
+        inject_stream_usage: cli.inject_stream_usage,
+        max_stop_sequences: cli.max_stop_sequences,
+        stop_overflow: cli.stop_overflow,
+        deny_streaming: cli.deny_streaming,
+        deny_non_streaming: cli.deny_non_streaming,
+        denied_stream_action: cli.denied_stream_action,
+        enforce_echo: cli.enforce_echo,
+        stream_limiter: cli
+            .max_streams_per_key
+            .map(|max_streams| ConcurrencyLimiter::new(max_streams.get())),
+        connection_limiter: cli
+            .max_connections_per_ip
+            .map(|max_connections| ConcurrencyLimiter::new(max_connections.get())),
+        max_decompress_ratio: cli.max_decompress_ratio,
+        max_decompressed_size: cli.max_decompressed_size,
+        smoother: cli
+            .smooth_rate
+            .map(|rate| Smoother::new(rate, cli.smooth_queue_depth)),
+        priority_keys: cli.priority_key,
+        load_shedder: cli
+            .latency_shed_threshold
+            .map(|threshold| LoadShedder::new(Duration::from_millis(threshold), cli.shed_fraction)),
+        fingerprints: cli.track_fingerprint.then(Fingerprints::default),
+        strip_store: cli.strip_store,
+        default_metadata: cli.default_metadata,
+        rename_params: cli.rename_param,
+        fallback_models: cli.fallback_model.into_iter().collect(),
+        prices: cli.price.into_iter().collect(),
+        request_id_source: cli.request_id_source,
+        cancels: cli.enable_cancel.then(Cancels::default),
+        transform_command: cli.transform_command,
+        transform_timeout: Duration::from_secs(cli.transform_timeout),
+        response_script,
+        response_script_stream: cli.response_script_stream,
+        sse_initial_comment: cli.sse_initial_comment,
+        forward_upstream_keepalive: cli.forward_upstream_keepalive,
+        reasoning_event_name: cli.reasoning_event_name,
+        strip_response_headers: cli.strip_response_header,
+        allow_response_headers: cli.allow_response_header,
+        stream_error_min_text_chunks: cli.stream_error_min_text_chunks,
+        pace_rate: cli.output_token_rate.map(|rate| PaceRate {
+            content: rate,
+            reasoning: cli.reasoning_token_rate.unwrap_or(rate),
+        }),
+        stream_buffer: cli.stream_buffer,
+        stream_buffer_timeout: Duration::from_secs(cli.stream_buffer_timeout),
+        // keep active streams inside the client idle timeout
+        sse_keepalive: cli
+            .client_idle_timeout
+            .map(|timeout| Duration::from_secs(timeout).div_f64(2.0)),
+        request_timeout: cli.request_timeout.map(Duration::from_secs),
+        admin_token: cli.admin_token,
+    });
+
+    let mut router = Router::new()
         .route(
             "/v1/completions",
             post(handle_completion).fallback(proxy_handler),
@@ -377,24 +2368,170 @@ pub async fn run() -> anyhow::Result<()> {
             "/v1/chat/completions",
             post(handle_chat).fallback(proxy_handler),
         )
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            json_limit::limit_json,
+        ))
+        // never forwarded to the backend, rejected when `--admin-token` is not set
+        .route("/admin/config", get(admin::config));
+    if let Some(ready_path) = &cli.ready_path {
+        router = router.route(ready_path, get(ready::ready));
+    }
+    if state.fingerprints.is_some() {
+        router = router.route("/debug/fingerprints", get(fingerprints_handler));
+    }
+    if state.load_shedder.is_some() {
+        router = router.route("/debug/shed", get(shed_handler));
+    }
+    if state.cancels.is_some() {
+        router = router.route("/v1/cancel/{request_id}", post(cancel_handler));
+    }
+
+    let app = router
         .fallback(proxy_handler)
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            request_timeout_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            client_ip_middleware,
+        ))
         .layer(cors)
-        .with_state(Arc::new(ServerState {
-            backend: cli.backend.parse()?,
-            client: Default::default(),
-            input_max_token: cli.input_max_token,
-            bpe,
-            cot_parser: cli.cot_parser,
-        }));
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            decompress::limit_decompressed,
+        ))
+        // clients on slow links may send `Content-Encoding: gzip` bodies
+        .layer(RequestDecompressionLayer::new())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            decompress::count_compressed,
+        ))
+        .with_state(state);
+
+    Ok(app)
+}
 
-    let listener = TcpListener::bind(cli.listen).await?;
+async fn client_ip_middleware(
+    state: State<Arc<ServerState>>,
+    ConnectInfo(PeerAddr(peer)): ConnectInfo<PeerAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client_ip = client_ip::resolve(peer.ip(), request.headers(), state.trust_proxy);
+    request.extensions_mut().insert(ClientIp(client_ip));
 
-    select! {
-        res = axum::serve(listener, app).into_future().fuse() => res?,
-        _ = signal_stop().fuse() => {}
+    let span = info_span!("request", %client_ip);
+
+    #[cfg(feature = "otel")]
+    otel::set_parent(&span, request.headers());
+
+    let connection_guard = match &state.connection_limiter {
+        None => None,
+
+        Some(connection_limiter) => match connection_limiter.acquire(&client_ip.to_string()) {
+            None => {
+                span.in_scope(|| warn!("too many connections of the client ip"));
+
+                return error::openai_error(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "too many connections of the client ip",
+                    Some("too_many_connections"),
+                );
+            }
+
+            Some(connection_guard) => Some(connection_guard),
+        },
+    };
+
+    let response = next.run(request).instrument(span).await;
+
+    match connection_guard {
+        // only the stream is wrapped, the other bodies keep their size and release the slot with
+        // the handler
+        Some(connection_guard) if sse::is_event_stream(response.headers()) => {
+            // release the slot when the stream ends or the client disconnects
+            response.map(|body| {
+                Body::from_stream(body.into_data_stream().inspect(move |_| {
+                    let _ = &connection_guard;
+                }))
+            })
+        }
+
+        _ => response,
     }
+}
 
-    Ok(())
+async fn request_timeout_middleware(
+    state: State<Arc<ServerState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(request_timeout) = state.request_timeout else {
+        return next.run(request).await;
+    };
+
+    match time::timeout(request_timeout, next.run(request)).await {
+        Ok(response) => response,
+
+        Err(_) => {
+            warn!(?request_timeout, "request timeout");
+
+            error::openai_error(
+                StatusCode::GATEWAY_TIMEOUT,
+                "request timeout",
+                Some("request_timeout"),
+            )
+        }
+    }
+}
+
+fn build_client(cli: &Cli) -> anyhow::Result<Client> {
+    // reqwest strips Authorization and Cookie headers when redirecting to other host
+    let redirect = match cli.follow_redirects {
+        FollowRedirects::None => Policy::none(),
+        FollowRedirects::SameHost => Policy::custom(|attempt| {
+            if attempt.previous().len() > MAX_REDIRECTS {
+                return attempt.error("too many redirects");
+            }
+
+            let same_host = attempt.previous().last().is_none_or(|previous| {
+                previous.host_str() == attempt.url().host_str()
+                    && previous.port_or_known_default() == attempt.url().port_or_known_default()
+            });
+            if same_host {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        }),
+        FollowRedirects::Limited => Policy::limited(MAX_REDIRECTS),
+    };
+
+    let mut builder = Client::builder()
+        .redirect(redirect)
+        .user_agent(&cli.upstream_user_agent);
+    if let Some(outbound_proxy) = &cli.outbound_proxy {
+        let url = outbound_proxy
+            .parse::<Url>()
+            .with_context(|| format!("invalid outbound proxy {outbound_proxy}"))?;
+        if !OUTBOUND_PROXY_SCHEMES.contains(&url.scheme()) {
+            anyhow::bail!("unsupported outbound proxy scheme {}", url.scheme());
+        }
+
+        let proxy = Proxy::all(url)
+            .with_context(|| format!("invalid outbound proxy {outbound_proxy}"))?
+            .no_proxy(
+                cli.outbound_no_proxy
+                    .as_deref()
+                    .and_then(NoProxy::from_string),
+            );
+
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder.build()?)
 }
 
 async fn signal_stop() {
@@ -407,22 +2544,35 @@ async fn signal_stop() {
     }
 }
 
-fn init_log(debug: bool) {
+/// the log level of each target
+fn log_targets(cli: &Cli) -> Targets {
+    let level = if cli.debug {
+        LevelFilter::DEBUG
+    } else {
+        LevelFilter::INFO
+    };
+
+    Targets::new()
+        .with_default(level)
+        .with_target("hickory_resolver", LevelFilter::OFF)
+        .with_target(
+            truncate::LOG_TARGET,
+            cli.truncation_log_level.unwrap_or(level),
+        )
+}
+
+fn init_log(cli: &Cli) -> anyhow::Result<()> {
     let layer = fmt::layer()
         .pretty()
         .with_target(true)
         .with_writer(io::stderr);
 
-    let level = if debug {
-        LevelFilter::DEBUG
-    } else {
-        LevelFilter::INFO
-    };
+    let layered = Registry::default().with(log_targets(cli)).with(layer);
 
-    let targets = Targets::new()
-        .with_default(LevelFilter::DEBUG)
-        .with_target("hickory_resolver", LevelFilter::OFF);
-    let layered = Registry::default().with(targets).with(layer).with(level);
+    #[cfg(feature = "otel")]
+    let layered = layered.with(cli.otlp_endpoint.as_deref().map(otel::layer).transpose()?);
 
-    subscriber::set_global_default(layered).unwrap();
+    subscriber::set_global_default(layered)?;
+
+    Ok(())
 }
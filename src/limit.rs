@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+
+use tracing::debug;
+
+/// limit the concurrent holders of each key, e.g. the streams of each api key or the in-flight
+/// requests of each client ip
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    max: usize,
+    active: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            active: Default::default(),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// return [`None`] when the key already holds max slots, the slot is released when the guard
+    /// is dropped
+    pub fn acquire(&self, key: &str) -> Option<ConcurrencyGuard> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(key.to_string()).or_default();
+        if *count >= self.max {
+            return None;
+        }
+
+        *count += 1;
+
+        debug!(active = *count, "acquire concurrency slot");
+
+        Some(ConcurrencyGuard {
+            active: self.active.clone(),
+            key: key.to_string(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ConcurrencyGuard {
+    active: Arc<Mutex<HashMap<String, usize>>>,
+    key: String,
+}
+
+impl Drop for ConcurrencyGuard {
+    fn drop(&mut self) {
+        let mut active = self.active.lock().unwrap_or_else(|err| err.into_inner());
+        if let Entry::Occupied(mut entry) = active.entry(self.key.clone()) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
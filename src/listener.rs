@@ -0,0 +1,238 @@
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use anyhow::Context as _;
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{self, TcpListener, TcpStream};
+use tokio::time::{self, Instant, Sleep};
+use tracing::warn;
+
+use crate::cli::DualStack;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// bind the listen addr, when `dual_stack` is set, `IPV6_V6ONLY` of IPv6 addr is set explicitly,
+/// otherwise the OS default is used
+pub async fn bind(listen: &str, dual_stack: Option<DualStack>) -> anyhow::Result<TcpListener> {
+    let Some(dual_stack) = dual_stack else {
+        return Ok(TcpListener::bind(listen).await?);
+    };
+
+    let addr = net::lookup_host(listen)
+        .await?
+        .next()
+        .with_context(|| format!("listen addr {listen} resolves nothing"))?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if let SocketAddr::V6(_) = addr {
+        socket.set_only_v6(dual_stack == DualStack::Off)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// the peer addr of an accepted client connection
+#[derive(Debug, Copy, Clone)]
+pub struct PeerAddr(pub SocketAddr);
+
+impl Connected<IncomingStream<'_, ClientListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, ClientListener>) -> Self {
+        PeerAddr(*stream.remote_addr())
+    }
+}
+
+/// a [`TcpListener`] which applies TCP keep-alive and idle timeout to accepted client connections
+#[derive(Debug)]
+pub struct ClientListener {
+    inner: TcpListener,
+    keepalive: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl ClientListener {
+    pub fn new(
+        inner: TcpListener,
+        keepalive: Option<Duration>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            keepalive,
+            idle_timeout,
+        }
+    }
+}
+
+impl Listener for ClientListener {
+    type Io = IdleTimeoutStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, addr) = Listener::accept(&mut self.inner).await;
+
+        if let Some(keepalive) = self.keepalive
+            && let Err(err) =
+                SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))
+        {
+            warn!(%err, %addr, "set client connection keepalive failed");
+        }
+
+        (IdleTimeoutStream::new(stream, self.idle_timeout), addr)
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+/// the idle timer is armed when the connection is accepted and after a response write, and is
+/// disarmed when request data is read, so an in-flight request is never reaped, a streaming
+/// response stays alive as long as it keeps writing
+#[derive(Debug)]
+pub struct IdleTimeoutStream {
+    stream: TcpStream,
+    idle: Option<IdleTimer>,
+}
+
+#[derive(Debug)]
+struct IdleTimer {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+    armed: bool,
+}
+
+impl IdleTimeoutStream {
+    fn new(stream: TcpStream, idle_timeout: Option<Duration>) -> Self {
+        Self {
+            stream,
+            idle: idle_timeout.map(|timeout| IdleTimer {
+                timeout,
+                sleep: Box::pin(time::sleep(timeout)),
+                armed: true,
+            }),
+        }
+    }
+
+    fn set_armed(&mut self, armed: bool) {
+        if let Some(idle) = &mut self.idle {
+            idle.armed = armed;
+            if armed {
+                idle.sleep.as_mut().reset(Instant::now() + idle.timeout);
+            }
+        }
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        match &mut self.idle {
+            Some(idle) if idle.armed => {
+                idle.sleep.as_mut().poll(cx).map(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "client connection idle timeout")
+                })
+            }
+
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for IdleTimeoutStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        match Pin::new(&mut this.stream).poll_read(cx, buf) {
+            Poll::Ready(res) => {
+                if buf.filled().len() > filled {
+                    this.set_armed(false);
+                }
+
+                Poll::Ready(res)
+            }
+
+            Poll::Pending => this.poll_idle(cx).map(Err),
+        }
+    }
+}
+
+impl AsyncWrite for IdleTimeoutStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let res = ready!(Pin::new(&mut this.stream).poll_write(cx, buf));
+        if res.as_ref().is_ok_and(|n| *n > 0) {
+            this.set_armed(true);
+        }
+
+        Poll::Ready(res)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let res = ready!(Pin::new(&mut this.stream).poll_write_vectored(cx, bufs));
+        if res.as_ref().is_ok_and(|n| *n > 0) {
+            this.set_armed(true);
+        }
+
+        Poll::Ready(res)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn dual_stack_bind() {
+        let listener = bind("[::]:0", Some(DualStack::On)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        listener.accept().await.unwrap();
+
+        let listener = bind("[::]:0", Some(DualStack::Off)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap_err();
+        TcpStream::connect(("::1", port)).await.unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use serde_json::{Map, Value};
+use tiktoken_rs::{CoreBPE, Rank};
+use tracing::{debug, warn};
+
+/// re-encode `logit_bias` token ids from the client tokenizer to the backend tokenizer, when a
+/// token is re-encoded to multiple tokens, all of them get the bias
+pub fn remap(logit_bias: &mut Value, from: &CoreBPE, to: &CoreBPE) {
+    let Some(biases) = logit_bias.as_object() else {
+        return;
+    };
+
+    let mut remapped = Map::new();
+    for (token, bias) in biases {
+        let Ok(token) = token.parse::<Rank>() else {
+            warn!(token, "invalid logit_bias token, drop it");
+
+            continue;
+        };
+
+        let text = match from.decode(vec![token]) {
+            Err(err) => {
+                warn!(token, %err, "decode logit_bias token failed, drop it");
+
+                continue;
+            }
+
+            Ok(text) => text,
+        };
+
+        let new_tokens = to.encode_with_special_tokens(&text);
+
+        debug!(token, ?new_tokens, "remap logit_bias token");
+
+        for new_token in new_tokens {
+            remapped.insert(new_token.to_string(), bias.clone());
+        }
+    }
+
+    *logit_bias = Value::Object(remapped);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn remap_small_bias_map() {
+        let cl100k = tiktoken_rs::cl100k_base().unwrap();
+        let o200k = tiktoken_rs::o200k_base().unwrap();
+        let token = |bpe: &CoreBPE, text| {
+            let tokens = bpe.encode_with_special_tokens(text);
+            assert_eq!(tokens.len(), 1);
+
+            tokens[0].to_string()
+        };
+
+        let mut logit_bias = json!({
+            token(&cl100k, " hello"): -100,
+            token(&cl100k, " world"): 5,
+            "not a token": 1,
+            "4294967295": 1,
+        });
+        remap(&mut logit_bias, &cl100k, &o200k);
+
+        assert_eq!(
+            logit_bias,
+            json!({
+                token(&o200k, " hello"): -100,
+                token(&o200k, " world"): 5,
+            })
+        );
+
+        // not a map, kept as is
+        let mut logit_bias = json!([1, 2]);
+        remap(&mut logit_bias, &cl100k, &o200k);
+        assert_eq!(logit_bias, json!([1, 2]));
+    }
+}
@@ -0,0 +1,96 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use lru::LruCache;
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+const CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(1024).unwrap();
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+}
+
+/// check inputs with an OpenAI compatible moderation endpoint, the verdicts are cached briefly so
+/// a retried request is not moderated twice
+#[derive(Debug)]
+pub struct Moderator {
+    endpoint: Url,
+    cache: Mutex<LruCache<[u8; 32], (Instant, bool)>>,
+}
+
+impl Moderator {
+    pub fn new(endpoint: Url) -> Self {
+        Self {
+            endpoint,
+            cache: Mutex::new(LruCache::new(CACHE_SIZE)),
+        }
+    }
+
+    pub fn endpoint(&self) -> &Url {
+        &self.endpoint
+    }
+
+    /// return whether any of the inputs is flagged
+    pub async fn flagged(
+        &self,
+        client: &Client,
+        authorization: Option<&HeaderValue>,
+        inputs: &[&str],
+    ) -> anyhow::Result<bool> {
+        let key = cache_key(inputs);
+
+        if let Some((checked_at, flagged)) = self.cache.lock().unwrap().get(&key)
+            && checked_at.elapsed() < CACHE_TTL
+        {
+            return Ok(*flagged);
+        }
+
+        let mut request = client
+            .post(self.endpoint.clone())
+            .json(&json!({ "input": inputs }));
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("request moderation endpoint {} failed", self.endpoint))?
+            .error_for_status()?
+            .json::<ModerationResponse>()
+            .await
+            .context("decode moderation response failed")?;
+
+        let flagged = response.results.iter().any(|result| result.flagged);
+        self.cache
+            .lock()
+            .unwrap()
+            .put(key, (Instant::now(), flagged));
+
+        Ok(flagged)
+    }
+}
+
+/// the SHA-256 of the length prefixed inputs, so different inputs can't share a verdict
+fn cache_key(inputs: &[&str]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for input in inputs {
+        hasher.update((input.len() as u64).to_le_bytes());
+        hasher.update(input);
+    }
+
+    hasher.finalize().into()
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Number, Value};
+
+/// the sampling fields kept in the flattened request fields, their string values are coerced
+const SAMPLING_FIELDS: &[&str] = &[
+    "top_p",
+    "top_k",
+    "min_p",
+    "frequency_penalty",
+    "presence_penalty",
+    "repetition_penalty",
+];
+
+/// parse the string encoded number, e.g. `"0.7"`, which some clients send
+fn parse_number(s: &str) -> Option<Number> {
+    s.trim().parse().ok()
+}
+
+/// deserialize the optional float which may be encoded as a string
+pub fn lenient_f64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    match Option::<Value>::deserialize(deserializer)? {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Number(n)) => Ok(n.as_f64()),
+        Some(Value::String(s)) => parse_number(&s)
+            .and_then(|n| n.as_f64())
+            .map(Some)
+            .ok_or_else(|| D::Error::custom(format!("invalid number `{s}`"))),
+        Some(value) => Err(D::Error::custom(format!(
+            "invalid number `{value}`, expect number or string"
+        ))),
+    }
+}
+
+/// coerce the string encoded sampling fields to numbers
+pub fn coerce_sampling_fields(other_fields: &mut HashMap<String, Value>) -> Result<(), String> {
+    for field in SAMPLING_FIELDS {
+        if let Some(value) = other_fields.get_mut(*field)
+            && let Value::String(s) = value
+        {
+            let n = parse_number(s).ok_or_else(|| format!("invalid `{field}` number `{s}`"))?;
+            *value = Value::Number(n);
+        }
+    }
+
+    Ok(())
+}
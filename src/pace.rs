@@ -0,0 +1,111 @@
+use std::num::NonZeroU32;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{Stream, StreamExt};
+use tokio::time::{self, Instant};
+
+use crate::sse::Chunk;
+use crate::tokenizer::Encoder;
+use crate::truncate::encode;
+
+/// the tokens per second of the paced stream
+#[derive(Debug, Copy, Clone)]
+pub struct PaceRate {
+    pub content: NonZeroU32,
+    pub reasoning: NonZeroU32,
+}
+
+/// delay the chunks so the text is sent at most at the rate, the delay of a chunk is paid before
+/// the next chunk, the time waiting for the upstream counts toward it
+pub async gen fn pace<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    encoder: Arc<Encoder>,
+    rate: PaceRate,
+) -> anyhow::Result<Chunk> {
+    let mut due = Instant::now();
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        let mut delay = Duration::ZERO;
+        for choice in &chunk.choices {
+            for (text, rate) in [
+                (&choice.delta.reasoning_content, rate.reasoning),
+                (&choice.delta.content, rate.content),
+            ] {
+                if let Some(text) = text.as_deref().filter(|text| !text.is_empty()) {
+                    let tokens = encode(&encoder.bpe, encoder.token_cache.as_ref(), text).len();
+                    delay += Duration::from_secs(tokens as _) / rate.get();
+                }
+            }
+        }
+
+        time::sleep_until(due).await;
+
+        yield Ok(chunk);
+
+        due = due.max(Instant::now()) + delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+    use crate::tokenizer::Bpe;
+
+    /// the elapsed time when each chunk arrives
+    async fn paced_arrivals(deltas: &[&str], rate: PaceRate) -> Vec<Duration> {
+        let encoder = Arc::new(Encoder {
+            bpe: Bpe::Estimate,
+            token_cache: None,
+        });
+        let st = stream::iter(build_chunks(deltas).unwrap().into_iter().map(Ok));
+        let mut st = pin!(StreamAsyncIterAdapter(pace(st, encoder, rate)));
+
+        let start = Instant::now();
+        let mut arrivals = vec![];
+        while let Some(chunk) = st.next().await {
+            chunk.unwrap();
+            arrivals.push(start.elapsed());
+        }
+
+        arrivals
+    }
+
+    #[tokio::test]
+    async fn pace_output_rate() {
+        let rate = PaceRate {
+            content: NonZeroU32::new(20).unwrap(),
+            reasoning: NonZeroU32::new(10).unwrap(),
+        };
+
+        // each delta is one estimated token, the content is sent 50ms apart
+        let arrivals = paced_arrivals(&[r#"{"content":"abc"}"#; 5], rate).await;
+        assert!(arrivals[0] < Duration::from_millis(20), "{arrivals:?}");
+        assert!(arrivals[4] >= Duration::from_millis(190), "{arrivals:?}");
+        assert!(arrivals[4] < Duration::from_millis(400), "{arrivals:?}");
+
+        // the reasoning is sent 100ms apart
+        let arrivals = paced_arrivals(&[r#"{"reasoning_content":"abc"}"#; 3], rate).await;
+        assert!(arrivals[2] >= Duration::from_millis(190), "{arrivals:?}");
+        assert!(arrivals[2] < Duration::from_millis(400), "{arrivals:?}");
+
+        // the chunks without text are not delayed
+        let arrivals = paced_arrivals(&[r#"{"role":"assistant"}"#; 5], rate).await;
+        assert!(arrivals[4] < Duration::from_millis(20), "{arrivals:?}");
+    }
+}
@@ -0,0 +1,23 @@
+use serde_json::Value;
+
+/// the model price per 1k tokens
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl Price {
+    /// estimate the cost from the OpenAI `usage`, return [`None`] when the usage has no token count
+    pub fn cost(&self, usage: &Value) -> Option<f64> {
+        let prompt_tokens = usage.get("prompt_tokens")?.as_u64()?;
+        let completion_tokens = usage.get("completion_tokens")?.as_u64()?;
+
+        Some((prompt_tokens as f64 * self.input + completion_tokens as f64 * self.output) / 1000.0)
+    }
+}
+
+/// format the cost for the `X-Estimated-Cost` header
+pub fn format_cost(cost: f64) -> String {
+    format!("{cost:.6}")
+}
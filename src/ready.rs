@@ -0,0 +1,124 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use reqwest::Url;
+use serde_json::{Value, json};
+use tracing::warn;
+
+use crate::admin::redact_url;
+use crate::reasoning_sink::SinkTarget;
+use crate::truncate::Tokenize;
+use crate::{ServerState, check};
+
+/// readiness probes are usually frequent with a short timeout
+const BACKEND_TIMEOUT: Duration = Duration::from_secs(3);
+const TOKENIZER_CHECK_TEXT: &str = "ready";
+
+/// `GET /ready`, check the backend, the tokenizer and the config, return the status of each
+/// subsystem, `503` when any of them is not ready
+pub async fn ready(state: State<Arc<ServerState>>) -> Response {
+    let checks = [
+        ("backend", check_backend(&state).await),
+        ("tokenizer", check_tokenizer(&state).await),
+        ("config", check_config(&state)),
+    ];
+
+    let mut ready = true;
+    let mut subsystems = serde_json::Map::new();
+    for (name, check) in checks {
+        let status = match check {
+            Ok(mut detail) => {
+                detail["ok"] = Value::Bool(true);
+
+                detail
+            }
+
+            Err(err) => {
+                warn!(subsystem = name, %err, "subsystem is not ready");
+
+                ready = false;
+
+                json!({ "ok": false, "error": err.to_string() })
+            }
+        };
+
+        subsystems.insert(name.to_string(), status);
+    }
+
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (
+        status,
+        Json(json!({
+            "ready": ready,
+            "subsystems": subsystems,
+        })),
+    )
+        .into_response()
+}
+
+/// any HTTP response means the backend is reachable
+async fn check_backend(state: &ServerState) -> anyhow::Result<Value> {
+    let (_, status) = check::reachable(&state.backend, &state.client, BACKEND_TIMEOUT).await?;
+
+    Ok(json!({ "status": status.as_u16() }))
+}
+
+async fn check_tokenizer(state: &ServerState) -> anyhow::Result<Value> {
+    let encoder = state.encoders.get("").await?;
+    if encoder.bpe.tokenize(TOKENIZER_CHECK_TEXT).is_empty() {
+        anyhow::bail!("default tokenizer encodes nothing");
+    }
+
+    Ok(json!({
+        "loaded": state.encoders.loaded(),
+        "estimating": state.encoders.estimating(),
+    }))
+}
+
+fn check_config(state: &ServerState) -> anyhow::Result<Value> {
+    check_url("backend", &state.backend)?;
+    if let Some(moderator) = &state.moderator {
+        check_url("moderation endpoint", moderator.endpoint())?;
+    }
+    if let Some(summarizer) = &state.summarizer {
+        check_url("summarize endpoint", summarizer.endpoint())?;
+    }
+    if let Some(SinkTarget::Url(url)) = state.reasoning_sink.as_ref().map(|sink| sink.target()) {
+        check_url("reasoning sink", url)?;
+    }
+
+    if let Some(model) = state
+        .fallback_models
+        .iter()
+        .find_map(|(model, fallback)| (model == fallback).then_some(model))
+    {
+        anyhow::bail!("model {model} falls back to itself");
+    }
+
+    if let Some(context_window) = state.context_window
+        && state.context_min_output_token >= context_window
+    {
+        anyhow::bail!(
+            "context min output token {} leaves no prompt room in context window {context_window}",
+            state.context_min_output_token
+        );
+    }
+
+    Ok(json!({}))
+}
+
+fn check_url(name: &str, url: &Url) -> anyhow::Result<()> {
+    if !matches!(url.scheme(), "http" | "https") || !url.has_host() {
+        anyhow::bail!("invalid {name} {}", redact_url(url));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use std::pin::pin;
+use std::sync::Arc;
+
+use futures_util::{Stream, StreamExt};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::sse::Chunk;
+use crate::tokenizer::Encoder;
+use crate::truncate::encode;
+
+fn count(encoder: &Encoder, text: Option<&str>) -> usize {
+    text.filter(|text| !text.is_empty()).map_or(0, |text| {
+        encode(&encoder.bpe, encoder.token_cache.as_ref(), text).len()
+    })
+}
+
+/// warn when the reasoning tokens exceed `threshold` times the content tokens, the response spends
+/// too much on reasoning, return whether it alerts
+fn alert(threshold: f64, model: &str, reasoning_tokens: usize, content_tokens: usize) -> bool {
+    if reasoning_tokens == 0 || reasoning_tokens as f64 <= threshold * content_tokens as f64 {
+        return false;
+    }
+
+    warn!(
+        model,
+        reasoning_tokens, content_tokens, threshold, "reasoning to content ratio is too high"
+    );
+
+    true
+}
+
+/// check the reasoning to content ratio of the non streaming chat response, all choices are
+/// counted together, return whether it alerts
+pub fn check_response(threshold: f64, encoder: &Encoder, response: &Value) -> bool {
+    let Some(choices) = response.get("choices").and_then(Value::as_array) else {
+        return false;
+    };
+
+    let (mut reasoning_tokens, mut content_tokens) = (0, 0);
+    for message in choices.iter().filter_map(|choice| choice.get("message")) {
+        reasoning_tokens += count(
+            encoder,
+            message.get("reasoning_content").and_then(Value::as_str),
+        );
+        content_tokens += count(encoder, message.get("content").and_then(Value::as_str));
+    }
+
+    let model = response
+        .get("model")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    alert(threshold, model, reasoning_tokens, content_tokens)
+}
+
+/// check the reasoning to content ratio once the CoT parsed stream is done, all choices are
+/// counted together
+pub async gen fn check_stream<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    threshold: f64,
+    encoder: Arc<Encoder>,
+) -> anyhow::Result<Chunk> {
+    let (mut reasoning_tokens, mut content_tokens) = (0, 0);
+    let mut model = String::new();
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        if model.is_empty() {
+            model.clone_from(&chunk.model);
+        }
+        for choice in &chunk.choices {
+            reasoning_tokens += count(&encoder, choice.delta.reasoning_content.as_deref());
+            content_tokens += count(&encoder, choice.delta.content.as_deref());
+        }
+
+        yield Ok(chunk);
+    }
+
+    alert(threshold, &model, reasoning_tokens, content_tokens);
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{TryStreamExt, stream};
+    use serde_json::json;
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+    use crate::tokenizer::Bpe;
+
+    /// 3 bytes per token
+    fn encoder() -> Encoder {
+        Encoder {
+            bpe: Bpe::Estimate,
+            token_cache: None,
+        }
+    }
+
+    fn response(choices: &[(&str, &str)]) -> Value {
+        let choices = choices
+            .iter()
+            .map(|(reasoning, content)| {
+                json!({"message": {"reasoning_content": reasoning, "content": content}})
+            })
+            .collect::<Vec<_>>();
+
+        json!({"model": "gpt-4o", "choices": choices})
+    }
+
+    #[test]
+    fn alert_high_ratio_response() {
+        let encoder = encoder();
+
+        // 30 reasoning tokens to 2 content tokens
+        assert!(check_response(
+            10.0,
+            &encoder,
+            &response(&[(&"x".repeat(90), "answer")])
+        ));
+        assert!(!check_response(
+            20.0,
+            &encoder,
+            &response(&[(&"x".repeat(90), "answer")])
+        ));
+        // the choices are counted together
+        assert!(!check_response(
+            10.0,
+            &encoder,
+            &response(&[(&"x".repeat(90), "answer"), ("", &"y".repeat(30))])
+        ));
+        // no reasoning never alerts, even without content
+        assert!(!check_response(0.0, &encoder, &response(&[("", "")])));
+        assert!(!check_response(10.0, &encoder, &json!({})));
+    }
+
+    #[tokio::test]
+    async fn pass_stream_through() {
+        let deltas = [
+            r#"{"role":"assistant","reasoning_content":"think"}"#,
+            r#"{"content":"answer"}"#,
+        ];
+        let chunks = build_chunks(&deltas).unwrap();
+        let st = stream::iter(chunks.clone().into_iter().map(Ok));
+
+        let checked = StreamAsyncIterAdapter(check_stream(st, 0.0, Arc::new(encoder())))
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&checked).unwrap(),
+            serde_json::to_value(&chunks).unwrap()
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::Arc;
+
+use anyhow::Context;
+use futures_util::{Stream, StreamExt};
+use reqwest::{Client, Url};
+use serde_json::{Value, json};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{self, Receiver};
+use tracing::warn;
+
+use crate::sse::Chunk;
+
+/// the reasoning records waiting to be written, more are dropped so the streams never wait for
+/// the sink
+const QUEUE_SIZE: usize = 1024;
+
+/// where the reasoning is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SinkTarget {
+    /// append a JSON line of each request to the file
+    File(PathBuf),
+    /// `POST` a JSON of each request to the URL
+    Url(Url),
+}
+
+impl Display for SinkTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkTarget::File(path) => write!(f, "{}", path.display()),
+            SinkTarget::Url(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// write the reasoning of the CoT parsed streams to the sink in the background
+#[derive(Debug)]
+pub struct ReasoningSink {
+    target: SinkTarget,
+    tx: mpsc::Sender<Value>,
+}
+
+impl ReasoningSink {
+    pub fn new(target: SinkTarget, client: Client) -> anyhow::Result<Self> {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+
+        match &target {
+            SinkTarget::File(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("open reasoning sink {} failed", path.display()))?;
+
+                tokio::spawn(write_file(File::from_std(file), rx));
+            }
+
+            SinkTarget::Url(url) => {
+                tokio::spawn(post_url(url.clone(), client, rx));
+            }
+        }
+
+        Ok(Self { target, tx })
+    }
+
+    pub fn target(&self) -> &SinkTarget {
+        &self.target
+    }
+
+    fn send(&self, record: Value) {
+        if let Err(err) = self.tx.try_send(record) {
+            warn!(%err, "reasoning sink is busy, drop the reasoning");
+        }
+    }
+}
+
+async fn write_file(mut file: File, mut rx: Receiver<Value>) {
+    while let Some(record) = rx.recv().await {
+        let mut line = record.to_string();
+        line.push('\n');
+
+        if let Err(err) = file.write_all(line.as_bytes()).await {
+            warn!(%err, "write reasoning sink failed");
+
+            continue;
+        }
+        if let Err(err) = file.flush().await {
+            warn!(%err, "flush reasoning sink failed");
+        }
+    }
+}
+
+async fn post_url(url: Url, client: Client, mut rx: Receiver<Value>) {
+    while let Some(record) = rx.recv().await {
+        let result = client
+            .post(url.clone())
+            .json(&record)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            warn!(%err, %url, "post reasoning sink failed");
+        }
+    }
+}
+
+/// the reasoning of a request, it is sent to the sink when the stream ends or is dropped by the
+/// client
+struct Record {
+    sink: Arc<ReasoningSink>,
+    request_id: Option<String>,
+    id: String,
+    model: String,
+    reasoning: BTreeMap<i64, String>,
+}
+
+impl Record {
+    fn push(&mut self, chunk: &Chunk) {
+        if self.id.is_empty() {
+            self.id.clone_from(&chunk.id);
+            self.model.clone_from(&chunk.model);
+        }
+
+        for choice in &chunk.choices {
+            if let Some(reasoning) = &choice.delta.reasoning_content {
+                self.reasoning
+                    .entry(choice.index)
+                    .or_default()
+                    .push_str(reasoning);
+            }
+        }
+    }
+}
+
+impl Drop for Record {
+    fn drop(&mut self) {
+        if self.reasoning.is_empty() {
+            return;
+        }
+
+        let choices = self
+            .reasoning
+            .iter()
+            .map(|(index, reasoning)| {
+                json!({
+                    "index": index,
+                    "reasoning_content": reasoning,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.sink.send(json!({
+            "request_id": self.request_id,
+            "id": self.id,
+            "model": self.model,
+            "choices": choices,
+        }));
+    }
+}
+
+/// tee the `reasoning_content` of the stream to the sink, the stream is not changed
+pub async gen fn tee<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    sink: Arc<ReasoningSink>,
+    request_id: Option<String>,
+) -> anyhow::Result<Chunk> {
+    let mut record = Record {
+        sink,
+        request_id,
+        id: String::new(),
+        model: String::new(),
+        reasoning: BTreeMap::new(),
+    };
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        if let Ok(chunk) = &chunk {
+            record.push(chunk);
+        }
+
+        yield chunk;
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::pin::pin;
+use std::sync::Arc;
+
+use futures_util::{Stream, StreamExt};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::sse::{Choice, Chunk, Delta};
+
+const REDACTED: &str = "[REDACTED]";
+
+/// replace the matched secrets in model output
+#[derive(Debug)]
+pub struct Redactor {
+    regexes: Vec<Regex>,
+    reasoning: bool,
+    window: usize,
+}
+
+#[derive(Debug, Default)]
+struct HeldText {
+    reasoning_content: String,
+    content: String,
+}
+
+impl Redactor {
+    pub fn new(patterns: &[String], reasoning: bool, window: usize) -> anyhow::Result<Self> {
+        let regexes = patterns
+            .iter()
+            .map(|pattern| Regex::new(pattern))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            regexes,
+            reasoning,
+            window,
+        })
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.regexes.iter().fold(text.to_string(), |text, regex| {
+            regex.replace_all(&text, REDACTED).into_owned()
+        })
+    }
+
+    /// redact `message.content` of chat and `text` of completion in the non-streaming response
+    pub fn redact_response(&self, response: &mut Value) {
+        let Some(choices) = response.get_mut("choices").and_then(Value::as_array_mut) else {
+            return;
+        };
+
+        for choice in choices {
+            if let Some(Value::String(text)) = choice.get_mut("text") {
+                *text = self.redact(text);
+            }
+
+            let Some(message) = choice.get_mut("message") else {
+                continue;
+            };
+
+            if let Some(Value::String(content)) = message.get_mut("content") {
+                *content = self.redact(content);
+            }
+            if self.reasoning
+                && let Some(Value::String(reasoning_content)) = message.get_mut("reasoning_content")
+            {
+                *reasoning_content = self.redact(reasoning_content);
+            }
+        }
+    }
+
+    /// append the delta text to the raw held text, redact and return the text before the last
+    /// `window` chars, the held tail may be the head of a secret split across chunks, a secret
+    /// reaching into the tail is held entirely, so it is matched again with the next chunk
+    fn push(&self, held: &mut String, text: Option<String>, flush: bool) -> Option<String> {
+        if text.is_none() && (!flush || held.is_empty()) {
+            return None;
+        }
+
+        if let Some(text) = text {
+            held.push_str(&text);
+        }
+
+        if flush {
+            let redacted = self.redact(held);
+            held.clear();
+
+            return Some(redacted);
+        }
+
+        let mut split = match self.window {
+            0 => held.len(),
+            window => held
+                .char_indices()
+                .rev()
+                .nth(window - 1)
+                .map_or(0, |(index, _)| index),
+        };
+
+        // a match may grow with the next chunk when it reaches the split or the end
+        loop {
+            let new_split = self
+                .regexes
+                .iter()
+                .flat_map(|regex| regex.find_iter(held))
+                .filter(|m| m.start() < split && (m.end() > split || m.end() == held.len()))
+                .map(|m| m.start())
+                .min()
+                .unwrap_or(split);
+            if new_split == split {
+                break;
+            }
+
+            split = new_split;
+        }
+
+        let rest = held.split_off(split);
+        let redacted = self.redact(held);
+        *held = rest;
+
+        Some(redacted)
+    }
+}
+
+/// redact streaming `content`, and `reasoning_content` if enabled, the held text of each choice is
+/// flushed at its `finish_reason` or the end of stream
+pub async gen fn redact_stream<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    redactor: Arc<Redactor>,
+) -> anyhow::Result<Chunk> {
+    let mut held_texts = HashMap::<i64, HeldText>::new();
+    let mut last_chunk = None;
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let mut chunk = match chunk {
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        for choice in &mut chunk.choices {
+            let held = held_texts.entry(choice.index).or_default();
+            let flush = choice.finish_reason.is_some();
+
+            if redactor.reasoning {
+                choice.delta.reasoning_content = redactor.push(
+                    &mut held.reasoning_content,
+                    choice.delta.reasoning_content.take(),
+                    flush,
+                );
+            }
+            choice.delta.content =
+                redactor.push(&mut held.content, choice.delta.content.take(), flush);
+        }
+
+        last_chunk = Some(chunk.clone());
+
+        yield Ok(chunk);
+    }
+
+    let Some(mut last_chunk) = last_chunk else {
+        return;
+    };
+    last_chunk.usage = None;
+    last_chunk.prompt_logprobs = None;
+
+    for (index, held) in held_texts {
+        if held.reasoning_content.is_empty() && held.content.is_empty() {
+            continue;
+        }
+
+        let mut chunk = last_chunk.clone();
+        chunk.choices = vec![Choice {
+            index,
+            delta: Delta {
+                role: None,
+                reasoning_content: Some(redactor.redact(&held.reasoning_content))
+                    .filter(|text| !text.is_empty()),
+                content: Some(redactor.redact(&held.content)).filter(|text| !text.is_empty()),
+                annotations: None,
+            },
+            logprobs: None,
+            finish_reason: None,
+            stop_reason: None,
+            prompt_logprobs: None,
+        }];
+
+        yield Ok(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// push the deltas and flush, return the output of each push
+    fn push_all(redactor: &Redactor, deltas: &[&str]) -> Vec<String> {
+        let mut held = String::new();
+        let mut output = deltas
+            .iter()
+            .map(|delta| {
+                redactor
+                    .push(&mut held, Some(delta.to_string()), false)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        output.push(redactor.push(&mut held, None, true).unwrap_or_default());
+
+        output
+    }
+
+    #[test]
+    fn redact_secret_split_across_deltas() {
+        let redactor = Redactor::new(&["sk-[a-z0-9]+".to_string()], false, 4).unwrap();
+
+        let output = push_all(&redactor, &["key sk-abc", "def end"]);
+        assert_eq!(output.concat(), "key [REDACTED] end");
+        // the redacted text is never held, so it is not cut
+        assert!(output.iter().all(|text| !text.contains("sk-")));
+        assert_eq!(output[0], "key ");
+
+        // the secret longer than the window
+        let output = push_all(&redactor, &["a sk-0123", "4567", "89ab", " b"]);
+        assert_eq!(output.concat(), "a [REDACTED] b");
+        assert!(output.iter().all(|text| !text.contains("sk-")));
+
+        // no window, the secret at the end is still held
+        let redactor = Redactor::new(&["sk-[a-z0-9]+".to_string()], false, 0).unwrap();
+        let output = push_all(&redactor, &["x sk-abc", "def y"]);
+        assert_eq!(output, ["x ", "[REDACTED] y", ""]);
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt::{self, Display, Formatter};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::{HeaderMap, HeaderName};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// the header carrying the request ID to the backend and back to the client
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const CROCKFORD_BASE32: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// the client supplied request ID longer than it is replaced
+const MAX_HEADER_ID_LEN: usize = 128;
+
+/// where the request ID comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestIdSource {
+    Uuid,
+    Ulid,
+    /// the client request header, a UUID is generated when the header is missing or invalid
+    Header(HeaderName),
+}
+
+impl RequestIdSource {
+    pub fn request_id(&self, headers: &HeaderMap) -> String {
+        match self {
+            RequestIdSource::Uuid => uuid(),
+            RequestIdSource::Ulid => ulid(),
+            RequestIdSource::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|id| !id.is_empty() && id.len() <= MAX_HEADER_ID_LEN)
+                // it is echoed in a SSE comment, don't let it break the framing
+                .filter(|id| !id.contains(['\r', '\n']))
+                .map(str::to_string)
+                .unwrap_or_else(uuid),
+        }
+    }
+}
+
+impl Display for RequestIdSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestIdSource::Uuid => f.write_str("uuid"),
+            RequestIdSource::Ulid => f.write_str("ulid"),
+            RequestIdSource::Header(name) => write!(f, "header:{name}"),
+        }
+    }
+}
+
+/// the OS random bytes, when the OS source fails the bytes are derived from the time, the process
+/// and a counter, they are still unique but predictable
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0; 16];
+    if let Err(err) = getrandom::fill(&mut bytes) {
+        warn!(%err, "get random bytes failed, derive the request ID from the time");
+
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let mut hasher = Sha256::new();
+        hasher.update(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_le_bytes(),
+        );
+        hasher.update(process::id().to_le_bytes());
+        hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        bytes.copy_from_slice(&hasher.finalize()[..16]);
+    }
+
+    bytes
+}
+
+/// random UUID v4
+fn uuid() -> String {
+    let mut bytes = random_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let n = u128::from_be_bytes(bytes);
+
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        n >> 96,
+        (n >> 80) & 0xffff,
+        (n >> 64) & 0xffff,
+        (n >> 48) & 0xffff,
+        n & 0xffff_ffff_ffff
+    )
+}
+
+/// ULID, the 48 bits milliseconds timestamp and 80 random bits in Crockford base32, so the IDs
+/// sort by time
+fn ulid() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        & ((1 << 48) - 1);
+    let random = u128::from_be_bytes(random_bytes()) & ((1 << 80) - 1);
+    let n = (millis << 80) | random;
+
+    // 26 chars of 5 bits, the first char only has the top 3 bits
+    (0..26)
+        .rev()
+        .map(|i| CROCKFORD_BASE32[((n >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
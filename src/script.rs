@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use rhai::{AST, Dynamic, Engine, Scope};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+const RESPONSE_FN: &str = "on_response";
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_STRING_SIZE: usize = 16 * 1024 * 1024;
+const MAX_ARRAY_SIZE: usize = 64 * 1024;
+const MAX_MAP_SIZE: usize = 64 * 1024;
+
+/// response post-processing script, the script defines `fn on_response(response)` which returns
+/// the modified response
+pub struct ResponseScript {
+    engine: Engine,
+    ast: AST,
+}
+
+impl ResponseScript {
+    /// load script from file, `max_operations` limits the CPU usage of each call
+    pub fn load(path: &Path, max_operations: u64) -> anyhow::Result<Self> {
+        let script = fs::read_to_string(path)
+            .with_context(|| format!("read response script {} failed", path.display()))?;
+
+        Self::compile(&script, max_operations)
+            .with_context(|| format!("compile response script {} failed", path.display()))
+    }
+
+    fn compile(script: &str, max_operations: u64) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(max_operations)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_ARRAY_SIZE)
+            .set_max_map_size(MAX_MAP_SIZE)
+            .disable_symbol("eval");
+
+        let ast = engine.compile(script)?;
+
+        Ok(Self { engine, ast })
+    }
+
+    /// run `on_response` with the response
+    pub fn process<T: Serialize + DeserializeOwned>(&self, response: T) -> anyhow::Result<T> {
+        let response = rhai::serde::to_dynamic(response)?;
+        let response = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, RESPONSE_FN, (response,))
+            .map_err(|err| anyhow::anyhow!("run response script failed: {err}"))?;
+
+        Ok(rhai::serde::from_dynamic(&response)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    fn load(script: &str, max_operations: u64) -> ResponseScript {
+        ResponseScript::compile(script, max_operations).unwrap()
+    }
+
+    #[test]
+    fn append_disclaimer() {
+        let script = load(
+            r#"
+            fn on_response(response) {
+                for i in 0..response.choices.len() {
+                    response.choices[i].message.content += "\n\n(generated by AI)";
+                }
+                response
+            }
+            "#,
+            10_000,
+        );
+
+        let response = script
+            .process(json!({
+                "id": "test",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": "hi"}}],
+            }))
+            .unwrap();
+
+        assert_eq!(
+            response["choices"][0]["message"]["content"],
+            "hi\n\n(generated by AI)"
+        );
+        assert_eq!(response["id"], "test");
+    }
+
+    #[test]
+    fn limit_script_resources() {
+        let script = load("fn on_response(response) { loop {} }", 10_000);
+        script.process::<Value>(json!({})).unwrap_err();
+
+        let script = load(
+            "fn on_response(response) { let a = []; loop { a.push(1); } }",
+            u64::MAX,
+        );
+        script.process::<Value>(json!({})).unwrap_err();
+
+        let script = load(
+            "fn f(n) { f(n + 1) } fn on_response(response) { f(0) }",
+            u64::MAX,
+        );
+        script.process::<Value>(json!({})).unwrap_err();
+    }
+}
@@ -0,0 +1,319 @@
+use std::pin::pin;
+
+use futures_util::{StreamExt, TryStreamExt, stream};
+use serde_json::{Value, json};
+
+use crate::adapter::StreamAsyncIterAdapter;
+use crate::cli::CotParser;
+use crate::cot::{deepseek, fence};
+use crate::sse::{Chunk, FinishReason};
+
+struct Fixture {
+    name: &'static str,
+    /// the recorded delta of each chunk, the `choices` array of a multiple choices chunk, or the
+    /// whole chunk
+    deltas: &'static [&'static str],
+    /// the expected reasoning of each choice index
+    reasoning: &'static [&'static str],
+    /// the expected content of each choice index
+    content: &'static [&'static str],
+}
+
+const DEEPSEEK_FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "think tag split across chunks",
+        deltas: &[
+            r#"{"role":"assistant","content":""}"#,
+            r#"{"content":"<think>"}"#,
+            r#"{"content":"\n"}"#,
+            r#"{"content":"The user says hello"}"#,
+            r#"{"content":", greet back."}"#,
+            r#"{"content":"\n</think>"}"#,
+            r#"{"content":"\n\n"}"#,
+            r#"{"content":"Hello! How can I help you?"}"#,
+        ],
+        reasoning: &["The user says hello, greet back.\n"],
+        content: &["\n\nHello! How can I help you?"],
+    },
+    Fixture {
+        name: "short think in one chunk",
+        deltas: &[
+            r#"{"role":"assistant"}"#,
+            r#"{"content":"<think>\nsimple</think>\n\nanswer"}"#,
+        ],
+        reasoning: &["simple"],
+        content: &["answer"],
+    },
+    Fixture {
+        name: "think end tag with content",
+        deltas: &[
+            r#"{"content":"<think>\nfirst"}"#,
+            r#"{"content":" second</think>\n\nthe"}"#,
+            r#"{"content":" answer"}"#,
+        ],
+        reasoning: &["first second"],
+        content: &["\n\nthe answer"],
+    },
+    Fixture {
+        name: "native reasoning content",
+        deltas: &[
+            r#"{"role":"assistant","reasoning_content":""}"#,
+            r#"{"reasoning_content":"think"}"#,
+            r#"{"reasoning_content":" more"}"#,
+            r#"{"content":"answer"}"#,
+        ],
+        reasoning: &["think more"],
+        content: &["answer"],
+    },
+    Fixture {
+        name: "reasoning tail and content in one delta",
+        deltas: &[
+            r#"{"reasoning_content":"think"}"#,
+            r#"{"reasoning_content":" done","content":"answer"}"#,
+            r#"{"content":" end"}"#,
+        ],
+        reasoning: &["think done"],
+        content: &["answer end"],
+    },
+    Fixture {
+        name: "no think tag",
+        deltas: &[
+            r#"{"role":"assistant"}"#,
+            r#"{"content":"plain"}"#,
+            r#"{"content":" <think>answer"}"#,
+        ],
+        reasoning: &[""],
+        content: &["plain <think>answer"],
+    },
+];
+
+/// the fixtures are labeled with the default `thinking`
+const MARKDOWN_FENCE_LABEL: &str = "thinking";
+
+const MARKDOWN_FENCE_FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "opening fence split across chunks",
+        deltas: &[
+            r#"{"role":"assistant","content":""}"#,
+            r#"{"content":"```"}"#,
+            r#"{"content":"thin"}"#,
+            r#"{"content":"king\n"}"#,
+            r#"{"content":"The user says hi.\n"}"#,
+            r#"{"content":"```"}"#,
+            r#"{"content":"\n\nHello!"}"#,
+        ],
+        reasoning: &["The user says hi.\n"],
+        content: &["\nHello!"],
+    },
+    Fixture {
+        name: "nested code fence in one chunk",
+        deltas: &[
+            r#"{"role":"assistant"}"#,
+            r#"{"content":"```thinking\nuse code:\n```python\nprint(1)\n```\ndone\n```\nanswer"}"#,
+        ],
+        reasoning: &["use code:\n```python\nprint(1)\n```\ndone\n"],
+        content: &["answer"],
+    },
+    Fixture {
+        name: "nested code fence split across chunks",
+        deltas: &[
+            r#"{"content":"```thinking\n"}"#,
+            r#"{"content":"try\n``"}"#,
+            r#"{"content":"`rust\nfn main() {}\n"}"#,
+            r#"{"content":"  ``"}"#,
+            r#"{"content":"`\n"}"#,
+            r#"{"content":"ok\n`"}"#,
+            r#"{"content":"``\n\nThe answer"}"#,
+        ],
+        reasoning: &["try\n```rust\nfn main() {}\n  ```\nok\n"],
+        content: &["\nThe answer"],
+    },
+    Fixture {
+        name: "other fence label",
+        deltas: &[
+            r#"{"content":"```python\n"}"#,
+            r#"{"content":"print(1)\n```"}"#,
+        ],
+        reasoning: &[""],
+        content: &["```python\nprint(1)\n```"],
+    },
+    Fixture {
+        name: "backticks but no fence",
+        deltas: &[r#"{"content":"``"}"#, r#"{"content":"ok"}"#],
+        reasoning: &[""],
+        content: &["``ok"],
+    },
+    Fixture {
+        name: "stream ends with closing fence",
+        deltas: &[
+            r#"{"role":"assistant","content":"```thinking\nonly"}"#,
+            r#"{"content":" reasoning\n"}"#,
+            r#"[{"index":0,"delta":{"content":"```"},"finish_reason":"length"}]"#,
+        ],
+        reasoning: &["only reasoning\n"],
+        content: &[""],
+    },
+    Fixture {
+        name: "native reasoning content",
+        deltas: &[
+            r#"{"role":"assistant","reasoning_content":"think"}"#,
+            r#"{"content":"```thinking\nanswer\n```"}"#,
+        ],
+        reasoning: &["think"],
+        content: &["```thinking\nanswer\n```"],
+    },
+];
+
+/// the reasoning, content and finish reasons of each choice index
+#[derive(Debug, Default, Eq, PartialEq)]
+struct Output {
+    reasoning: Vec<String>,
+    content: Vec<String>,
+    finish_reasons: Vec<Vec<FinishReason>>,
+    /// the chunk and choice level prompt logprobs, they must be sent once
+    prompt_logprobs: Vec<Value>,
+}
+
+impl Output {
+    fn new(choices: usize) -> Self {
+        Self {
+            reasoning: vec![String::new(); choices],
+            content: vec![String::new(); choices],
+            finish_reasons: vec![vec![]; choices],
+            prompt_logprobs: vec![],
+        }
+    }
+
+    fn push(&mut self, chunk: Chunk) -> anyhow::Result<()> {
+        self.prompt_logprobs.extend(chunk.prompt_logprobs);
+        for choice in chunk.choices {
+            let index = choice.index as usize;
+            if index >= self.reasoning.len() {
+                anyhow::bail!("unexpected choice index {index}");
+            }
+
+            if let Some(text) = &choice.delta.reasoning_content {
+                self.reasoning[index].push_str(text);
+            }
+            if let Some(text) = &choice.delta.content {
+                self.content[index].push_str(text);
+            }
+            self.finish_reasons[index].extend(choice.finish_reason);
+            self.prompt_logprobs.extend(choice.prompt_logprobs);
+        }
+
+        Ok(())
+    }
+}
+
+/// run the CoT parser against the recorded transcripts, print the result of each fixture
+pub async fn selftest(parser: CotParser, strict: bool) -> anyhow::Result<()> {
+    let fixtures = match parser {
+        CotParser::Deepseek => DEEPSEEK_FIXTURES,
+        CotParser::MarkdownFence => MARKDOWN_FENCE_FIXTURES,
+    };
+
+    let mut failed = 0;
+    for fixture in fixtures {
+        let (expect, output) = run_fixture(parser, strict, fixture).await?;
+        if output == expect {
+            println!("{}: ok", fixture.name);
+
+            continue;
+        }
+
+        failed += 1;
+
+        println!(
+            "{}: failed, expect {expect:?}, got {output:?}",
+            fixture.name
+        );
+    }
+
+    if failed > 0 {
+        anyhow::bail!("cot selftest failed {failed}/{}", fixtures.len());
+    }
+
+    println!("cot selftest: ok {}/{}", fixtures.len(), fixtures.len());
+
+    Ok(())
+}
+
+/// build the chunks of the recorded deltas, see [`Fixture::deltas`]
+pub fn build_chunks(deltas: &[&str]) -> anyhow::Result<Vec<Chunk>> {
+    deltas
+        .iter()
+        .map(|delta| {
+            let mut chunk = json!({
+                "id": "selftest",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": "selftest",
+            });
+            match serde_json::from_str::<Value>(delta)? {
+                Value::Object(fields) if fields.contains_key("choices") => {
+                    chunk.as_object_mut().unwrap().extend(fields);
+                }
+                choices @ Value::Array(_) => chunk["choices"] = choices,
+                delta => chunk["choices"] = json!([{ "index": 0, "delta": delta }]),
+            }
+
+            Ok(serde_json::from_value::<Chunk>(chunk)?)
+        })
+        .collect()
+}
+
+/// return the expected and the parsed output, the parser must keep the finish reasons and the
+/// prompt logprobs
+async fn run_fixture(
+    parser: CotParser,
+    strict: bool,
+    fixture: &Fixture,
+) -> anyhow::Result<(Output, Output)> {
+    let chunks = build_chunks(fixture.deltas)?;
+
+    let mut expect = Output::new(fixture.reasoning.len());
+    for chunk in &chunks {
+        expect.prompt_logprobs.extend(chunk.prompt_logprobs.clone());
+        for choice in &chunk.choices {
+            expect
+                .prompt_logprobs
+                .extend(choice.prompt_logprobs.clone());
+            if let Some(finish_reasons) = expect.finish_reasons.get_mut(choice.index as usize) {
+                finish_reasons.extend(choice.finish_reason);
+            }
+        }
+    }
+    expect.reasoning = fixture.reasoning.iter().map(|s| s.to_string()).collect();
+    expect.content = fixture.content.iter().map(|s| s.to_string()).collect();
+
+    let st = stream::iter(chunks.into_iter().map(Ok));
+    let chunks = match parser {
+        CotParser::Deepseek => StreamAsyncIterAdapter(deepseek::extract_cot(st, strict)).boxed(),
+        CotParser::MarkdownFence => StreamAsyncIterAdapter(fence::extract_cot(
+            st,
+            strict,
+            MARKDOWN_FENCE_LABEL.to_string(),
+        ))
+        .boxed(),
+    };
+
+    let mut output = Output::new(fixture.reasoning.len());
+    let mut chunks = pin!(chunks);
+    while let Some(chunk) = chunks.try_next().await? {
+        output.push(chunk)?;
+    }
+
+    Ok((expect, output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pass_fixtures() {
+        selftest(CotParser::Deepseek, false).await.unwrap();
+        selftest(CotParser::MarkdownFence, false).await.unwrap();
+    }
+}
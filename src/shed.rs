@@ -0,0 +1,138 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+/// the weight of the newest latency in the rolling average
+const LATENCY_WEIGHT: f64 = 0.2;
+/// how long the average latency must stay above the threshold before shedding
+const SHED_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct LatencyState {
+    average: Option<Duration>,
+    over_since: Option<Instant>,
+    shedding: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShedStatus {
+    pub average_latency_ms: Option<u128>,
+    pub shedding: bool,
+}
+
+/// reject a fraction of the requests while the rolling average of the backend latency stays
+/// above the threshold, the left requests keep sampling the latency, so the shedding stops when it
+/// recovers
+#[derive(Debug)]
+pub struct LoadShedder {
+    threshold: Duration,
+    fraction: f64,
+    latency: Mutex<LatencyState>,
+    requests: AtomicU64,
+}
+
+impl LoadShedder {
+    pub fn new(threshold: Duration, fraction: f64) -> Self {
+        Self {
+            threshold,
+            fraction,
+            latency: Default::default(),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, latency: Duration) {
+        self.record_at(latency, Instant::now());
+    }
+
+    fn record_at(&self, latency: Duration, now: Instant) {
+        let mut state = self.latency.lock().unwrap();
+
+        let average = match state.average {
+            None => latency,
+            Some(average) => {
+                average.mul_f64(1.0 - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT)
+            }
+        };
+        state.average = Some(average);
+
+        if average > self.threshold {
+            state.over_since.get_or_insert(now);
+        } else {
+            state.over_since = None;
+        }
+
+        let shedding = state
+            .over_since
+            .is_some_and(|over_since| now.duration_since(over_since) >= SHED_WINDOW);
+        match (state.shedding, shedding) {
+            (false, true) => warn!(?average, threshold = ?self.threshold, "start load shedding"),
+            (true, false) => info!(?average, "stop load shedding"),
+            _ => {}
+        }
+        state.shedding = shedding;
+    }
+
+    /// return whether the request should be rejected, the rejected requests are spread evenly
+    pub fn should_shed(&self) -> bool {
+        if !self.latency.lock().unwrap().shedding {
+            return false;
+        }
+
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+
+        ((n + 1.0) * self.fraction).floor() > (n * self.fraction).floor()
+    }
+
+    pub fn status(&self) -> ShedStatus {
+        let state = self.latency.lock().unwrap();
+
+        ShedStatus {
+            average_latency_ms: state.average.map(|average| average.as_millis()),
+            shedding: state.shedding,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn shed_while_latency_stays_high() {
+        let shedder = LoadShedder::new(THRESHOLD, 0.25);
+        let start = Instant::now();
+        let slow = Duration::from_secs(1);
+
+        shedder.record_at(slow, start);
+        shedder.record_at(slow, start + SHED_WINDOW / 2);
+        assert!(!shedder.status().shedding);
+        assert!(!shedder.should_shed());
+
+        shedder.record_at(slow, start + SHED_WINDOW);
+        assert!(shedder.status().shedding);
+
+        // every fourth request is rejected
+        let shed = (0..8).map(|_| shedder.should_shed()).collect::<Vec<_>>();
+        assert_eq!(shed, [false, false, false, true, false, false, false, true]);
+
+        // the average drops below the threshold as the fast requests are sampled
+        let mut now = start + SHED_WINDOW;
+        while shedder.status().shedding {
+            now += Duration::from_secs(1);
+            shedder.record_at(Duration::from_millis(10), now);
+        }
+        assert!(shedder.status().average_latency_ms.unwrap() <= THRESHOLD.as_millis());
+        assert!((0..8).all(|_| !shedder.should_shed()));
+
+        // a new slow period waits for the whole window again
+        shedder.record_at(slow, now);
+        shedder.record_at(slow, now + Duration::from_secs(1));
+        assert!(!shedder.status().shedding);
+    }
+}
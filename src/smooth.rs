@@ -0,0 +1,133 @@
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tracing::debug;
+
+/// leaky bucket which paces requests to a steady rate, the burst is queued instead of rejected
+#[derive(Debug)]
+pub struct Smoother {
+    interval: Mutex<Interval>,
+    queue: Semaphore,
+    /// the priority requests are bounded by their own queue, so they can't starve the others
+    /// without limit
+    priority_queue: Semaphore,
+    /// the normal requests line up here before the interval, so at most one of them is ahead of
+    /// the priority requests
+    normal: Mutex<()>,
+}
+
+impl Smoother {
+    pub fn new(rate: NonZeroU32, queue_depth: usize) -> Self {
+        let mut interval = time::interval(Duration::from_secs(1) / rate.get());
+        // the requests after an idle period are paced too, instead of bursting to catch up
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            interval: Mutex::new(interval),
+            queue: Semaphore::new(queue_depth),
+            priority_queue: Semaphore::new(queue_depth),
+            normal: Mutex::new(()),
+        }
+    }
+
+    /// wait for the turn of the request, return `false` when the queue is full, the priority
+    /// request is served before the queued normal requests, it has its own queue of the same depth
+    pub async fn wait(&self, priority: bool) -> bool {
+        if priority {
+            let Ok(_permit) = self.priority_queue.try_acquire() else {
+                return false;
+            };
+
+            debug!("wait for priority smooth turn");
+
+            self.interval.lock().await.tick().await;
+
+            return true;
+        }
+
+        let Ok(_permit) = self.queue.try_acquire() else {
+            return false;
+        };
+
+        debug!("wait for smooth turn");
+
+        // the tokio mutex is fair, the queued requests are released in order
+        let _normal = self.normal.lock().await;
+        self.interval.lock().await.tick().await;
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use futures_util::future;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn pace_requests() {
+        let smoother = Smoother::new(NonZeroU32::new(20).unwrap(), 10);
+
+        let start = Instant::now();
+        let admitted = future::join_all((0..5).map(|_| smoother.wait(false))).await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(admitted, [true; 5]);
+        // the first request is served at once, the others are 50ms apart
+        assert!(elapsed >= Duration::from_millis(190), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn reject_when_queue_is_full() {
+        let smoother = Arc::new(Smoother::new(NonZeroU32::new(1).unwrap(), 1));
+        assert!(smoother.wait(false).await);
+
+        let queued = |priority| {
+            let smoother = smoother.clone();
+
+            tokio::spawn(async move { smoother.wait(priority).await })
+        };
+        let normal = queued(false);
+        let priority = queued(true);
+        time::sleep(Duration::from_millis(100)).await;
+
+        // each queue holds one request
+        assert!(!smoother.wait(false).await);
+        assert!(!smoother.wait(true).await);
+        assert!(priority.await.unwrap());
+        assert!(normal.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn admit_priority_first() {
+        let smoother = Arc::new(Smoother::new(NonZeroU32::new(20).unwrap(), 10));
+        assert!(smoother.wait(false).await);
+
+        let order = Arc::new(std::sync::Mutex::new(vec![]));
+        let queued = |name, priority| {
+            let smoother = smoother.clone();
+            let order = order.clone();
+
+            tokio::spawn(async move {
+                assert!(smoother.wait(priority).await);
+                order.lock().unwrap().push(name);
+            })
+        };
+        let mut tasks = vec![queued("normal 1", false), queued("normal 2", false)];
+        time::sleep(Duration::from_millis(10)).await;
+        tasks.push(queued("priority", true));
+
+        future::try_join_all(tasks).await.unwrap();
+
+        // at most one normal request is ahead of the priority request
+        let order = order.lock().unwrap();
+        assert_eq!(*order, ["normal 1", "priority", "normal 2"]);
+    }
+}
@@ -1,19 +1,36 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
 use std::future::ready;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures_util::{Stream, TryStreamExt};
-use reqwest::{Client, Method, Request, RequestBuilder, Url};
-use reqwest_eventsource::{Event, EventSource};
+use axum::body::Bytes;
+use axum::response::sse::Event;
+use eventsource_stream::Eventsource;
+use futures_util::{FutureExt, Stream, StreamExt, TryStreamExt, select, stream};
+use reqwest::header::{self, HeaderMap};
+use reqwest::{Client, Method, Request, RequestBuilder, Response, StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::Notify;
+use tokio::time;
+use tracing::{debug, warn};
 
-const END_SSE_DATA: &str = "[DONE]";
+pub const END_SSE_DATA: &str = "[DONE]";
+const REASONING_CONTENT_FIELD: &str = "reasoning_content";
+const KEEPALIVE_COMMENT: &[u8] = b": keep-alive\n\n";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Delta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub reasoning_content: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Value>,
 }
 
 #[derive(Debug, Clone, Copy, Ord, PartialOrd, PartialEq, Eq, Serialize, Deserialize)]
@@ -34,8 +51,12 @@ pub struct Choice {
     pub logprobs: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub finish_reason: Option<FinishReason>,
+    /// vLLM sets the matched stop string or stop token id along with `finish_reason`
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub stop_reason: Option<String>,
+    pub stop_reason: Option<Value>,
+    /// vLLM sends the prompt logprobs in the first chunk when requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,31 +65,324 @@ pub struct Chunk {
     pub object: String,
     pub created: u32,
     pub model: String,
+    #[serde(default)]
     pub choices: Vec<Choice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_logprobs: Option<Value>,
+
+    /// backend specific fields, e.g. the diagnostics of a chunk without choice
+    #[serde(flatten)]
+    pub other_fields: HashMap<String, Value>,
+}
+
+/// the upstream answered the stream request with an error status or a non SSE body, the response
+/// is kept to forward it as is
+#[derive(Debug)]
+pub struct UpstreamRejected(pub Response);
+
+impl Display for UpstreamRejected {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "upstream rejected stream request, status {}",
+            self.0.status()
+        )
+    }
 }
 
+impl std::error::Error for UpstreamRejected {}
+
 pub async fn send_stream_request<T: Serialize>(
     client: Client,
     url: Url,
+    headers: HeaderMap,
     body: T,
+    reasoning_field: Option<String>,
+    lenient: bool,
+    upstream_keepalive: Option<Arc<Notify>>,
 ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Chunk>> + use<T>> {
     let request = Request::new(Method::POST, url);
-    let builder = RequestBuilder::from_parts(client, request)
-        .header("Content-Type", "application/json")
-        .json(&body);
+    let response = RequestBuilder::from_parts(client, request)
+        .headers(headers)
+        .header(header::ACCEPT, "text/event-stream")
+        .header(header::CONTENT_TYPE, "application/json")
+        .json(&body)
+        .send()
+        .await?;
 
-    let event_source = EventSource::new(builder)?;
+    // the rejected request is not turned into a broken stream
+    if response.status() != StatusCode::OK || !is_event_stream(response.headers()) {
+        return Err(UpstreamRejected(response).into());
+    }
 
-    let stream = event_source
-        .try_filter_map(|event| {
-            ready(match event {
-                Event::Open => Ok(None),
-                Event::Message(event) => Ok(Some(event)),
-            })
-        })
-        .try_take_while(|event| ready(Ok(event.data != END_SSE_DATA)))
+    // the SSE parser drops the comments, so they are found in the raw bytes
+    let mut line_start = true;
+    let bytes = response.bytes_stream().inspect_ok(move |data| {
+        if let Some(upstream_keepalive) = &upstream_keepalive
+            && has_comment(&mut line_start, data)
+        {
+            upstream_keepalive.notify_one();
+        }
+    });
+
+    let stream = bytes
+        .eventsource()
         .map_err(anyhow::Error::from)
-        .and_then(async |event| Ok(serde_json::from_str::<Chunk>(&event.data)?));
+        .chain(stream::once(ready(Err(anyhow::anyhow!(
+            "stream ended without {END_SSE_DATA}"
+        )))))
+        .try_take_while(|event| ready(Ok(event.data != END_SSE_DATA)))
+        .try_filter_map(move |event| {
+            ready(match parse_chunk(&event.data, reasoning_field.as_deref()) {
+                Err(err) if lenient => {
+                    match parse_chunk(&repair_json(&event.data), reasoning_field.as_deref()) {
+                        Err(_) => {
+                            warn!(%err, data = event.data, "skip malformed chunk");
+
+                            Ok(None)
+                        }
+
+                        Ok(chunk) => {
+                            debug!(data = event.data, "repaired malformed chunk");
+
+                            Ok(Some(chunk))
+                        }
+                    }
+                }
+
+                res => res.map(Some),
+            })
+        });
 
     Ok(stream)
 }
+
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+}
+
+/// check whether the data has a comment line, `line_start` tracks whether the next data starts a
+/// new line
+fn has_comment(line_start: &mut bool, data: &[u8]) -> bool {
+    let mut found = false;
+    for &b in data {
+        found |= *line_start && b == b':';
+        *line_start = b == b'\n' || b == b'\r';
+    }
+
+    found
+}
+
+/// send a keep-alive comment to the client when the upstream sends one
+pub async gen fn forward_keepalive<S: Stream<Item = anyhow::Result<Event>>>(
+    st: S,
+    upstream_keepalive: Arc<Notify>,
+) -> anyhow::Result<Event> {
+    let mut st = pin!(st);
+    loop {
+        let event = select! {
+            event = st.next().fuse() => event,
+            _ = upstream_keepalive.notified().fuse() => {
+                yield Ok(Event::default().comment("keep-alive"));
+
+                continue;
+            }
+        };
+        let Some(event) = event else {
+            return;
+        };
+
+        yield event;
+    }
+}
+
+/// drop the trailing commas before `}` and `]`, the most common malformation of flaky backends
+fn repair_json(data: &str) -> String {
+    let mut repaired = String::with_capacity(data.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = data.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+        } else if c == ',' {
+            let mut rest = chars.clone().skip_while(|c| c.is_whitespace());
+            if matches!(rest.next(), Some('}' | ']')) {
+                continue;
+            }
+        }
+
+        repaired.push(c);
+    }
+
+    repaired
+}
+
+/// parse sse data as [`Chunk`], renaming the backend custom reasoning field to
+/// `reasoning_content` before deserializing
+fn parse_chunk(data: &str, reasoning_field: Option<&str>) -> anyhow::Result<Chunk> {
+    let reasoning_field = match reasoning_field {
+        None | Some(REASONING_CONTENT_FIELD) => return Ok(serde_json::from_str(data)?),
+        Some(reasoning_field) => reasoning_field,
+    };
+
+    let mut chunk = serde_json::from_str::<Value>(data)?;
+    if let Some(choices) = chunk.get_mut("choices").and_then(Value::as_array_mut) {
+        for choice in choices {
+            if let Some(delta) = choice.get_mut("delta").and_then(Value::as_object_mut)
+                && let Some(reasoning) = delta.remove(reasoning_field)
+            {
+                delta.insert(REASONING_CONTENT_FIELD.to_string(), reasoning);
+            }
+        }
+    }
+
+    Ok(serde_json::from_value(chunk)?)
+}
+
+/// forward the upstream SSE bytes as is, optionally prepend the initial comments, and send
+/// keep-alive comments only at event boundaries when upstream is silent
+pub async gen fn passthrough<S: Stream<Item = reqwest::Result<Bytes>>>(
+    st: S,
+    initial_comments: Vec<String>,
+    keepalive: Option<Duration>,
+) -> reqwest::Result<Bytes> {
+    for comment in initial_comments {
+        yield Ok(Bytes::from(format!(": {comment}\n\n")));
+    }
+
+    let mut st = pin!(st);
+    let mut at_boundary = true;
+    loop {
+        let data = match keepalive {
+            None => st.next().await,
+
+            Some(interval) => match time::timeout(interval, st.next()).await {
+                Err(_) => {
+                    if at_boundary {
+                        yield Ok(Bytes::from_static(KEEPALIVE_COMMENT));
+                    }
+
+                    continue;
+                }
+
+                Ok(data) => data,
+            },
+        };
+
+        let Some(data) = data else {
+            return;
+        };
+
+        if let Ok(data) = &data
+            && !data.is_empty()
+        {
+            at_boundary = data.ends_with(b"\n\n") || data.ends_with(b"\r\n\r\n");
+        }
+
+        yield data;
+    }
+}
+
+/// end the stream cleanly instead of the error once `min_text_chunks` chunks with text were sent,
+/// errors before that are still returned
+pub async gen fn end_on_late_error<S: Stream<Item = anyhow::Result<Chunk>>>(
+    st: S,
+    min_text_chunks: usize,
+) -> anyhow::Result<Chunk> {
+    let mut text_chunks = 0;
+
+    let mut st = pin!(st);
+    while let Some(chunk) = st.next().await {
+        let chunk = match chunk {
+            Err(err) if text_chunks >= min_text_chunks => {
+                warn!(%err, text_chunks, "stream error after text sent, end the stream");
+                return;
+            }
+
+            Err(err) => {
+                yield Err(err);
+                return;
+            }
+
+            Ok(chunk) => chunk,
+        };
+
+        if chunk.choices.iter().any(|choice| {
+            choice
+                .delta
+                .content
+                .as_ref()
+                .or(choice.delta.reasoning_content.as_ref())
+                .is_some_and(|text| !text.is_empty())
+        }) {
+            text_chunks += 1;
+        }
+
+        yield Ok(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+
+    use super::*;
+    use crate::adapter::StreamAsyncIterAdapter;
+    use crate::selftest::build_chunks;
+
+    #[tokio::test]
+    async fn end_cleanly_on_late_error() {
+        let chunks = || {
+            build_chunks(&[r#"{"role":"assistant"}"#, r#"{"content":"answer"}"#])
+                .unwrap()
+                .into_iter()
+                .map(Ok)
+        };
+
+        // the error after the text chunk ends the stream
+        let st = stream::iter(chunks().chain([Err(anyhow::anyhow!("broken"))]));
+        let results = StreamAsyncIterAdapter(end_on_late_error(st, 1))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(results.len(), 2);
+        assert!(results.iter().all(Result::is_ok));
+
+        // the error before the text chunk is returned
+        let st = stream::iter(
+            chunks()
+                .take(1)
+                .chain([Err(anyhow::anyhow!("broken"))])
+                .chain(chunks()),
+        );
+        let results = StreamAsyncIterAdapter(end_on_late_error(st, 1))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[1].as_ref().unwrap_err().to_string(), "broken");
+
+        // the threshold is not reached
+        let st = stream::iter(chunks().chain([Err(anyhow::anyhow!("broken"))]));
+        let results = StreamAsyncIterAdapter(end_on_late_error(st, 2))
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+    }
+}
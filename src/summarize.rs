@@ -0,0 +1,146 @@
+use anyhow::Context;
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Client, Url};
+use serde_json::{Value, json};
+use tracing::{info, warn};
+
+const SUMMARIZE_PROMPT: &str = "Summarize the following reasoning concisely. Keep the key steps \
+                                and the conclusion, reply with the summary only.";
+
+/// replace the long `reasoning_content` of the non streaming chat response with a summary made
+/// by an OpenAI compatible chat endpoint
+#[derive(Debug)]
+pub struct Summarizer {
+    endpoint: Url,
+    model: String,
+    min_chars: usize,
+    credential: Credential,
+}
+
+/// the `Authorization` of the summarize request
+#[derive(Debug)]
+enum Credential {
+    /// the endpoint is the backend, reuse the client `Authorization`
+    Client,
+    ApiKey(HeaderValue),
+    None,
+}
+
+impl Summarizer {
+    /// the client `Authorization` is only sent to the endpoint on the backend origin, the other
+    /// endpoints use the `api_key`
+    pub fn new(
+        endpoint: Url,
+        backend: &Url,
+        api_key: Option<&str>,
+        model: String,
+        min_chars: usize,
+    ) -> anyhow::Result<Self> {
+        let credential = match api_key {
+            Some(api_key) => Credential::ApiKey(
+                format!("Bearer {api_key}")
+                    .parse()
+                    .context("invalid summarize api key")?,
+            ),
+            None if endpoint.origin() == backend.origin() => Credential::Client,
+            None => Credential::None,
+        };
+
+        Ok(Self {
+            endpoint,
+            model,
+            min_chars,
+            credential,
+        })
+    }
+
+    pub fn endpoint(&self) -> &Url {
+        &self.endpoint
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn min_chars(&self) -> usize {
+        self.min_chars
+    }
+
+    /// summarize the reasoning of each choice, the full reasoning is kept when the summarization
+    /// fails
+    pub async fn summarize_response(
+        &self,
+        client: &Client,
+        authorization: Option<&HeaderValue>,
+        response: &mut Value,
+    ) {
+        let Some(choices) = response.get_mut("choices").and_then(Value::as_array_mut) else {
+            return;
+        };
+
+        for choice in choices {
+            let Some(Value::String(reasoning)) = choice.pointer_mut("/message/reasoning_content")
+            else {
+                continue;
+            };
+
+            let chars = reasoning.chars().count();
+            if chars < self.min_chars {
+                continue;
+            }
+
+            match self.summarize(client, authorization, reasoning).await {
+                Err(err) => warn!(%err, "summarize reasoning failed, keep the full reasoning"),
+
+                Ok(summary) => {
+                    info!(
+                        chars,
+                        summary_chars = summary.chars().count(),
+                        "summarize reasoning"
+                    );
+
+                    *reasoning = summary;
+                }
+            }
+        }
+    }
+
+    async fn summarize(
+        &self,
+        client: &Client,
+        authorization: Option<&HeaderValue>,
+        reasoning: &str,
+    ) -> anyhow::Result<String> {
+        let mut request = client.post(self.endpoint.clone()).json(&json!({
+            "model": self.model,
+            "messages": [
+                { "role": "system", "content": SUMMARIZE_PROMPT },
+                { "role": "user", "content": reasoning },
+            ],
+        }));
+        let authorization = match &self.credential {
+            Credential::Client => authorization,
+            Credential::ApiKey(api_key) => Some(api_key),
+            Credential::None => None,
+        };
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("request summarize endpoint {} failed", self.endpoint))?
+            .error_for_status()?
+            .json::<Value>()
+            .await
+            .context("decode summarize response failed")?;
+
+        response
+            .pointer("/choices/0/message/content")
+            .and_then(Value::as_str)
+            .filter(|summary| !summary.is_empty())
+            .map(str::to_string)
+            .context("summarize response has no content")
+    }
+}
@@ -0,0 +1,205 @@
+//! in-process tests of the handlers, the proxy is built from the command line args and talks to a
+//! mocked backend on a random local port
+
+mod proxy;
+mod request;
+mod streaming;
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use axum::body::{self, Body, Bytes};
+use axum::extract::ConnectInfo;
+use axum::http::{HeaderMap, Request, header};
+use axum::response::IntoResponse;
+use axum::response::Response;
+use axum::{Json, Router};
+use clap::Parser;
+use futures_util::stream;
+use reqwest::Url;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+use crate::cli::Cli;
+use crate::listener::PeerAddr;
+use crate::{build_app, build_client};
+
+/// the requests received by the mocked backend
+#[derive(Debug, Clone, Default)]
+struct Captured(Arc<Mutex<Vec<(HeaderMap, Value)>>>);
+
+impl Captured {
+    fn push(&self, headers: HeaderMap, body: Value) {
+        self.0.lock().unwrap().push((headers, body));
+    }
+
+    fn bodies(&self) -> Vec<Value> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, body)| body.clone())
+            .collect()
+    }
+
+    fn last(&self) -> (HeaderMap, Value) {
+        self.0.lock().unwrap().last().cloned().expect("no request")
+    }
+}
+
+/// serve the mocked backend, return its url
+async fn spawn_backend(router: Router) -> Url {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    format!("http://{addr}").parse().unwrap()
+}
+
+/// a backend answering every completion with `content` and capturing the requests
+async fn spawn_chat_backend(content: &'static str) -> (Url, Captured) {
+    let captured = Captured::default();
+    let handler = {
+        let captured = captured.clone();
+
+        move |headers: HeaderMap, Json(body): Json<Value>| async move {
+            captured.push(headers, body);
+
+            Json(completion(content))
+        }
+    };
+    let router = Router::new()
+        .route("/v1/chat/completions", axum::routing::post(handler.clone()))
+        .route("/v1/completions", axum::routing::post(handler));
+
+    (spawn_backend(router).await, captured)
+}
+
+/// a backend answering every completion with the SSE body sent in the `body` pieces and
+/// capturing the requests
+async fn spawn_sse_backend(body: Vec<String>) -> (Url, Captured) {
+    let captured = Captured::default();
+    let handler = {
+        let captured = captured.clone();
+
+        move |headers: HeaderMap, Json(request): Json<Value>| async move {
+            captured.push(headers, request);
+
+            let pieces = body
+                .into_iter()
+                .map(|piece| Ok::<_, std::io::Error>(Bytes::from(piece)));
+
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(stream::iter(pieces)),
+            )
+                .into_response()
+        }
+    };
+    let router = Router::new()
+        .route("/v1/chat/completions", axum::routing::post(handler.clone()))
+        .route("/v1/completions", axum::routing::post(handler));
+
+    (spawn_backend(router).await, captured)
+}
+
+/// the stream chunk of the first choice
+fn chunk(delta: Value, finish_reason: Option<&str>) -> Value {
+    json!({
+        "id": "test",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "gpt-4o",
+        "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+    })
+}
+
+/// the SSE events of the chunks, ended with `[DONE]`
+fn sse_events(chunks: &[Value]) -> Vec<String> {
+    chunks
+        .iter()
+        .map(|chunk| format!("data: {chunk}\n\n"))
+        .chain(["data: [DONE]\n\n".to_string()])
+        .collect()
+}
+
+/// the JSON data of the SSE response, the comments and `[DONE]` are skipped
+fn sse_data(text: &str) -> Vec<Value> {
+    text.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter(|data| *data != "[DONE]")
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect()
+}
+
+/// the non streaming chat completion of `content`
+fn completion(content: &str) -> Value {
+    json!({
+        "id": "test",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4o",
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": content},
+            "finish_reason": "stop",
+        }],
+    })
+}
+
+/// build the proxy of `backend` with the extra command line args
+fn app(backend: &Url, args: &[&str]) -> Router {
+    let cli = Cli::try_parse_from(
+        [
+            "openai_enhance",
+            "--listen",
+            "127.0.0.1:0",
+            "--backend",
+            backend.as_str(),
+        ]
+        .iter()
+        .chain(args),
+    )
+    .unwrap();
+
+    let client = build_client(&cli).unwrap();
+
+    build_app(cli, backend.clone(), client).unwrap()
+}
+
+/// send the request to the proxy from a loopback client
+async fn send(app: Router, mut request: Request<Body>) -> Response {
+    request
+        .extensions_mut()
+        .insert(ConnectInfo(PeerAddr(SocketAddr::from((
+            Ipv4Addr::LOCALHOST,
+            0,
+        )))));
+
+    app.oneshot(request).await.unwrap()
+}
+
+fn post_json(path: &str, body: &Value) -> Request<Body> {
+    Request::post(path)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+async fn body_text(response: Response) -> String {
+    let data = body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    String::from_utf8(data.to_vec()).unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let data = body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    serde_json::from_slice(&data).unwrap()
+}
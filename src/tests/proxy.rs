@@ -0,0 +1,492 @@
+use std::time::Duration;
+
+use axum::http::{Method, StatusCode};
+use axum::response::Redirect;
+use axum::routing::{get, post};
+use futures_util::StreamExt;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time;
+
+use super::*;
+use crate::listener::ClientListener;
+use crate::{check, serve};
+
+fn get_request(path: &str) -> Request<Body> {
+    Request::get(path)
+        .header(header::AUTHORIZATION, "Bearer sk-test")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn follow_redirects_policy() {
+    let authorizations = Arc::new(Mutex::new(vec![]));
+    let other = spawn_backend(Router::new().route(
+        "/v1/models",
+        get({
+            let authorizations = authorizations.clone();
+
+            move |headers: HeaderMap| async move {
+                authorizations
+                    .lock()
+                    .unwrap()
+                    .push(headers.get(header::AUTHORIZATION).cloned());
+
+                "other host"
+            }
+        }),
+    ))
+    .await;
+    let other_models = other.join("/v1/models").unwrap().to_string();
+    let backend = spawn_backend(
+        Router::new()
+            .route("/old", get(|| async { Redirect::temporary("/v1/models") }))
+            .route(
+                "/v1/models",
+                get(move || async move { Redirect::temporary(&other_models) }),
+            ),
+    )
+    .await;
+
+    // the same host redirect is followed, the other host redirect is returned
+    let response = send(app(&backend, &[]), get_request("/old")).await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert!(authorizations.lock().unwrap().is_empty());
+
+    let response = send(
+        app(&backend, &["--follow-redirects", "none"]),
+        get_request("/old"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    assert_eq!(response.headers()[header::LOCATION], "/v1/models");
+
+    // the credential is not sent to the other host
+    let response = send(
+        app(&backend, &["--follow-redirects", "limited"]),
+        get_request("/old"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_text(response).await, "other host");
+    assert_eq!(*authorizations.lock().unwrap(), [None]);
+}
+
+#[tokio::test]
+async fn route_through_outbound_proxy() {
+    // the mocked proxy answers the absolute-form requests of the plain http backend itself
+    let (outbound_proxy, captured) = spawn_chat_backend("proxied").await;
+    let backend = "http://backend.invalid".parse::<Url>().unwrap();
+    let proxied = app(&backend, &["--outbound-proxy", outbound_proxy.as_str()]);
+
+    let request = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]});
+    let response = send(proxied, post_json("/v1/chat/completions", &request)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_json(response).await["choices"][0]["message"]["content"],
+        "proxied"
+    );
+    assert_eq!(captured.last().0[header::HOST], "backend.invalid");
+
+    // the excepted backend is reached directly
+    let (backend, direct) = spawn_chat_backend("direct").await;
+    let app = app(
+        &backend,
+        &[
+            "--outbound-proxy",
+            outbound_proxy.as_str(),
+            "--outbound-no-proxy",
+            "127.0.0.1",
+        ],
+    );
+    let response = send(app, post_json("/v1/chat/completions", &request)).await;
+    assert_eq!(
+        body_json(response).await["choices"][0]["message"]["content"],
+        "direct"
+    );
+    assert_eq!(captured.bodies().len(), 1);
+    assert_eq!(direct.bodies().len(), 1);
+
+    // the unsupported scheme is refused at startup
+    let cli = Cli::try_parse_from([
+        "openai_enhance",
+        "--listen",
+        "127.0.0.1:0",
+        "--backend",
+        backend.as_str(),
+        "--outbound-proxy",
+        "ftp://127.0.0.1:21",
+    ])
+    .unwrap();
+    build_client(&cli).unwrap_err();
+}
+
+#[tokio::test]
+async fn idle_timeout_keeps_streams() {
+    let backend = spawn_backend(Router::new().route(
+        "/v1/events",
+        get(|| async {
+            let events = stream::iter(["data: 1\n\n", "data: 2\n\n"])
+                .enumerate()
+                .then(|(i, event)| async move {
+                    if i > 0 {
+                        time::sleep(Duration::from_millis(2500)).await;
+                    }
+
+                    Ok::<_, std::io::Error>(event)
+                });
+
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(events),
+            )
+        }),
+    ))
+    .await;
+    let app = app(&backend, &["--client-idle-timeout", "1"]);
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let listener = ClientListener::new(listener, None, Some(Duration::from_secs(1)));
+    tokio::spawn(serve(app, vec![listener]));
+
+    // the silent stream is kept alive by the keep-alive comments
+    let text = reqwest::get(format!("http://{addr}/v1/events"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(text.starts_with("data: 1\n\n"), "{text:?}");
+    assert!(text.contains(": keep-alive\n\n"), "{text:?}");
+    assert!(text.ends_with("data: 2\n\n"), "{text:?}");
+
+    // the idle connection without request is closed
+    let mut idle = TcpStream::connect(addr).await.unwrap();
+    let read = time::timeout(Duration::from_secs(3), idle.read(&mut [0; 1]))
+        .await
+        .expect("idle connection is not closed");
+    assert!(read.is_err() || read.unwrap() == 0);
+}
+
+#[tokio::test]
+async fn serve_on_two_ports() {
+    let backend =
+        spawn_backend(Router::new().route("/v1/models", get(|| async { "models" }))).await;
+    let app = app(&backend, &[]);
+
+    let mut listeners = vec![];
+    let mut addrs = vec![];
+    for _ in 0..2 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        addrs.push(listener.local_addr().unwrap());
+        listeners.push(ClientListener::new(listener, None, None));
+    }
+    tokio::spawn(serve(app, listeners));
+
+    assert_ne!(addrs[0].port(), addrs[1].port());
+    for addr in addrs {
+        let text = reqwest::get(format!("http://{addr}/v1/models"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(text, "models");
+    }
+}
+
+#[tokio::test]
+async fn filter_response_headers() {
+    let backend = spawn_backend(Router::new().route(
+        "/v1/models",
+        get(|| async {
+            (
+                [
+                    ("set-cookie", "session=1"),
+                    ("x-internal-route", "node-1"),
+                    ("x-request-cost", "1"),
+                    ("connection", "keep-alive"),
+                ],
+                "models",
+            )
+        }),
+    ))
+    .await;
+
+    let response = send(
+        app(
+            &backend,
+            &["--strip-response-header", "set-cookie,x-internal-route"],
+        ),
+        get_request("/v1/models"),
+    )
+    .await;
+    let headers = response.headers();
+    assert!(!headers.contains_key("set-cookie"));
+    assert!(!headers.contains_key("x-internal-route"));
+    assert!(!headers.contains_key("connection"));
+    assert_eq!(headers["x-request-cost"], "1");
+
+    let response = send(
+        app(&backend, &["--allow-response-header", "x-request-cost"]),
+        get_request("/v1/models"),
+    )
+    .await;
+    let headers = response.headers();
+    assert!(!headers.contains_key("set-cookie"));
+    assert!(!headers.contains_key("x-internal-route"));
+    assert_eq!(headers["x-request-cost"], "1");
+    assert!(headers.contains_key(header::CONTENT_TYPE));
+    assert_eq!(body_text(response).await, "models");
+}
+
+#[tokio::test]
+async fn forward_bodyless_methods() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let handler = {
+        let received = received.clone();
+
+        move |method: Method, headers: HeaderMap, body: Bytes| async move {
+            received.lock().unwrap().push((
+                method.clone(),
+                headers.contains_key(header::CONTENT_LENGTH)
+                    || headers.contains_key(header::TRANSFER_ENCODING),
+                body.len(),
+            ));
+
+            Json(json!({"id": "file-1", "method": method.as_str()}))
+        }
+    };
+    let backend =
+        spawn_backend(Router::new().route("/v1/files/{id}", get(handler.clone()).delete(handler)))
+            .await;
+    let proxied = app(&backend, &[]);
+
+    let response = send(proxied.clone(), get_request("/v1/files/file-1")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["method"], "GET");
+
+    let response = send(
+        proxied,
+        Request::delete("/v1/files/file-1")
+            .header(header::AUTHORIZATION, "Bearer sk-test")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["method"], "DELETE");
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        [(Method::GET, false, 0), (Method::DELETE, false, 0)]
+    );
+}
+
+#[tokio::test]
+async fn keep_backend_path_prefix() {
+    let (backend, captured) = spawn_chat_backend("ok").await;
+    let upstream = Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(move |Json(body): Json<Value>| {
+            let backend = backend.clone();
+
+            // forward to the chat backend, so the request is captured
+            async move {
+                let response = reqwest::Client::new()
+                    .post(backend.join("/v1/chat/completions").unwrap())
+                    .json(&body)
+                    .send()
+                    .await
+                    .unwrap();
+
+                Json(response.json::<Value>().await.unwrap())
+            }
+        }),
+    );
+    let prefixed = spawn_backend(Router::new().nest(
+        "/openai",
+        upstream.route("/v1/models", get(|| async { Json(json!({"data": []})) })),
+    ))
+    .await
+    .join("/openai")
+    .unwrap();
+
+    let proxied = app(&prefixed, &[]);
+    let response = send(
+        proxied.clone(),
+        post_json(
+            "/v1/chat/completions",
+            &json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]}),
+        ),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(captured.bodies().len(), 1);
+
+    let response = send(proxied, get_request("/v1/models")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let client = reqwest::Client::new();
+    check::check(&prefixed, &client, None).await.unwrap();
+    let (url, status) = check::reachable(&prefixed, &client, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(url.path(), "/openai/v1/models");
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn check_unreachable_backend() {
+    // nothing listens on the port after the listener is dropped
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let backend = format!("http://{}/openai/", listener.local_addr().unwrap())
+        .parse::<Url>()
+        .unwrap();
+    drop(listener);
+
+    let client = reqwest::Client::new();
+    let err = check::check(&backend, &client, None).await.unwrap_err();
+    assert!(err.to_string().contains("/openai/v1/models"), "{err}");
+
+    let err = check::probe(&backend, &client).await.unwrap_err();
+    assert!(err.to_string().contains("is unreachable"), "{err}");
+}
+
+#[tokio::test]
+async fn probe_readiness() {
+    let backend =
+        spawn_backend(Router::new().route("/ready", get(|| async { "backend ready" }))).await;
+
+    // the backend path is forwarded when the probe is not enabled
+    let response = send(app(&backend, &[]), get_request("/ready")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_text(response).await, "backend ready");
+
+    let response = send(
+        app(&backend, &["--ready-path", "/ready"]),
+        get_request("/ready"),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let status = body_json(response).await;
+    assert_eq!(status["ready"], true, "{status}");
+    for subsystem in ["backend", "tokenizer", "config"] {
+        assert_eq!(status["subsystems"][subsystem]["ok"], true, "{status}");
+    }
+
+    // nothing listens on the port after the listener is dropped
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+    let unreachable = format!("http://{}", listener.local_addr().unwrap())
+        .parse::<Url>()
+        .unwrap();
+    drop(listener);
+
+    for (backend, args, failed) in [
+        (&unreachable, &[][..], "backend"),
+        (
+            &backend,
+            &[
+                "--context-window",
+                "100",
+                "--context-min-output-token",
+                "100",
+            ][..],
+            "config",
+        ),
+        (
+            &backend,
+            &["--fallback-model", "gpt-4o=gpt-4o"][..],
+            "config",
+        ),
+    ] {
+        let mut args = args.to_vec();
+        args.extend(["--ready-path", "/healthz/ready"]);
+        let response = send(app(backend, &args), get_request("/healthz/ready")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let status = body_json(response).await;
+        assert_eq!(status["ready"], false, "{status}");
+        assert_eq!(status["subsystems"][failed]["ok"], false, "{status}");
+        assert!(
+            status["subsystems"][failed]["error"].is_string(),
+            "{status}"
+        );
+        assert_eq!(status["subsystems"]["tokenizer"]["ok"], true, "{status}");
+    }
+
+    assert!(Cli::try_parse_from(["openai_enhance", "--ready-path", "/v1/ready"]).is_err());
+}
+
+#[tokio::test]
+async fn restrict_proxy_paths() {
+    let backend = spawn_backend(
+        Router::new()
+            .route("/v1/models", get(|| async { "models" }))
+            .route("/v1/modelsX", get(|| async { "modelsX" }))
+            .route("/internal", get(|| async { "internal" })),
+    )
+    .await;
+    let router = app(&backend, &["--allowed-proxy-path", "/v1/models"]);
+
+    let response = send(router.clone(), get_request("/v1/models")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_text(response).await, "models");
+
+    // the prefix matches whole segments, the dot segments can't leave the prefix
+    for path in [
+        "/v1/modelsX",
+        "/v1/models/../../internal",
+        "/v1/models/%2e%2e/%2E%2e/internal",
+        "/v1/models/.%2e/..%2finternal",
+    ] {
+        let response = send(router.clone(), get_request(path)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn downgrade_proxy_stream() {
+    let captured = Captured::default();
+    let backend = spawn_backend(Router::new().route(
+        "/v1/batch",
+        post({
+            let captured = captured.clone();
+
+            // the backend may not accept the media type case-insensitively
+            move |headers: HeaderMap, body: Bytes| async move {
+                captured.push(headers, serde_json::from_slice(&body).unwrap());
+
+                "ok"
+            }
+        }),
+    ))
+    .await;
+    let router = app(&backend, &["--downgrade-proxy-stream"]);
+
+    let request = |content_type: &str, body: String| {
+        Request::post("/v1/batch")
+            .header(header::AUTHORIZATION, "Bearer sk-test")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    // the media type is case-insensitive
+    let body = json!({"stream": true, "stream_options": {"include_usage": true}}).to_string();
+    let response = send(
+        router.clone(),
+        request("Application/JSON; charset=utf-8", body),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(captured.last().1, json!({"stream": false}));
+
+    // the buffered body is limited like the other JSON bodies
+    let body = json!({"stream": true, "input": "a".repeat(4 * 1024 * 1024)}).to_string();
+    let response = send(router, request("application/json", body)).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(captured.bodies().len(), 1);
+}
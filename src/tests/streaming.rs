@@ -0,0 +1,673 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use futures_util::StreamExt;
+use serde_json::{Value, json};
+
+use super::*;
+
+/// the reasoning and content texts of the first choice
+fn texts(chunks: &[Value]) -> (String, String) {
+    let mut reasoning = String::new();
+    let mut content = String::new();
+    for chunk in chunks {
+        let delta = &chunk["choices"][0]["delta"];
+        reasoning.push_str(delta["reasoning_content"].as_str().unwrap_or_default());
+        content.push_str(delta["content"].as_str().unwrap_or_default());
+    }
+
+    (reasoning, content)
+}
+
+fn chat_stream() -> Request<Body> {
+    post_json(
+        "/v1/chat/completions",
+        &json!({
+            "model": "gpt-4o",
+            "messages": [{"role": "user", "content": "hi"}],
+            "stream": true,
+        }),
+    )
+}
+
+#[tokio::test]
+async fn rename_backend_reasoning_field() {
+    let (backend, _) = spawn_sse_backend(sse_events(&[
+        chunk(json!({"role": "assistant", "reasoning": "think "}), None),
+        chunk(json!({"reasoning": "hard"}), None),
+        chunk(json!({"content": "answer"}), Some("stop")),
+    ]))
+    .await;
+    let app = app(
+        &backend,
+        &["--cot-parser", "deepseek", "--reasoning-field", "reasoning"],
+    );
+
+    let response = send(app, chat_stream()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let chunks = sse_data(&body_text(response).await);
+    assert_eq!(
+        texts(&chunks),
+        ("think hard".to_string(), "answer".to_string())
+    );
+    assert!(
+        chunks
+            .iter()
+            .all(|chunk| chunk["choices"][0]["delta"].get("reasoning").is_none())
+    );
+}
+
+#[tokio::test]
+async fn initial_comment_before_data() {
+    let (backend, _) = spawn_sse_backend(sse_events(&[chunk(
+        json!({"content": "answer"}),
+        Some("stop"),
+    )]))
+    .await;
+
+    // both the CoT parsed and the passthrough streams
+    for args in [
+        &["--sse-initial-comment", "--cot-parser", "deepseek"][..],
+        &["--sse-initial-comment"],
+    ] {
+        let response = send(app(&backend, args), chat_stream()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let text = body_text(response).await;
+        assert!(text.starts_with(": connected\n\n"), "{text:?}");
+        assert_eq!(text.matches(": connected").count(), 1);
+        assert_eq!(texts(&sse_data(&text)).1, "answer");
+    }
+}
+
+#[tokio::test]
+async fn passthrough_upstream_bytes() {
+    // the comments, the extra fields and the odd framing are kept
+    let upstream = vec![
+        ": upstream comment\n\n".to_string(),
+        "data: {\"id\":\"x\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"a\"},\"extra\":1}]}\r\n".to_string(),
+        "\r\nevent: custom\ndata: {\"b\":".to_string(),
+        "2}\n\n".to_string(),
+        "data: [DONE]\n\n".to_string(),
+    ];
+    let (backend, _) = spawn_sse_backend(upstream.clone()).await;
+
+    let response = send(app(&backend, &[]), chat_stream()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_text(response).await, upstream.concat());
+}
+
+#[tokio::test]
+async fn request_timeout_exempts_stream_body() {
+    let backend = spawn_backend(Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|| async {
+            let events = stream::iter(sse_events(&[
+                chunk(json!({"content": "first"}), None),
+                chunk(json!({"content": " late"}), Some("stop")),
+            ]))
+            .enumerate()
+            .then(|(i, event)| async move {
+                if i == 1 {
+                    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+                }
+
+                Ok::<_, std::io::Error>(event)
+            });
+
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(events),
+            )
+        }),
+    ))
+    .await;
+    let app = app(&backend, &["--request-timeout", "1"]);
+
+    let response = send(app, chat_stream()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(texts(&sse_data(&body_text(response).await)).1, "first late");
+}
+
+#[tokio::test]
+async fn limit_anonymous_streams_by_client_ip() {
+    // the stream is held open until the test ends
+    let backend = spawn_backend(Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|| async {
+            let events = stream::iter(sse_events(&[chunk(json!({"content": "first"}), None)]))
+                .take(1)
+                .chain(stream::pending())
+                .map(Ok::<_, std::io::Error>);
+
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(events),
+            )
+        }),
+    ))
+    .await;
+    let app = app(&backend, &["--max-streams-per-key", "1"]);
+
+    let from = |ip: [u8; 4], authorization: Option<&str>| {
+        let mut request = chat_stream();
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        }
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(PeerAddr(SocketAddr::from((ip, 0)))));
+
+        app.clone().oneshot(request)
+    };
+
+    let first = from([203, 0, 113, 1], None).await.unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+
+    let response = from([203, 0, 113, 1], None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        body_json(response).await["error"]["code"],
+        "too_many_streams"
+    );
+
+    // the other anonymous client and the api key have their own slots
+    let other = from([203, 0, 113, 2], None).await.unwrap();
+    assert_eq!(other.status(), StatusCode::OK);
+    let keyed = from([203, 0, 113, 1], Some("Bearer sk-test"))
+        .await
+        .unwrap();
+    assert_eq!(keyed.status(), StatusCode::OK);
+
+    // the slot is released with the stream
+    drop(first);
+    let response = from([203, 0, 113, 1], None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn select_cot_parser_by_model() {
+    let (backend, _) = spawn_sse_backend(sse_events(&[
+        chunk(json!({"content": "<think>think</think>"}), None),
+        chunk(json!({"content": "answer"}), Some("stop")),
+    ]))
+    .await;
+    let app = app(
+        &backend,
+        &[
+            "--cot-parser",
+            "deepseek",
+            "--model-cot-parser",
+            "plain-model=none",
+        ],
+    );
+    let request = |model: &str| {
+        post_json(
+            "/v1/chat/completions",
+            &json!({
+                "model": model,
+                "messages": [{"role": "user", "content": "hi"}],
+                "stream": true,
+            }),
+        )
+    };
+
+    let response = send(app.clone(), request("reasoning-model")).await;
+    assert_eq!(
+        texts(&sse_data(&body_text(response).await)),
+        ("think".to_string(), "answer".to_string())
+    );
+
+    let response = send(app, request("plain-model")).await;
+    assert_eq!(
+        texts(&sse_data(&body_text(response).await)),
+        (String::new(), "<think>think</think>answer".to_string())
+    );
+}
+
+#[tokio::test]
+async fn skip_malformed_event() {
+    let (backend, _) = spawn_sse_backend(vec![
+        // repairable trailing comma
+        "data: {\"id\":\"test\",\"object\":\"chat.completion.chunk\",\"created\":0,\"model\":\"gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"a\"},}],}\n\n".to_string(),
+        "data: {\"id\":\n\n".to_string(),
+        format!("data: {}\n\n", chunk(json!({"content": "b"}), Some("stop"))),
+        "data: [DONE]\n\n".to_string(),
+    ])
+    .await;
+
+    let response = send(
+        app(&backend, &["--cot-parser", "deepseek", "--lenient-sse"]),
+        chat_stream(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let text = body_text(response).await;
+    assert_eq!(texts(&sse_data(&text)).1, "ab");
+    assert!(text.ends_with("data: [DONE]\n\n"));
+
+    // the malformed event breaks the stream without the lenient mode
+    let response = send(app(&backend, &["--cot-parser", "deepseek"]), chat_stream()).await;
+    body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn attach_request_id() {
+    let (backend, captured) = spawn_sse_backend(sse_events(&[chunk(
+        json!({"content": "answer"}),
+        Some("stop"),
+    )]))
+    .await;
+
+    for (source, client_id) in [
+        ("header:x-client-id", Some("client-123")),
+        ("header:x-client-id", None),
+        ("uuid", None),
+        ("ulid", None),
+    ] {
+        let app = app(
+            &backend,
+            &["--cot-parser", "deepseek", "--request-id-source", source],
+        );
+        let mut request = chat_stream();
+        if let Some(client_id) = client_id {
+            request
+                .headers_mut()
+                .insert("x-client-id", client_id.parse().unwrap());
+        }
+
+        let response = send(app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let request_id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        match (source, client_id) {
+            (_, Some(client_id)) => assert_eq!(request_id, client_id),
+            ("ulid", _) => assert_eq!(request_id.len(), 26, "{request_id}"),
+            // the missing header falls back to a UUID
+            _ => assert_eq!(request_id.split('-').count(), 5, "{request_id}"),
+        }
+        assert_eq!(captured.last().0["x-request-id"], request_id.as_str());
+
+        let text = body_text(response).await;
+        assert!(
+            text.starts_with(&format!(": request-id: {request_id}\n\n")),
+            "{text:?}"
+        );
+        assert_eq!(texts(&sse_data(&text)).1, "answer");
+    }
+}
+
+#[tokio::test]
+async fn cancel_stream_mid_flight() {
+    // the backend sends the first chunks and then stalls until the stream is cancelled
+    let router = Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|| async {
+            let pieces = sse_events(&[
+                chunk(
+                    json!({"role": "assistant", "reasoning_content": "think"}),
+                    None,
+                ),
+                chunk(json!({"content": "partial"}), None),
+            ]);
+            let pieces = stream::iter(pieces.into_iter().take(2))
+                .map(|piece| Ok::<_, std::io::Error>(Bytes::from(piece)))
+                .chain(stream::pending());
+
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(pieces),
+            )
+        }),
+    );
+    let backend = spawn_backend(router).await;
+    let app = app(
+        &backend,
+        &[
+            "--cot-parser",
+            "deepseek",
+            "--request-id-source",
+            "header:x-client-id",
+            "--enable-cancel",
+            "--admin-token",
+            "admin-secret",
+        ],
+    );
+    let stream_request = |request_id: &str, authorization: Option<&str>| {
+        let mut request = chat_stream();
+        request
+            .headers_mut()
+            .insert("x-client-id", request_id.parse().unwrap());
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        }
+
+        request
+    };
+    let cancel = |request_id: &str, authorization: Option<&str>| {
+        let mut request = Request::post(format!("/v1/cancel/{request_id}"))
+            .body(Body::empty())
+            .unwrap();
+        if let Some(authorization) = authorization {
+            request
+                .headers_mut()
+                .insert(header::AUTHORIZATION, authorization.parse().unwrap());
+        }
+
+        send(app.clone(), request)
+    };
+
+    for (request_id, authorization, canceller) in [
+        ("with-key", Some("Bearer user-key"), "Bearer user-key"),
+        // only the admin can cancel the stream requested without `Authorization`
+        ("without-key", None, "Bearer admin-secret"),
+    ] {
+        let response = send(app.clone(), stream_request(request_id, authorization)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body().into_data_stream();
+        let mut text = String::new();
+        while !text.contains("partial") {
+            let data = tokio::time::timeout(Duration::from_secs(5), body.next())
+                .await
+                .expect("stream stalled before the partial content")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&data).unwrap());
+        }
+
+        // the request ID is still streaming
+        let response = send(app.clone(), stream_request(request_id, authorization)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        for wrong in [None, Some("Bearer other-key")] {
+            let response = cancel(request_id, wrong).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{wrong:?}");
+        }
+
+        let response = cancel(request_id, Some(canceller)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let completion = body_json(response).await;
+        assert_eq!(completion["choices"][0]["message"]["content"], "partial");
+        assert_eq!(
+            completion["choices"][0]["message"]["reasoning_content"],
+            "think"
+        );
+
+        // the cancelled stream ends although the backend still stalls
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(data) = body.next().await {
+                data.unwrap();
+            }
+        })
+        .await
+        .expect("cancelled stream did not end");
+    }
+}
+
+#[tokio::test]
+async fn name_reasoning_events() {
+    let (backend, _) = spawn_sse_backend(sse_events(&[
+        chunk(
+            json!({"role": "assistant", "reasoning_content": "think"}),
+            None,
+        ),
+        chunk(json!({"content": "ans"}), None),
+        chunk(json!({"content": "wer"}), Some("stop")),
+    ]))
+    .await;
+    // the script sends the end of the reasoning along with the first content
+    let script = std::env::temp_dir().join(format!("name-reasoning-{}.rhai", std::process::id()));
+    std::fs::write(
+        &script,
+        r#"
+        fn on_response(chunk) {
+            if chunk.choices[0].delta.content == "ans" {
+                chunk.choices[0].delta.reasoning_content = " more";
+            }
+            chunk
+        }
+        "#,
+    )
+    .unwrap();
+    let app = app(
+        &backend,
+        &[
+            "--cot-parser",
+            "deepseek",
+            "--reasoning-event-name",
+            "reasoning",
+            "--response-script",
+            script.to_str().unwrap(),
+            "--response-script-stream",
+        ],
+    );
+    // the script is loaded when the app is built
+    std::fs::remove_file(&script).unwrap();
+
+    let response = send(app, chat_stream()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let text = body_text(response).await;
+
+    // the event name and the delta of every data event
+    let events = text
+        .split("\n\n")
+        .filter_map(|event| {
+            let name = event
+                .lines()
+                .find_map(|line| line.strip_prefix("event: "))
+                .unwrap_or_default();
+            let data = event.lines().find_map(|line| line.strip_prefix("data: "))?;
+            if data == "[DONE]" {
+                return None;
+            }
+            let chunk = serde_json::from_str::<Value>(data).unwrap();
+
+            Some((name.to_string(), chunk["choices"][0]["delta"].clone()))
+        })
+        .collect::<Vec<_>>();
+
+    for (name, delta) in &events {
+        let is_reasoning = delta.get("reasoning_content").is_some();
+        assert_eq!(name == "reasoning", is_reasoning, "{name}: {delta}");
+        // the reasoning and the content are never sent in one chunk
+        assert!(!is_reasoning || delta.get("content").is_none(), "{delta}");
+    }
+
+    let chunks = events
+        .into_iter()
+        .map(|(_, delta)| json!({"choices": [{"delta": delta}]}));
+    assert_eq!(
+        texts(&chunks.collect::<Vec<_>>()),
+        ("think more".to_string(), "answer".to_string())
+    );
+}
+
+#[tokio::test]
+async fn limit_connections_per_ip() {
+    // the stream is held open until the test ends, the non streaming chat is answered at once
+    let backend = spawn_backend(Router::new().route(
+        "/v1/chat/completions",
+        axum::routing::post(|Json(body): Json<Value>| async move {
+            if body["stream"] != true {
+                return Json(completion("answer")).into_response();
+            }
+
+            let events = stream::iter(sse_events(&[chunk(json!({"content": "first"}), None)]))
+                .take(1)
+                .chain(stream::pending())
+                .map(Ok::<_, std::io::Error>);
+
+            (
+                [(header::CONTENT_TYPE, "text/event-stream")],
+                Body::from_stream(events),
+            )
+                .into_response()
+        }),
+    ))
+    .await;
+    let app = app(&backend, &["--max-connections-per-ip", "1"]);
+
+    let from = |ip: [u8; 4], request: Request<Body>| {
+        let mut request = request;
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(PeerAddr(SocketAddr::from((ip, 0)))));
+
+        app.clone().oneshot(request)
+    };
+    let chat = || {
+        post_json(
+            "/v1/chat/completions",
+            &json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]}),
+        )
+    };
+
+    // the non streaming response releases the slot with the handler, before its body is read
+    let held = from([203, 0, 113, 1], chat()).await.unwrap();
+    assert_eq!(held.status(), StatusCode::OK);
+    let response = from([203, 0, 113, 1], chat()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(held).await, completion("answer"));
+
+    let first = from([203, 0, 113, 1], chat_stream()).await.unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+
+    let response = from([203, 0, 113, 1], chat()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(
+        body_json(response).await["error"]["code"],
+        "too_many_connections"
+    );
+
+    // the other client has its own slot
+    let other = from([203, 0, 113, 2], chat()).await.unwrap();
+    assert_eq!(other.status(), StatusCode::OK);
+
+    // the slot is released with the stream
+    drop(first);
+    let response = from([203, 0, 113, 1], chat()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn skip_interspersed_comments() {
+    let events = sse_events(&[
+        chunk(
+            json!({"role": "assistant", "content": "<think>think"}),
+            None,
+        ),
+        chunk(json!({"content": "</think>ans"}), None),
+        chunk(json!({"content": "wer"}), Some("stop")),
+    ]);
+    // the comments come between the events, inside an event and in the middle of a piece
+    let (first, second) = events[1].split_at(10);
+    let (backend, _) = spawn_sse_backend(vec![
+        ": OPENROUTER PROCESSING\n\n".to_string(),
+        events[0].clone(),
+        format!(": keep-alive\n\n{first}"),
+        second.to_string(),
+        format!("event: message\n: inside the event\n{}", events[2]),
+        ": keep-alive\n\n".to_string(),
+        events[3].clone(),
+    ])
+    .await;
+    let app = app(&backend, &["--cot-parser", "deepseek"]);
+
+    let response = send(app, chat_stream()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let text = body_text(response).await;
+    assert!(text.ends_with("data: [DONE]\n\n"), "{text}");
+    assert_eq!(
+        texts(&sse_data(&text)),
+        ("think".to_string(), "answer".to_string())
+    );
+}
+
+#[tokio::test]
+async fn sink_redacted_reasoning() {
+    let (backend, _) = spawn_sse_backend(sse_events(&[
+        chunk(
+            json!({"role": "assistant", "reasoning_content": "key sk-123 "}),
+            None,
+        ),
+        chunk(json!({"reasoning_content": "found"}), None),
+        chunk(json!({"content": "done"}), Some("stop")),
+    ]))
+    .await;
+    let sink = std::env::temp_dir().join(format!("reasoning-sink-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&sink);
+
+    let response = send(
+        app(
+            &backend,
+            &[
+                "--cot-parser",
+                "deepseek",
+                "--output-redact",
+                r"sk-\d+",
+                "--output-redact-reasoning",
+                "--reasoning-sink",
+                sink.to_str().unwrap(),
+            ],
+        ),
+        chat_stream(),
+    )
+    .await;
+    let (reasoning, _) = texts(&sse_data(&body_text(response).await));
+    assert_eq!(reasoning, "key [REDACTED] found");
+
+    // the record is written in the background once the stream ends
+    let mut line = String::new();
+    for _ in 0..50 {
+        line = std::fs::read_to_string(&sink).unwrap_or_default();
+        if !line.is_empty() {
+            break;
+        }
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    std::fs::remove_file(&sink).unwrap();
+
+    let record = serde_json::from_str::<Value>(line.trim_end()).unwrap();
+    assert_eq!(record["choices"][0]["reasoning_content"], reasoning);
+}
+
+#[tokio::test]
+async fn redact_stream_without_cot_parser() {
+    let (backend, _) = spawn_sse_backend(sse_events(&[
+        chunk(
+            json!({"role": "assistant", "content": "<think>no parser</think> key sk-1"}),
+            None,
+        ),
+        chunk(json!({"content": "23 done"}), Some("stop")),
+    ]))
+    .await;
+
+    for args in [
+        &[][..],
+        &[
+            "--cot-parser",
+            "deepseek",
+            "--model-cot-parser",
+            "gpt-4o=none",
+        ],
+    ] {
+        let args = [args, &["--output-redact", r"sk-\d+"]].concat();
+        let response = send(app(&backend, &args), chat_stream()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let (reasoning, content) = texts(&sse_data(&body_text(response).await));
+        assert_eq!(reasoning, "");
+        assert_eq!(content, "<think>no parser</think> key [REDACTED] done");
+    }
+}
@@ -0,0 +1,88 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use tiktoken_rs::Rank;
+
+use crate::truncate::Tokenize;
+
+/// LRU cache of encoded tokens, keyed by the SHA-256 of content, so repeated contents like system
+/// prompts are not re-encoded on every request, and different contents never share the tokens
+#[derive(Debug)]
+pub struct TokenCache {
+    cache: Mutex<LruCache<[u8; 32], Vec<Rank>>>,
+    min_len: usize,
+}
+
+impl TokenCache {
+    pub fn new(size: NonZeroUsize, min_len: usize) -> Self {
+        Self {
+            cache: Mutex::new(LruCache::new(size)),
+            min_len,
+        }
+    }
+
+    /// encode content, only content not shorter than `min_len` is cached
+    pub fn encode<B: Tokenize>(&self, bpe: &B, content: &str) -> Vec<Rank> {
+        if content.len() < self.min_len {
+            return bpe.tokenize(content);
+        }
+
+        let key = Sha256::digest(content).into();
+
+        if let Some(tokens) = self.cache.lock().unwrap().get(&key) {
+            return tokens.clone();
+        }
+
+        let tokens = bpe.tokenize(content);
+        self.cache.lock().unwrap().put(key, tokens.clone());
+
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// one token per byte, counting the tokenize calls
+    #[derive(Default)]
+    struct CountingBpe(AtomicUsize);
+
+    impl Tokenize for CountingBpe {
+        fn tokenize(&self, content: &str) -> Vec<Rank> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            content.bytes().map(Rank::from).collect()
+        }
+
+        fn detokenize_split(&self, tokens: Vec<Rank>) -> Vec<Vec<u8>> {
+            tokens.into_iter().map(|token| vec![token as u8]).collect()
+        }
+    }
+
+    #[test]
+    fn cache_hit() {
+        let bpe = CountingBpe::default();
+        let cache = TokenCache::new(NonZeroUsize::new(2).unwrap(), 4);
+
+        let tokens = cache.encode(&bpe, "system prompt");
+        assert_eq!(cache.encode(&bpe, "system prompt"), tokens);
+        assert_eq!(bpe.0.load(Ordering::Relaxed), 1);
+
+        // other content is encoded, not served from the cache
+        assert_eq!(
+            cache.encode(&bpe, "system prompt!"),
+            bpe.tokenize("system prompt!")
+        );
+        assert_eq!(bpe.0.load(Ordering::Relaxed), 3);
+
+        // short content is not cached
+        cache.encode(&bpe, "hi");
+        cache.encode(&bpe, "hi");
+        assert_eq!(bpe.0.load(Ordering::Relaxed), 5);
+    }
+}
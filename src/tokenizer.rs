@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use educe::Educe;
+use tiktoken_rs::tokenizer::{self, Tokenizer};
+use tiktoken_rs::{CoreBPE, Rank, get_bpe_from_tokenizer};
+use tokio::sync::OnceCell;
+use tokio::task;
+use tracing::{error, info, warn};
+
+use crate::cli::{TokenizerFallback, TokenizerName};
+use crate::token_cache::TokenCache;
+use crate::truncate::Tokenize;
+
+const DEFAULT_TOKENIZER: Tokenizer = Tokenizer::O200kBase;
+const WARMUP_TEXT: &str = "warmup";
+/// the estimating tokenizer packs the bytes and their count into a token
+const ESTIMATE_TOKEN_BYTES: usize = 3;
+
+impl From<TokenizerName> for Tokenizer {
+    fn from(value: TokenizerName) -> Self {
+        match value {
+            TokenizerName::O200kBase => Tokenizer::O200kBase,
+            TokenizerName::Cl100kBase => Tokenizer::Cl100kBase,
+            TokenizerName::P50kBase => Tokenizer::P50kBase,
+            TokenizerName::R50kBase => Tokenizer::R50kBase,
+            TokenizerName::P50kEdit => Tokenizer::P50kEdit,
+            TokenizerName::Gpt2 => Tokenizer::Gpt2,
+        }
+    }
+}
+
+/// the tokenizer of an encoder, `Estimate` is the fallback when the tiktoken tokenizer fails to
+/// load, it counts about one token per 3 bytes
+pub enum Bpe {
+    Tiktoken(CoreBPE),
+    Estimate,
+}
+
+impl Bpe {
+    /// the tiktoken tokenizer, [`None`] when the tokens are estimated
+    pub fn tiktoken(&self) -> Option<&CoreBPE> {
+        match self {
+            Bpe::Tiktoken(bpe) => Some(bpe),
+            Bpe::Estimate => None,
+        }
+    }
+}
+
+impl Tokenize for Bpe {
+    fn tokenize(&self, content: &str) -> Vec<Rank> {
+        match self {
+            Bpe::Tiktoken(bpe) => bpe.tokenize(content),
+
+            // the byte count goes to the highest byte, so the token is decoded back to the bytes
+            Bpe::Estimate => content
+                .as_bytes()
+                .chunks(ESTIMATE_TOKEN_BYTES)
+                .map(|bytes| {
+                    let mut token = [0; 4];
+                    token[0] = bytes.len() as u8;
+                    token[1..=bytes.len()].copy_from_slice(bytes);
+
+                    Rank::from_be_bytes(token)
+                })
+                .collect(),
+        }
+    }
+
+    fn detokenize_split(&self, tokens: Vec<Rank>) -> Vec<Vec<u8>> {
+        match self {
+            Bpe::Tiktoken(bpe) => bpe.detokenize_split(tokens),
+
+            Bpe::Estimate => tokens
+                .into_iter()
+                .map(|token| {
+                    let token = token.to_be_bytes();
+                    let len = usize::from(token[0]).min(ESTIMATE_TOKEN_BYTES);
+
+                    token[1..=len].to_vec()
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Educe)]
+#[educe(Debug)]
+pub struct Encoder {
+    #[educe(Debug(ignore))]
+    pub bpe: Bpe,
+    pub token_cache: Option<TokenCache>,
+}
+
+/// select the encoder by request model, fallback to o200k_base
+#[derive(Debug)]
+pub struct Encoders {
+    default: Arc<Encoder>,
+    /// encoders by model tokenizer, only set when auto select is enabled, the cell is taken under
+    /// the lock and loaded outside it, so a loading tokenizer doesn't block the other models
+    auto: Option<Mutex<HashMap<Tokenizer, LoadingEncoder>>>,
+    token_cache: Option<(NonZeroUsize, usize)>,
+    fallback: TokenizerFallback,
+    load: LoadEncoder,
+}
+
+type LoadingEncoder = Arc<OnceCell<Arc<Encoder>>>;
+
+type LoadEncoder = fn(Tokenizer, Option<(NonZeroUsize, usize)>) -> anyhow::Result<Encoder>;
+
+impl Encoders {
+    /// `token_cache` is the cache size and min cached content len of each encoder, `preload`
+    /// encoders are loaded for auto select at startup, when a tokenizer fails to load and
+    /// `fallback` is not [`TokenizerFallback::Fail`], the default tokenizer is used instead, or
+    /// the tokens are estimated when the default tokenizer fails too
+    pub fn new(
+        auto_select: bool,
+        token_cache: Option<(NonZeroUsize, usize)>,
+        preload: &[TokenizerName],
+        fallback: TokenizerFallback,
+    ) -> anyhow::Result<Self> {
+        Self::with_loader(auto_select, token_cache, preload, fallback, new_encoder)
+    }
+
+    fn with_loader(
+        auto_select: bool,
+        token_cache: Option<(NonZeroUsize, usize)>,
+        preload: &[TokenizerName],
+        fallback: TokenizerFallback,
+        load: LoadEncoder,
+    ) -> anyhow::Result<Self> {
+        let auto = auto_select
+            .then(|| {
+                let mut auto = HashMap::new();
+                for tokenizer in preload.iter().map(|&name| Tokenizer::from(name)) {
+                    if tokenizer == DEFAULT_TOKENIZER {
+                        continue;
+                    }
+
+                    match load(tokenizer, token_cache) {
+                        Err(err) if fallback != TokenizerFallback::Fail => {
+                            warn!(?tokenizer, %err, "preload tokenizer failed, skip it");
+                        }
+
+                        encoder => {
+                            let encoder = Arc::new(encoder?);
+                            auto.insert(tokenizer, Arc::new(OnceCell::new_with(Some(encoder))));
+                        }
+                    }
+                }
+
+                anyhow::Ok(Mutex::new(auto))
+            })
+            .transpose()?;
+
+        let default = match load(DEFAULT_TOKENIZER, token_cache) {
+            Err(err) if fallback != TokenizerFallback::Fail => {
+                error!(
+                    tokenizer = ?DEFAULT_TOKENIZER,
+                    %err,
+                    ?fallback,
+                    "load default tokenizer failed, estimate tokens by bytes"
+                );
+
+                Encoder {
+                    bpe: Bpe::Estimate,
+                    token_cache: None,
+                }
+            }
+
+            default => default?,
+        };
+
+        Ok(Self {
+            default: Arc::new(default),
+            auto,
+            token_cache,
+            fallback,
+            load,
+        })
+    }
+
+    /// whether the default tokenizer failed to load and the tokens are estimated
+    pub fn estimating(&self) -> bool {
+        matches!(self.default.bpe, Bpe::Estimate)
+    }
+
+    /// whether the input truncation is disabled for the encoder by
+    /// [`TokenizerFallback::Disable`], its tokenizer failed to load
+    pub fn truncation_disabled(&self, encoder: &Encoder) -> bool {
+        self.fallback == TokenizerFallback::Disable && matches!(encoder.bpe, Bpe::Estimate)
+    }
+
+    /// the names of the loaded tokenizers, the failed ones replaced by a fallback are skipped
+    pub fn loaded(&self) -> Vec<String> {
+        let mut loaded = vec![match self.default.bpe {
+            Bpe::Tiktoken(_) => format!("{DEFAULT_TOKENIZER:?}"),
+            Bpe::Estimate => "Estimate".to_string(),
+        }];
+        if let Some(auto) = &self.auto {
+            loaded.extend(
+                auto.lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, cell)| {
+                        cell.get().is_some_and(|encoder| {
+                            matches!(encoder.bpe, Bpe::Tiktoken(_))
+                                && !Arc::ptr_eq(encoder, &self.default)
+                        })
+                    })
+                    .map(|(tokenizer, _)| format!("{tokenizer:?}")),
+            );
+        }
+
+        loaded
+    }
+
+    pub async fn get(&self, model: &str) -> anyhow::Result<Arc<Encoder>> {
+        let Some(auto) = &self.auto else {
+            return Ok(self.default.clone());
+        };
+
+        let tokenizer = match tokenizer::get_tokenizer(model) {
+            None | Some(DEFAULT_TOKENIZER) => return Ok(self.default.clone()),
+            Some(tokenizer) => tokenizer,
+        };
+
+        let cell = auto.lock().unwrap().entry(tokenizer).or_default().clone();
+
+        cell.get_or_try_init(|| self.load_auto(model, tokenizer))
+            .await
+            .cloned()
+    }
+
+    /// load the tokenizer of the model, the fallback is returned when it fails, so the tokenizer
+    /// is not loaded again by every request
+    async fn load_auto(&self, model: &str, tokenizer: Tokenizer) -> anyhow::Result<Arc<Encoder>> {
+        info!(model, ?tokenizer, "load model tokenizer");
+
+        // loading the BPE takes a while, don't block the async workers
+        let (load, token_cache) = (self.load, self.token_cache);
+        let encoder = task::spawn_blocking(move || load(tokenizer, token_cache)).await?;
+
+        let encoder = match (encoder, self.fallback) {
+            (Err(err), TokenizerFallback::Estimate) => {
+                warn!(model, ?tokenizer, %err, "load model tokenizer failed, use the default one");
+
+                self.default.clone()
+            }
+
+            (Err(err), TokenizerFallback::Disable) => {
+                warn!(
+                    model,
+                    ?tokenizer,
+                    %err,
+                    "load model tokenizer failed, disable the input truncation of the model"
+                );
+
+                Arc::new(Encoder {
+                    bpe: Bpe::Estimate,
+                    token_cache: None,
+                })
+            }
+
+            (encoder, _) => Arc::new(encoder?),
+        };
+
+        Ok(encoder)
+    }
+}
+
+fn new_encoder(
+    tokenizer: Tokenizer,
+    token_cache: Option<(NonZeroUsize, usize)>,
+) -> anyhow::Result<Encoder> {
+    let start = Instant::now();
+
+    let bpe = get_bpe_from_tokenizer(tokenizer)?;
+    // pay the first encoding cost at loading instead of the first request
+    bpe.encode_with_special_tokens(WARMUP_TEXT);
+
+    info!(?tokenizer, elapsed = ?start.elapsed(), "tokenizer ready");
+
+    Ok(Encoder {
+        bpe: Bpe::Tiktoken(bpe),
+        token_cache: token_cache.map(|(size, min_len)| TokenCache::new(size, min_len)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn select_cl100k_by_model() {
+        let encoders = Encoders::new(true, None, &[], TokenizerFallback::Fail).unwrap();
+        let text = "hello world, 你好世界";
+
+        let encoder = encoders.get("gpt-4").await.unwrap();
+        assert_eq!(
+            encoder.bpe.tokenize(text),
+            tiktoken_rs::cl100k_base()
+                .unwrap()
+                .encode_with_special_tokens(text)
+        );
+        assert_eq!(encoders.loaded(), ["O200kBase", "Cl100kBase"]);
+        // the loaded encoder is reused
+        assert!(Arc::ptr_eq(
+            &encoder,
+            &encoders.get("gpt-4-0613").await.unwrap()
+        ));
+
+        // the unknown model uses the default tokenizer
+        let encoder = encoders.get("my-model").await.unwrap();
+        assert_eq!(
+            encoder.bpe.tokenize(text),
+            tiktoken_rs::o200k_base()
+                .unwrap()
+                .encode_with_special_tokens(text)
+        );
+
+        // without auto select, the default tokenizer is always used
+        let encoders = Encoders::new(false, None, &[], TokenizerFallback::Fail).unwrap();
+        assert!(Arc::ptr_eq(
+            &encoders.get("gpt-4").await.unwrap(),
+            &encoders.get("my-model").await.unwrap()
+        ));
+    }
+
+    #[test]
+    fn estimate_round_trip() {
+        let text = "hello, 你好🙂";
+
+        let tokens = Bpe::Estimate.tokenize(text);
+        assert_eq!(tokens.len(), text.len().div_ceil(ESTIMATE_TOKEN_BYTES));
+        assert_eq!(
+            Bpe::Estimate.detokenize_split(tokens).concat(),
+            text.as_bytes()
+        );
+        assert!(Bpe::Estimate.tokenize("").is_empty());
+    }
+
+    fn fail_cl100k(
+        tokenizer: Tokenizer,
+        token_cache: Option<(NonZeroUsize, usize)>,
+    ) -> anyhow::Result<Encoder> {
+        if tokenizer == Tokenizer::Cl100kBase {
+            anyhow::bail!("broken tokenizer");
+        }
+
+        new_encoder(tokenizer, token_cache)
+    }
+
+    fn fail_all(_: Tokenizer, _: Option<(NonZeroUsize, usize)>) -> anyhow::Result<Encoder> {
+        anyhow::bail!("broken tokenizer")
+    }
+
+    #[tokio::test]
+    async fn fall_back_from_failed_auto_tokenizer() {
+        let encoders =
+            Encoders::with_loader(true, None, &[], TokenizerFallback::Fail, fail_cl100k).unwrap();
+        assert!(encoders.get("gpt-4").await.is_err());
+        assert!(
+            Encoders::with_loader(
+                true,
+                None,
+                &[TokenizerName::Cl100kBase],
+                TokenizerFallback::Fail,
+                fail_cl100k
+            )
+            .is_err()
+        );
+
+        let encoders =
+            Encoders::with_loader(true, None, &[], TokenizerFallback::Estimate, fail_cl100k)
+                .unwrap();
+        let encoder = encoders.get("gpt-4").await.unwrap();
+        assert!(Arc::ptr_eq(
+            &encoder,
+            &encoders.get("my-model").await.unwrap()
+        ));
+        assert!(!encoders.truncation_disabled(&encoder));
+
+        // only the model of the failed tokenizer is disabled
+        let encoders =
+            Encoders::with_loader(true, None, &[], TokenizerFallback::Disable, fail_cl100k)
+                .unwrap();
+        let encoder = encoders.get("gpt-4").await.unwrap();
+        assert!(matches!(encoder.bpe, Bpe::Estimate));
+        assert!(encoders.truncation_disabled(&encoder));
+        assert!(Arc::ptr_eq(
+            &encoder,
+            &encoders.get("gpt-4-0613").await.unwrap()
+        ));
+        assert!(!encoders.truncation_disabled(&encoders.get("my-model").await.unwrap()));
+        assert!(!encoders.estimating());
+    }
+
+    static LOADS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_failed_cl100k(
+        tokenizer: Tokenizer,
+        token_cache: Option<(NonZeroUsize, usize)>,
+    ) -> anyhow::Result<Encoder> {
+        if tokenizer == Tokenizer::Cl100kBase {
+            LOADS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fail_cl100k(tokenizer, token_cache)
+    }
+
+    #[tokio::test]
+    async fn load_failed_tokenizer_once() {
+        let encoders = Encoders::with_loader(
+            true,
+            None,
+            &[],
+            TokenizerFallback::Estimate,
+            count_failed_cl100k,
+        )
+        .unwrap();
+
+        // the concurrent requests wait for the same load, the fallback is cached
+        let (first, second) = tokio::join!(encoders.get("gpt-4"), encoders.get("gpt-4-0613"));
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+        encoders.get("gpt-4").await.unwrap();
+        assert_eq!(LOADS.load(Ordering::Relaxed), 1);
+        assert_eq!(encoders.loaded(), ["O200kBase"]);
+    }
+
+    #[tokio::test]
+    async fn fall_back_from_failed_default_tokenizer() {
+        assert!(
+            Encoders::with_loader(false, None, &[], TokenizerFallback::Fail, fail_all).is_err()
+        );
+
+        for fallback in [TokenizerFallback::Estimate, TokenizerFallback::Disable] {
+            let encoders = Encoders::with_loader(false, None, &[], fallback, fail_all).unwrap();
+            assert!(encoders.estimating());
+            assert_eq!(encoders.loaded(), ["Estimate"]);
+
+            let encoder = encoders.get("gpt-4o").await.unwrap();
+            assert_eq!(encoder.bpe.tokenize("abcdef").len(), 2);
+            assert_eq!(
+                encoders.truncation_disabled(&encoder),
+                fallback == TokenizerFallback::Disable
+            );
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::time;
+
+/// pipe the request body through the external command, the command reads the JSON request from
+/// stdin and writes the transformed JSON request to stdout
+pub async fn transform(command: &Path, body: &Value, timeout: Duration) -> anyhow::Result<Value> {
+    let input = serde_json::to_vec(body)?;
+
+    let mut child = Command::new(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("spawn transform command {} failed", command.display()))?;
+    let mut stdin = child.stdin.take().unwrap();
+
+    let write_stdin = async move {
+        stdin.write_all(&input).await?;
+        // close stdin so the command knows the input ends
+        drop(stdin);
+
+        Ok::<_, anyhow::Error>(())
+    };
+
+    let (write_result, output) = time::timeout(timeout, async {
+        tokio::join!(write_stdin, child.wait_with_output())
+    })
+    .await
+    .with_context(|| format!("transform command timeout after {timeout:?}"))?;
+
+    write_result.context("write transform command stdin failed")?;
+    let output = output.context("wait transform command failed")?;
+    if !output.status.success() {
+        anyhow::bail!("transform command exit with {}", output.status);
+    }
+
+    serde_json::from_slice(&output.stdout).context("parse transform command output failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn identity_transform() {
+        let body = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "hi"}]});
+
+        let transformed = transform(Path::new("cat"), &body, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(transformed, body);
+    }
+
+    #[tokio::test]
+    async fn failed_transform() {
+        let body = json!({});
+
+        transform(Path::new("false"), &body, Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        transform(
+            Path::new("/nonexistent/transform"),
+            &body,
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap_err();
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::{HashMap, VecDeque};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tiktoken_rs::{CoreBPE, Rank};
+use tracing::{debug, info, warn};
+
+use crate::token_cache::TokenCache;
+use crate::utf8::Utf8Buffer;
+
+const PARALLEL_ENCODE_MIN_MESSAGES: usize = 16;
+
+/// the tracing target of the truncation decisions, its level is set by `--truncation-log-level`
+pub const LOG_TARGET: &str = "openai_enhance::truncate";
+
+/// the tokenizer of the truncation
+pub trait Tokenize: Sync {
+    fn tokenize(&self, content: &str) -> Vec<Rank>;
+
+    /// decode the tokens to the bytes of each token, a multibyte char may be split across tokens
+    fn detokenize_split(&self, tokens: Vec<Rank>) -> Vec<Vec<u8>>;
+}
+
+impl Tokenize for CoreBPE {
+    fn tokenize(&self, content: &str) -> Vec<Rank> {
+        self.encode_with_special_tokens(content)
+    }
+
+    fn detokenize_split(&self, tokens: Vec<Rank>) -> Vec<Vec<u8>> {
+        self._decode_native_and_split(tokens).collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Message {
+    pub role: String,
+    // assistant tool calls message may have null content
+    #[serde(default)]
+    pub content: Option<String>,
+
+    #[serde(flatten)]
+    pub other_fields: HashMap<String, Value>,
+}
+
+impl Message {
+    pub fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: Some(content.into()),
+            other_fields: HashMap::new(),
+        }
+    }
+
+    pub fn content(&self) -> &str {
+        self.content.as_deref().unwrap_or_default()
+    }
+
+    /// tool result message, it is orphaned when its assistant tool calls message is dropped
+    pub fn is_tool_result(&self) -> bool {
+        self.role == "tool" || self.role == "function"
+    }
+}
+
+/// the completion prompt or the chat messages
+pub enum MessageType<'a> {
+    Single(&'a mut String),
+    Multiple(&'a mut VecDeque<Message>),
+}
+
+/// the options of [`truncate_messages`]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TruncateOptions {
+    /// skip the last user message, the messages around it are dropped or truncated instead,
+    /// unless it alone exceeds `max_token`
+    pub protect_last_user: bool,
+    /// drop the front message instead of truncating it to less tokens than this
+    pub min_message_tokens: usize,
+}
+
+/// truncate the input to `max_token` tokens, the front messages are dropped first, then the
+/// front of the remaining message is truncated, the tool results orphaned by dropping are dropped
+/// too
+///
+/// ```
+/// use std::collections::VecDeque;
+///
+/// use openai_enhance::truncate::{Message, MessageType, TruncateOptions, truncate_messages};
+///
+/// let bpe = tiktoken_rs::o200k_base().unwrap();
+/// let mut messages = VecDeque::from([
+///     Message::new("user", "hello ".repeat(100)),
+///     Message::new("assistant", "hi, how can I help you?"),
+///     Message::new("user", "tell me a joke"),
+/// ]);
+///
+/// truncate_messages(
+///     &bpe,
+///     None,
+///     MessageType::Multiple(&mut messages),
+///     20,
+///     TruncateOptions::default(),
+/// );
+///
+/// let tokens = messages
+///     .iter()
+///     .map(|message| bpe.encode_with_special_tokens(message.content()).len())
+///     .sum::<usize>();
+/// assert!(tokens <= 20);
+/// assert_eq!(messages[2].content(), "tell me a joke");
+///
+/// let question = "why ".repeat(30);
+/// let mut messages = VecDeque::from([
+///     Message::new("user", "hello ".repeat(100)),
+///     Message::new("assistant", "hi, how can I help you?"),
+///     Message::new("user", question.clone()),
+///     Message::new("assistant", "because ".repeat(100)),
+/// ]);
+///
+/// let options = TruncateOptions {
+///     protect_last_user: true,
+///     ..Default::default()
+/// };
+/// truncate_messages(&bpe, None, MessageType::Multiple(&mut messages), 40, options);
+///
+/// assert_eq!(messages[0].content(), question);
+///
+/// let mut messages = VecDeque::from([
+///     Message::new("user", "hello ".repeat(100)),
+///     Message::new("user", "tell me a joke"),
+/// ]);
+/// let options = TruncateOptions {
+///     min_message_tokens: 20,
+///     ..Default::default()
+/// };
+/// truncate_messages(&bpe, None, MessageType::Multiple(&mut messages), 15, options);
+///
+/// // the front message would be truncated to 10 tokens, it is dropped
+/// assert_eq!(messages.len(), 1);
+/// assert_eq!(messages[0].content(), "tell me a joke");
+/// ```
+pub fn truncate_messages<B: Tokenize>(
+    bpe: &B,
+    token_cache: Option<&TokenCache>,
+    messages: MessageType,
+    max_token: usize,
+    options: TruncateOptions,
+) {
+    match messages {
+        MessageType::Single(message) => {
+            let tokens = encode(bpe, token_cache, message);
+
+            truncate_encoded_message(bpe, message, tokens, max_token);
+        }
+
+        MessageType::Multiple(messages) => {
+            let mut token_list = encode_messages(bpe, token_cache, messages);
+
+            truncate_encoded_messages(bpe, messages, &mut token_list, max_token, options);
+        }
+    }
+}
+
+/// truncate the encoded content to `max_token` tokens like [`truncate_messages`], return the
+/// tokens of the truncated content
+pub fn truncate_encoded_message<B: Tokenize>(
+    bpe: &B,
+    content: &mut String,
+    tokens: Vec<Rank>,
+    max_token: usize,
+) -> Vec<Rank> {
+    if tokens.len() <= max_token {
+        return tokens;
+    }
+
+    info!(
+        target: LOG_TARGET,
+        tokens_len = tokens.len(),
+        max_token,
+        "truncating single message"
+    );
+
+    truncate_message(bpe, max_token, content, tokens);
+
+    bpe.tokenize(content)
+}
+
+/// truncate the encoded messages to `max_token` tokens like [`truncate_messages`], `token_list`
+/// is the tokens of each message, it is kept in sync with the messages, so the messages are not
+/// encoded again by the next truncation
+pub fn truncate_encoded_messages<B: Tokenize>(
+    bpe: &B,
+    messages: &mut VecDeque<Message>,
+    token_list: &mut VecDeque<Vec<Rank>>,
+    max_token: usize,
+    options: TruncateOptions,
+) {
+    let mut sum = token_list.iter().map(|tokens| tokens.len()).sum::<usize>();
+    if sum <= max_token {
+        return;
+    }
+
+    let mut protected = options
+        .protect_last_user
+        .then(|| messages.iter().rposition(|message| message.role == "user"))
+        .flatten();
+
+    while sum > max_token {
+        assert!(!token_list.is_empty());
+
+        // the protected message is the front, make room behind it
+        let front = usize::from(protected == Some(0));
+        if front == token_list.len() {
+            warn!(
+                target: LOG_TARGET,
+                sum,
+                max_token,
+                "last user message exceeds max token, truncating it"
+            );
+
+            protected = None;
+            continue;
+        }
+
+        let token_len = token_list[front].len();
+        let too_long = sum - token_len > max_token;
+        // the truncated front message would be a useless stub
+        let stub = !too_long && token_len + max_token - sum < options.min_message_tokens;
+        if too_long || stub {
+            // the tool results of the dropped tool calls are orphaned, backend rejects them
+            let orphans = messages
+                .range(front + 1..)
+                .take_while(|message| message.is_tool_result())
+                .count();
+            // only the tool calls and its results remain, they are kept together
+            let keep_pair = front == 0 && orphans > 0 && orphans + 1 == token_list.len();
+
+            // truncate the tool results from the front instead of dropping all messages
+            if keep_pair
+                && let Some(index) = (1..token_list.len()).find(|&i| !token_list[i].is_empty())
+                && let Some(content) = &mut messages[index].content
+            {
+                let tokens = std::mem::take(&mut token_list[index]);
+                let token_len = tokens.len();
+                let new_len = (token_len + max_token).saturating_sub(sum);
+
+                warn!(
+                    target: LOG_TARGET,
+                    sum,
+                    max_token,
+                    new_len,
+                    "only the tool calls and its results remain, truncating the tool result"
+                );
+
+                truncate_message(bpe, new_len, content, tokens);
+                token_list[index] = bpe.tokenize(content);
+                sum = sum - token_len + token_list[index].len();
+
+                continue;
+            }
+
+            if token_list.len() > 1 && !keep_pair {
+                let dropped = 1 + orphans;
+
+                for (i, tokens) in token_list.drain(front..front + dropped).enumerate() {
+                    let message = messages.remove(front).unwrap();
+                    if i > 0 {
+                        warn!(
+                            target: LOG_TARGET,
+                            role = message.role,
+                            "drop orphaned tool result message"
+                        );
+                    }
+
+                    sum -= tokens.len();
+                }
+                if let Some(index) = protected.as_mut()
+                    && *index > front
+                {
+                    *index -= dropped;
+                }
+
+                info!(target: LOG_TARGET, dropped, "drop front message");
+
+                continue;
+            }
+
+            if too_long {
+                info!(
+                    target: LOG_TARGET,
+                    sum,
+                    max_token,
+                    "truncating multiple message to single"
+                );
+
+                // the null content of the tool calls message is kept
+                if let Some(content) = &mut messages[0].content {
+                    let tokens = token_list.pop_front().unwrap();
+                    let tokens = truncate_encoded_message(bpe, content, tokens, max_token);
+                    token_list.push_front(tokens);
+                }
+
+                return;
+            }
+        }
+
+        let new_len = token_len + max_token - sum;
+        let tokens = token_list.remove(front).unwrap();
+
+        info!(
+            target: LOG_TARGET,
+            sum,
+            max_token,
+            new_front_len = new_len,
+            "truncating front multiple message"
+        );
+
+        match &mut messages[front].content {
+            None => token_list.insert(front, tokens),
+
+            Some(content) => {
+                truncate_message(bpe, new_len, content, tokens);
+                token_list.insert(front, bpe.tokenize(content));
+            }
+        }
+
+        return;
+    }
+}
+
+/// put `head` back to the front of the front truncated content, so a template head is never cut,
+/// the rest is truncated again to keep the token count, return the tokens of the content
+pub fn restore_head<B: Tokenize>(
+    bpe: &B,
+    head: &str,
+    content: &mut String,
+    tokens: Vec<Rank>,
+) -> Vec<Rank> {
+    if head.is_empty() || content.starts_with(head) {
+        return tokens;
+    }
+
+    let max_token = tokens.len().saturating_sub(bpe.tokenize(head).len());
+    truncate_message(bpe, max_token, content, tokens);
+    content.insert_str(0, head);
+
+    bpe.tokenize(content)
+}
+
+/// encode the content, use the token cache when it is set
+pub fn encode<B: Tokenize>(bpe: &B, token_cache: Option<&TokenCache>, content: &str) -> Vec<Rank> {
+    match token_cache {
+        None => bpe.tokenize(content),
+        Some(token_cache) => token_cache.encode(bpe, content),
+    }
+}
+
+/// encode messages in parallel on the shared rayon pool, the result keeps the messages order
+pub fn encode_messages<B: Tokenize>(
+    bpe: &B,
+    token_cache: Option<&TokenCache>,
+    messages: &VecDeque<Message>,
+) -> VecDeque<Vec<Rank>> {
+    if messages.len() < PARALLEL_ENCODE_MIN_MESSAGES {
+        return messages
+            .iter()
+            .map(|message| encode(bpe, token_cache, message.content()))
+            .collect();
+    }
+
+    messages
+        .par_iter()
+        .map(|message| encode(bpe, token_cache, message.content()))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+/// keep the last `max_token` tokens of content
+fn truncate_message<B: Tokenize>(
+    bpe: &B,
+    max_token: usize,
+    content: &mut String,
+    tokens: Vec<Rank>,
+) {
+    let mut tokens = VecDeque::from(tokens);
+    tokens.drain(..tokens.len().saturating_sub(max_token));
+    content.clear();
+
+    // a multibyte char may be split across tokens
+    let mut buf = Utf8Buffer::default();
+    for data in bpe.detokenize_split(tokens.into()) {
+        content.push_str(&buf.push(&data));
+    }
+
+    if !buf.is_empty() {
+        debug!(
+            target: LOG_TARGET,
+            "drop incomplete utf8 char at the end of truncated message"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test::Bencher;
+
+    use super::*;
+
+    fn long_messages() -> VecDeque<Message> {
+        (0..200)
+            .map(|i| Message::new("user", format!("message {i} ").repeat(200)))
+            .collect()
+    }
+
+    #[test]
+    fn encode_messages_in_order() {
+        let bpe = tiktoken_rs::o200k_base().unwrap();
+        let messages = long_messages();
+
+        let token_list = encode_messages(&bpe, None, &messages);
+        assert_eq!(token_list.len(), messages.len());
+        for (tokens, message) in token_list.iter().zip(&messages) {
+            assert_eq!(*tokens, bpe.tokenize(message.content()));
+        }
+    }
+
+    #[test]
+    fn truncate_keeps_split_multibyte_chars() {
+        let bpe = tiktoken_rs::o200k_base().unwrap();
+        let mut content = "🙂".repeat(50);
+
+        let tokens = bpe.tokenize(&content);
+        let max_token = tokens.len() / 2;
+        truncate_messages(
+            &bpe,
+            None,
+            MessageType::Single(&mut content),
+            max_token,
+            TruncateOptions::default(),
+        );
+
+        assert!(!content.is_empty());
+        assert!(content.chars().all(|c| c == '🙂'));
+        assert!(bpe.tokenize(&content).len() <= max_token);
+    }
+
+    fn tool_calls_message() -> Message {
+        let mut message = Message::new("assistant", "");
+        message.content = None;
+        message.other_fields.insert(
+            "tool_calls".to_string(),
+            serde_json::json!([{"id": "call", "type": "function"}]),
+        );
+
+        message
+    }
+
+    fn roles(messages: &VecDeque<Message>) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.role.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn drop_orphaned_tool_results() {
+        let bpe = tiktoken_rs::o200k_base().unwrap();
+        let conversation = || {
+            VecDeque::from([
+                Message::new("user", "hello ".repeat(50)),
+                tool_calls_message(),
+                Message::new("tool", "result ".repeat(20)),
+                Message::new("tool", "result ".repeat(20)),
+                Message::new("user", "question"),
+            ])
+        };
+        let options = TruncateOptions {
+            protect_last_user: false,
+            min_message_tokens: 1,
+        };
+        let tokens = |messages: &VecDeque<Message>| {
+            messages
+                .iter()
+                .map(|message| bpe.tokenize(message.content()).len())
+                .sum::<usize>()
+        };
+
+        // the tool calls and its results are kept together, the null content is not changed
+        let mut messages = conversation();
+        let max_token = tokens(&messages) - bpe.tokenize(messages[0].content()).len();
+        truncate_messages(
+            &bpe,
+            None,
+            MessageType::Multiple(&mut messages),
+            max_token,
+            options,
+        );
+        assert_eq!(roles(&messages), ["assistant", "tool", "tool", "user"]);
+        assert!(messages[0].content.is_none());
+
+        // the tool results are dropped with the tool calls
+        let mut messages = conversation();
+        truncate_messages(
+            &bpe,
+            None,
+            MessageType::Multiple(&mut messages),
+            bpe.tokenize("question").len() + 1,
+            options,
+        );
+        assert_eq!(roles(&messages), ["user"]);
+        assert_eq!(messages[0].content(), "question");
+
+        // only the pair remains, the tool results are truncated, no tool result outlives its
+        // tool calls message
+        for options in [TruncateOptions::default(), options] {
+            let mut messages = VecDeque::from([
+                tool_calls_message(),
+                Message::new("tool", "first ".repeat(20)),
+                Message::new("tool", "second ".repeat(20)),
+            ]);
+            truncate_messages(&bpe, None, MessageType::Multiple(&mut messages), 5, options);
+            assert_eq!(roles(&messages), ["assistant", "tool", "tool"]);
+            assert_eq!(messages[1].content(), "");
+            assert_eq!(tokens(&messages), 5);
+        }
+    }
+
+    #[bench]
+    fn bench_encode_200_messages(b: &mut Bencher) {
+        let bpe = tiktoken_rs::o200k_base().unwrap();
+        let messages = long_messages();
+
+        b.iter(|| encode_messages(&bpe, None, &messages));
+    }
+}
@@ -0,0 +1,79 @@
+use std::str;
+
+/// reassemble UTF-8 text from bytes split at arbitrary boundaries, an incomplete trailing char is
+/// kept until the next push, invalid bytes are dropped instead of leaking replacement chars
+#[derive(Debug, Default)]
+pub struct Utf8Buffer {
+    buf: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    /// push bytes, return the complete text
+    pub fn push(&mut self, data: &[u8]) -> String {
+        self.buf.extend_from_slice(data);
+
+        let mut text = String::new();
+        loop {
+            match str::from_utf8(&self.buf) {
+                Ok(s) => {
+                    text.push_str(s);
+                    self.buf.clear();
+
+                    return text;
+                }
+
+                Err(err) => {
+                    let valid_len = err.valid_up_to();
+                    // Safety: checked by from_utf8
+                    text.push_str(unsafe { str::from_utf8_unchecked(&self.buf[..valid_len]) });
+
+                    match err.error_len() {
+                        // incomplete trailing char, wait for more bytes
+                        None => {
+                            self.buf.drain(..valid_len);
+
+                            return text;
+                        }
+
+                        Some(invalid_len) => {
+                            self.buf.drain(..valid_len + invalid_len);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// whether there are incomplete bytes left
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassemble_split_chars() {
+        let data = "a你好🙂".as_bytes();
+
+        let mut buf = Utf8Buffer::default();
+        let text = data.iter().map(|b| buf.push(&[*b])).collect::<Vec<_>>();
+        assert_eq!(text.concat(), "a你好🙂");
+        assert_eq!(text[1], "");
+        assert_eq!(text[3], "你");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn drop_invalid_bytes() {
+        let mut buf = Utf8Buffer::default();
+        assert_eq!(buf.push(b"a\xffb\xe4"), "ab");
+        assert!(!buf.is_empty());
+
+        // the incomplete char is broken by the next bytes
+        assert_eq!(buf.push(b"c"), "c");
+        assert!(buf.is_empty());
+    }
+}